@@ -0,0 +1,33 @@
+//!
+//! Generates the C header, `kv_store.h`, from the exported functions
+//!
+//! It's written to the output directory, leaving the source tree alone. Setting
+//! `KV_FFI_WRITE_HEADER` copies it to `include/kv_store.h` too, which is how the header
+//! shipped with the sources is kept up to date.
+//!
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("include");
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=KV_FFI_WRITE_HEADER");
+    // Where tests find the header
+    println!("cargo:rustc-env=KV_FFI_INCLUDE_DIR={}", out_dir.display());
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    let header = out_dir.join("kv_store.h");
+    fs::create_dir_all(&out_dir).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/lib.rs"))
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(&header);
+
+    if env::var_os("KV_FFI_WRITE_HEADER").is_some() {
+        fs::copy(&header, crate_dir.join("include/kv_store.h")).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+#ifndef KV_STORE_H
+#define KV_STORE_H
+
+/* Generated by cbindgen from kv_ffi/src/lib.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What a call came to
+ */
+typedef enum KvStatus {
+  KV_STATUS_OK = 0,
+  /**
+   * The key has no value, or the iterator is done
+   */
+  KV_STATUS_NOT_FOUND = 1,
+  /**
+   * A null pointer or a key or value the store doesn't accept
+   */
+  KV_STATUS_INVALID_ARGUMENT = 2,
+  /**
+   * Reading or writing the data file failed
+   */
+  KV_STATUS_IO = 3,
+  /**
+   * The file isn't a store, or is corrupted
+   */
+  KV_STATUS_CORRUPTED = 4,
+  /**
+   * The library hit a bug. The store may be left halfway through a change, so it should
+   * be closed
+   */
+  KV_STATUS_PANICKED = 5,
+} KvStatus;
+
+/**
+ * The records a scan found, created by `kv_iter_new` and freed by `kv_iter_free`
+ */
+typedef struct KvIter KvIter;
+
+/**
+ * An open store, created by `kv_open` and freed by `kv_close`
+ */
+typedef struct KvStore KvStore;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ *
+ * Opens the store at `path`, creating it if needed, and loads its index
+ *
+ * Returns `NULL` on failure, with the reason given by `kv_last_error`.
+ *
+ * # Safety
+ *
+ * `path` must be `NULL` or a NUL terminated string.
+ *
+ */
+struct KvStore *kv_open(const char *path);
+
+/**
+ *
+ * Opens the store at `path` for reading only, next to other readers
+ *
+ * Like `kv_open`, but writing to the store fails.
+ *
+ * # Safety
+ *
+ * `path` must be `NULL` or a NUL terminated string.
+ *
+ */
+struct KvStore *kv_open_read_only(const char *path);
+
+/**
+ *
+ * Closes a store opened by `kv_open`, doing nothing if it's `NULL`
+ *
+ * # Safety
+ *
+ * `store` must be `NULL` or a handle from `kv_open` that isn't used afterwards.
+ *
+ */
+void kv_close(struct KvStore *store);
+
+/**
+ *
+ * Looks up `key`, setting `value` and `value_len` to a copy of its value
+ *
+ * Returns `KV_STATUS_NOT_FOUND` if the key has no value, deleted keys included. The copy
+ * must be freed with `kv_free`.
+ *
+ * # Safety
+ *
+ * `store` must be a handle from `kv_open`, `key` must point to `key_len` bytes, and
+ * `value` and `value_len` must be writable.
+ *
+ */
+enum KvStatus kv_get(struct KvStore *store,
+                     const uint8_t *key,
+                     size_t key_len,
+                     uint8_t **value,
+                     size_t *value_len);
+
+/**
+ *
+ * Sets `key` to `value`
+ *
+ * Values can't be empty, since empty values are how deletions are stored.
+ *
+ * # Safety
+ *
+ * `store` must be a handle from `kv_open`, and `key` and `value` must point to `key_len`
+ * and `value_len` bytes.
+ *
+ */
+enum KvStatus kv_put(struct KvStore *store,
+                     const uint8_t *key,
+                     size_t key_len,
+                     const uint8_t *value,
+                     size_t value_len);
+
+/**
+ *
+ * Deletes `key`
+ *
+ * Returns `KV_STATUS_NOT_FOUND` if it had no value.
+ *
+ * # Safety
+ *
+ * `store` must be a handle from `kv_open`, and `key` must point to `key_len` bytes.
+ *
+ */
+enum KvStatus kv_delete(struct KvStore *store, const uint8_t *key, size_t key_len);
+
+/**
+ *
+ * Frees a value returned by `kv_get`, doing nothing if it's `NULL`
+ *
+ * # Safety
+ *
+ * `value` and `value_len` must be as `kv_get` set them, and not freed before.
+ *
+ */
+void kv_free(uint8_t *value, size_t value_len);
+
+/**
+ *
+ * Starts iterating over every key starting with `prefix`, in key order
+ *
+ * The records are read up front, so writes made while iterating aren't seen. Returns
+ * `NULL` on failure, with the reason given by `kv_last_error`.
+ *
+ * # Safety
+ *
+ * `store` must be a handle from `kv_open`, and `prefix` must point to `prefix_len`
+ * bytes. It may be `NULL` if `prefix_len` is zero, to iterate over every key.
+ *
+ */
+struct KvIter *kv_iter_new(struct KvStore *store, const uint8_t *prefix, size_t prefix_len);
+
+/**
+ *
+ * Moves to the next record, setting `key` and `value` to point to it
+ *
+ * Returns `KV_STATUS_NOT_FOUND` once every record was seen. The pointers stay valid until
+ * the next call with this iterator, and must not be freed.
+ *
+ * # Safety
+ *
+ * `iter` must be a handle from `kv_iter_new`, and the other arguments must be writable.
+ *
+ */
+enum KvStatus kv_iter_next(struct KvIter *iter,
+                           const uint8_t **key,
+                           size_t *key_len,
+                           const uint8_t **value,
+                           size_t *value_len);
+
+/**
+ *
+ * Frees an iterator from `kv_iter_new`, doing nothing if it's `NULL`
+ *
+ * # Safety
+ *
+ * `iter` must be `NULL` or a handle from `kv_iter_new` that isn't used afterwards.
+ *
+ */
+void kv_iter_free(struct KvIter *iter);
+
+/**
+ *
+ * Describes the last failure on the calling thread, or returns `NULL` if there was none
+ *
+ * The string stays valid until the next failing call on the same thread.
+ *
+ */
+const char *kv_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* KV_STORE_H */
@@ -0,0 +1,469 @@
+//!
+//! C API for the key value store
+//!
+//! Exposes `KV` to C and everything that can call C, such as Python through `ctypes`. The
+//! declarations are in `include/kv_store.h`, and `tests/c/kv_test.c` shows how they're
+//! used. The build generates the header from this file into its output directory, and
+//! copies it to `include` when `KV_FFI_WRITE_HEADER` is set.
+//!
+//! Every function returning a `KvStatus` leaves a message for `kv_last_error` when it
+//! fails, as does `kv_open` when it returns `NULL`. Panics never reach C: they're reported
+//! as `KV_STATUS_PANICKED`, or as `NULL`. Keys and values are byte strings given
+//! as a pointer and a length. A store handle must not be used by two threads at once.
+//!
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char},
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr, slice,
+};
+
+use kv_store::{ByteString, KV};
+
+/// What a call came to
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvStatus {
+    Ok = 0,
+    /// The key has no value, or the iterator is done
+    NotFound = 1,
+    /// A null pointer or a key or value the store doesn't accept
+    InvalidArgument = 2,
+    /// Reading or writing the data file failed
+    Io = 3,
+    /// The file isn't a store, or is corrupted
+    Corrupted = 4,
+    /// The library hit a bug. The store may be left halfway through a change, so it should
+    /// be closed
+    Panicked = 5,
+}
+
+/// An open store, created by `kv_open` and freed by `kv_close`
+pub struct KvStore {
+    kv: KV,
+}
+
+/// The records a scan found, created by `kv_iter_new` and freed by `kv_iter_free`
+pub struct KvIter {
+    records: std::vec::IntoIter<(ByteString, ByteString)>,
+    // Kept here so the pointers handed out stay valid until the next call
+    current: Option<(ByteString, ByteString)>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    // Messages never hold a NUL byte unless a path does, which is cut off there
+    let message = message.to_string();
+    let message = message.split('\0').next().unwrap_or_default();
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Records `err` for `kv_last_error`, returning the status telling about it
+fn failed(err: io::Error) -> KvStatus {
+    let status = match err.kind() {
+        io::ErrorKind::NotFound => KvStatus::NotFound,
+        io::ErrorKind::InvalidInput => KvStatus::InvalidArgument,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => KvStatus::Corrupted,
+        _ => KvStatus::Io,
+    };
+    set_last_error(err);
+    status
+}
+
+///
+/// Runs `f`, turning a panic into `on_panic` so that it never unwinds into C
+///
+/// Unwinding out of an `extern "C"` function aborts the whole process. The panic message
+/// is left for `kv_last_error`.
+///
+fn guard<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => payload
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "unknown cause".into()),
+        };
+        set_last_error(format!("panicked: {message}"));
+        on_panic
+    })
+}
+
+fn invalid_argument(what: &str) -> KvStatus {
+    set_last_error(format!("{what} must not be NULL"));
+    KvStatus::InvalidArgument
+}
+
+/// Borrows `len` bytes at `data`, which may be `NULL` if `len` is zero
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        // SAFETY: the caller guarantees `data` points to `len` readable bytes
+        (false, _) => Some(unsafe { slice::from_raw_parts(data, len) }),
+    }
+}
+
+///
+/// Hands `value` over to C, to be freed with `kv_free`
+///
+/// Boxed slices are freed with the length alone, unlike vectors which need the capacity.
+///
+fn give(value: ByteString, out: *mut *mut u8, out_len: *mut usize) {
+    let value = value.into_boxed_slice();
+    // SAFETY: the callers checked that both pointers aren't NULL
+    unsafe {
+        *out_len = value.len();
+        *out = Box::into_raw(value).cast();
+    }
+}
+
+///
+/// Opens the store at `path`, creating it if needed, and loads its index
+///
+/// Returns `NULL` on failure, with the reason given by `kv_last_error`.
+///
+/// # Safety
+///
+/// `path` must be `NULL` or a NUL terminated string.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_open(path: *const c_char) -> *mut KvStore {
+    guard(ptr::null_mut(), || unsafe { open(path, KV::open) })
+}
+
+///
+/// Opens the store at `path` for reading only, next to other readers
+///
+/// Like `kv_open`, but writing to the store fails.
+///
+/// # Safety
+///
+/// `path` must be `NULL` or a NUL terminated string.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_open_read_only(path: *const c_char) -> *mut KvStore {
+    guard(ptr::null_mut(), || unsafe {
+        open(path, KV::open_read_only)
+    })
+}
+
+unsafe fn open(path: *const c_char, open_kv: fn(&Path) -> io::Result<KV>) -> *mut KvStore {
+    if path.is_null() {
+        invalid_argument("path");
+        return ptr::null_mut();
+    }
+    // SAFETY: the caller guarantees `path` is NUL terminated
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str() else {
+        set_last_error("path isn't UTF-8");
+        return ptr::null_mut();
+    };
+
+    let opened = open_kv(Path::new(path)).and_then(|mut kv| {
+        kv.load()?;
+        Ok(kv)
+    });
+    match opened {
+        Ok(kv) => Box::into_raw(Box::new(KvStore { kv })),
+        Err(err) => {
+            failed(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+///
+/// Closes a store opened by `kv_open`, doing nothing if it's `NULL`
+///
+/// # Safety
+///
+/// `store` must be `NULL` or a handle from `kv_open` that isn't used afterwards.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_close(store: *mut KvStore) {
+    guard((), || {
+        if !store.is_null() {
+            // SAFETY: the handle came from `Box::into_raw` in `kv_open`
+            drop(unsafe { Box::from_raw(store) });
+        }
+    })
+}
+
+///
+/// Looks up `key`, setting `value` and `value_len` to a copy of its value
+///
+/// Returns `KV_STATUS_NOT_FOUND` if the key has no value, deleted keys included. The copy
+/// must be freed with `kv_free`.
+///
+/// # Safety
+///
+/// `store` must be a handle from `kv_open`, `key` must point to `key_len` bytes, and
+/// `value` and `value_len` must be writable.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_get(
+    store: *mut KvStore,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> KvStatus {
+    guard(KvStatus::Panicked, || {
+        // SAFETY: the caller guarantees the handle is valid and not used elsewhere
+        let Some(store) = (unsafe { store.as_mut() }) else {
+            return invalid_argument("store");
+        };
+        let Some(key) = (unsafe { bytes(key, key_len) }) else {
+            return invalid_argument("key");
+        };
+        if value.is_null() || value_len.is_null() {
+            return invalid_argument("value");
+        }
+
+        match store.kv.get(key) {
+            // Deleted keys are stored as empty values
+            Ok(Some(found)) if !found.is_empty() => {
+                give(found, value, value_len);
+                KvStatus::Ok
+            }
+            Ok(_) => KvStatus::NotFound,
+            Err(err) => failed(err),
+        }
+    })
+}
+
+///
+/// Sets `key` to `value`
+///
+/// Values can't be empty, since empty values are how deletions are stored.
+///
+/// # Safety
+///
+/// `store` must be a handle from `kv_open`, and `key` and `value` must point to `key_len`
+/// and `value_len` bytes.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_put(
+    store: *mut KvStore,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> KvStatus {
+    guard(KvStatus::Panicked, || {
+        // SAFETY: the caller guarantees the handle is valid and not used elsewhere
+        let Some(store) = (unsafe { store.as_mut() }) else {
+            return invalid_argument("store");
+        };
+        let (Some(key), Some(value)) = (unsafe { (bytes(key, key_len), bytes(value, value_len)) })
+        else {
+            return invalid_argument("key and value");
+        };
+        if value.is_empty() {
+            set_last_error("values can't be empty, use kv_delete");
+            return KvStatus::InvalidArgument;
+        }
+
+        match store.kv.insert(key, value) {
+            Ok(()) => KvStatus::Ok,
+            Err(err) => failed(err),
+        }
+    })
+}
+
+///
+/// Deletes `key`
+///
+/// Returns `KV_STATUS_NOT_FOUND` if it had no value.
+///
+/// # Safety
+///
+/// `store` must be a handle from `kv_open`, and `key` must point to `key_len` bytes.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_delete(
+    store: *mut KvStore,
+    key: *const u8,
+    key_len: usize,
+) -> KvStatus {
+    guard(KvStatus::Panicked, || {
+        // SAFETY: the caller guarantees the handle is valid and not used elsewhere
+        let Some(store) = (unsafe { store.as_mut() }) else {
+            return invalid_argument("store");
+        };
+        let Some(key) = (unsafe { bytes(key, key_len) }) else {
+            return invalid_argument("key");
+        };
+
+        match store.kv.get(key) {
+            Ok(Some(found)) if !found.is_empty() => match store.kv.delete(key) {
+                Ok(()) => KvStatus::Ok,
+                Err(err) => failed(err),
+            },
+            Ok(_) => KvStatus::NotFound,
+            Err(err) => failed(err),
+        }
+    })
+}
+
+///
+/// Frees a value returned by `kv_get`, doing nothing if it's `NULL`
+///
+/// # Safety
+///
+/// `value` and `value_len` must be as `kv_get` set them, and not freed before.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_free(value: *mut u8, value_len: usize) {
+    guard((), || {
+        if !value.is_null() {
+            // SAFETY: the value came from `Box::into_raw` in `give`, with this length
+            drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(value, value_len)) });
+        }
+    })
+}
+
+///
+/// Starts iterating over every key starting with `prefix`, in key order
+///
+/// The records are read up front, so writes made while iterating aren't seen. Returns
+/// `NULL` on failure, with the reason given by `kv_last_error`.
+///
+/// # Safety
+///
+/// `store` must be a handle from `kv_open`, and `prefix` must point to `prefix_len`
+/// bytes. It may be `NULL` if `prefix_len` is zero, to iterate over every key.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter_new(
+    store: *mut KvStore,
+    prefix: *const u8,
+    prefix_len: usize,
+) -> *mut KvIter {
+    guard(ptr::null_mut(), || {
+        // SAFETY: the caller guarantees the handle is valid and not used elsewhere
+        let Some(store) = (unsafe { store.as_mut() }) else {
+            invalid_argument("store");
+            return ptr::null_mut();
+        };
+        let Some(prefix) = (unsafe { bytes(prefix, prefix_len) }) else {
+            invalid_argument("prefix");
+            return ptr::null_mut();
+        };
+
+        match store.kv.scan(prefix) {
+            Ok(found) => {
+                let mut records: Vec<(ByteString, ByteString)> =
+                    found.into_iter().map(|kv| (kv.key, kv.value)).collect();
+                records.sort();
+                let iter = KvIter {
+                    records: records.into_iter(),
+                    current: None,
+                };
+                Box::into_raw(Box::new(iter))
+            }
+            Err(err) => {
+                failed(err);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+///
+/// Moves to the next record, setting `key` and `value` to point to it
+///
+/// Returns `KV_STATUS_NOT_FOUND` once every record was seen. The pointers stay valid until
+/// the next call with this iterator, and must not be freed.
+///
+/// # Safety
+///
+/// `iter` must be a handle from `kv_iter_new`, and the other arguments must be writable.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter_next(
+    iter: *mut KvIter,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> KvStatus {
+    guard(KvStatus::Panicked, || {
+        // SAFETY: the caller guarantees the handle is valid and not used elsewhere
+        let Some(iter) = (unsafe { iter.as_mut() }) else {
+            return invalid_argument("iter");
+        };
+        if key.is_null() || key_len.is_null() || value.is_null() || value_len.is_null() {
+            return invalid_argument("key and value");
+        }
+
+        iter.current = iter.records.next();
+        let Some((k, v)) = &iter.current else {
+            return KvStatus::NotFound;
+        };
+        // SAFETY: checked not to be NULL above
+        unsafe {
+            (*key, *key_len) = (k.as_ptr(), k.len());
+            (*value, *value_len) = (v.as_ptr(), v.len());
+        }
+        KvStatus::Ok
+    })
+}
+
+///
+/// Frees an iterator from `kv_iter_new`, doing nothing if it's `NULL`
+///
+/// # Safety
+///
+/// `iter` must be `NULL` or a handle from `kv_iter_new` that isn't used afterwards.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter_free(iter: *mut KvIter) {
+    guard((), || {
+        if !iter.is_null() {
+            // SAFETY: the handle came from `Box::into_raw` in `kv_iter_new`
+            drop(unsafe { Box::from_raw(iter) });
+        }
+    })
+}
+
+///
+/// Describes the last failure on the calling thread, or returns `NULL` if there was none
+///
+/// The string stays valid until the next failing call on the same thread.
+///
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_last_error() -> *const c_char {
+    guard(ptr::null(), || {
+        LAST_ERROR.with(|last| match &*last.borrow() {
+            Some(message) => message.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_are_reported_as_errors() {
+        let status = guard(KvStatus::Panicked, || panic!("bug in {}", "kv_get"));
+        assert_eq!(status, KvStatus::Panicked);
+        // SAFETY: the message is NUL terminated, and nothing fails before it's read
+        let message = unsafe { CStr::from_ptr(kv_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "panicked: bug in kv_get");
+
+        assert_eq!(guard(KvStatus::Panicked, || KvStatus::Ok), KvStatus::Ok);
+        assert!(guard(ptr::null_mut(), || -> *mut KvStore { panic!() }).is_null());
+    }
+}
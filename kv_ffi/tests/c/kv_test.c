@@ -0,0 +1,79 @@
+/*
+ * Exercises the C API, as a C program would use it
+ *
+ * Run by `cargo test -p kv_ffi`, which builds it against the library. By hand:
+ *
+ *     cargo build -p kv_ffi
+ *     cc -I kv_ffi/include kv_ffi/tests/c/kv_test.c -L target/debug -lkv_ffi -o kv_test
+ *     LD_LIBRARY_PATH=target/debug ./kv_test /tmp/kv_test.db
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "kv_store.h"
+
+#define CHECK(condition)                                                      \
+    do {                                                                      \
+        if (!(condition)) {                                                   \
+            const char *error = kv_last_error();                              \
+            fprintf(stderr, "%s:%d: %s failed (%s)\n", __FILE__, __LINE__,    \
+                    #condition, error ? error : "no error");                  \
+            exit(1);                                                          \
+        }                                                                     \
+    } while (0)
+
+static KvStatus put(KvStore *store, const char *key, const char *value) {
+    return kv_put(store, (const uint8_t *)key, strlen(key),
+                  (const uint8_t *)value, strlen(value));
+}
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+    remove(argv[1]);
+
+    KvStore *store = kv_open(argv[1]);
+    CHECK(store != NULL);
+    CHECK(put(store, "fruit/apple", "red") == KV_STATUS_OK);
+    CHECK(put(store, "fruit/kiwi", "green") == KV_STATUS_OK);
+    CHECK(put(store, "veg/leek", "white") == KV_STATUS_OK);
+    CHECK(put(store, "empty", "") == KV_STATUS_INVALID_ARGUMENT);
+    CHECK(kv_last_error() != NULL);
+
+    uint8_t *value;
+    size_t value_len;
+    CHECK(kv_get(store, (const uint8_t *)"fruit/kiwi", 10, &value, &value_len) == KV_STATUS_OK);
+    CHECK(value_len == 5 && memcmp(value, "green", 5) == 0);
+    kv_free(value, value_len);
+
+    CHECK(kv_delete(store, (const uint8_t *)"veg/leek", 8) == KV_STATUS_OK);
+    CHECK(kv_delete(store, (const uint8_t *)"veg/leek", 8) == KV_STATUS_NOT_FOUND);
+    CHECK(kv_get(store, (const uint8_t *)"veg/leek", 8, &value, &value_len) == KV_STATUS_NOT_FOUND);
+    kv_close(store);
+
+    /* Everything written is there once the store is opened again */
+    store = kv_open_read_only(argv[1]);
+    CHECK(store != NULL);
+    CHECK(put(store, "veg/leek", "white") == KV_STATUS_IO);
+
+    KvIter *iter = kv_iter_new(store, (const uint8_t *)"fruit/", 6);
+    CHECK(iter != NULL);
+    const char *expected[][2] = {{"fruit/apple", "red"}, {"fruit/kiwi", "green"}};
+    const uint8_t *key;
+    size_t key_len;
+    const uint8_t *found;
+    size_t found_len;
+    for (int i = 0; i < 2; i++) {
+        CHECK(kv_iter_next(iter, &key, &key_len, &found, &found_len) == KV_STATUS_OK);
+        CHECK(key_len == strlen(expected[i][0]) && memcmp(key, expected[i][0], key_len) == 0);
+        CHECK(found_len == strlen(expected[i][1]) && memcmp(found, expected[i][1], found_len) == 0);
+    }
+    CHECK(kv_iter_next(iter, &key, &key_len, &found, &found_len) == KV_STATUS_NOT_FOUND);
+    kv_iter_free(iter);
+    kv_close(store);
+
+    CHECK(kv_open(NULL) == NULL);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,54 @@
+//!
+//! Builds the C test program against the library and runs it
+//!
+
+#![cfg(unix)]
+
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+fn c_program_uses_the_api() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // Tests run from target/<profile>/deps, next to which the library was built
+    let exe = env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap().parent().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let program = dir.path().join("kv_test");
+
+    // The header shipped with the sources must match the one the build generates
+    let include_dir = PathBuf::from(env!("KV_FFI_INCLUDE_DIR"));
+    let generated = std::fs::read_to_string(include_dir.join("kv_store.h")).unwrap();
+    let shipped = std::fs::read_to_string(manifest_dir.join("include/kv_store.h")).unwrap();
+    assert!(
+        generated == shipped,
+        "include/kv_store.h is out of date, rebuild with KV_FFI_WRITE_HEADER=1"
+    );
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".into());
+    let status = Command::new(compiler)
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(&include_dir)
+        .arg(manifest_dir.join("tests/c/kv_test.c"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lkv_ffi")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("Unable to run the C compiler");
+    assert!(status.success());
+
+    let output = Command::new(&program)
+        .arg(dir.path().join("store.db"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"ok\n");
+}
@@ -0,0 +1,88 @@
+//!
+//! Criterion benchmarks of the basic store operations
+//!
+//! `cargo bench --bench kv` runs them. For workloads mixing operations, see `kv_bench`.
+//!
+
+use std::hint::black_box;
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+
+use kv_store::{ByteString, Checksum, KV};
+
+const KEYS: usize = 10_000;
+const VALUE_LEN: usize = 100;
+
+fn key(n: usize) -> ByteString {
+    format!("key-{n:08}").into_bytes()
+}
+
+/// A store in a fresh directory holding `KEYS` keys
+fn filled_store(checksum: Checksum) -> (tempfile::TempDir, KV) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+    store.set_checksum(checksum).unwrap();
+    for n in 0..KEYS {
+        store.insert(&key(n), &[n as u8; VALUE_LEN]).unwrap();
+    }
+    (dir, store)
+}
+
+fn insert(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+    let value = [7; VALUE_LEN];
+    let mut n = 0;
+
+    c.bench_function("insert", |b| {
+        b.iter(|| {
+            n += 1;
+            store.insert(&key(n % KEYS), &value).unwrap();
+        })
+    });
+}
+
+fn get(c: &mut Criterion) {
+    let (_dir, mut store) = filled_store(Checksum::default());
+    let mut n = 0;
+
+    c.bench_function("get", |b| {
+        b.iter(|| {
+            n = (n + 7919) % KEYS;
+            black_box(store.get(&key(n)).unwrap());
+        })
+    });
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    for checksum in Checksum::ALL {
+        let (dir, store) = filled_store(checksum);
+        drop(store);
+        let path = dir.path().join("store.db");
+
+        group.bench_function(checksum.to_string(), |b| {
+            b.iter_batched(
+                || KV::open_read_only(&path).unwrap(),
+                |mut store| store.load().unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn checksums(c: &mut Criterion) {
+    let data: ByteString = (0..1 << 20).map(|i: u32| (i * 31) as u8).collect();
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(criterion::Throughput::Bytes(data.len() as u64));
+    for checksum in Checksum::ALL {
+        group.bench_function(checksum.to_string(), |b| {
+            b.iter(|| checksum.compute(black_box(&data)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, get, load, checksums);
+criterion_main!(benches);
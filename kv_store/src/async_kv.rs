@@ -0,0 +1,191 @@
+//!
+//! Async facade over `KV`, for use from tokio services
+//!
+//! `KV` does blocking file I/O, which would stall an executor thread. `AsyncKV` moves the
+//! store onto a dedicated thread instead, and every call is sent there as a job. The
+//! future only waits for the answer, so it never blocks. The store is used as is, so files
+//! stay readable by the blocking API and the CLI.
+//!
+
+use std::{
+    io,
+    path::Path,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use tokio::sync::oneshot;
+
+use crate::store::{ByteStr, ByteString, KV, KeyValuePair};
+
+type Job = Box<dyn FnOnce(&mut KV) + Send>;
+
+///
+/// Handle to a store running on its own thread
+///
+/// Cloning is cheap and every clone talks to the same store. Calls are carried out one at
+/// a time, in the order they were made. The thread stops once all handles are dropped.
+///
+#[derive(Clone)]
+pub struct AsyncKV {
+    jobs: Sender<Job>,
+}
+
+impl AsyncKV {
+    /// Takes over a store, which should already be loaded
+    pub fn new(mut store: KV) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name("kv-io".into())
+            .spawn(move || {
+                for job in queue {
+                    job(&mut store);
+                }
+            })
+            .expect("failed to spawn store thread");
+
+        Self { jobs }
+    }
+
+    /// Opens and loads the store at `path` without blocking the caller
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let (tx, rx) = oneshot::channel();
+
+        thread::spawn(move || {
+            let opened = KV::open(&path).and_then(|mut store| {
+                store.load()?;
+                Ok(store)
+            });
+            let _ = tx.send(opened);
+        });
+
+        let store = rx.await.map_err(|_| stopped())??;
+        Ok(Self::new(store))
+    }
+
+    /// Runs `f` on the store's thread and waits for its result
+    async fn call<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut KV) -> io::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |store| {
+            // The caller may have stopped waiting, that's fine
+            let _ = tx.send(f(store));
+        });
+
+        self.jobs.send(job).map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+
+    pub async fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let key = key.to_vec();
+        self.call(move |store| store.get(&key)).await
+    }
+
+    pub async fn put(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.call(move |store| store.insert(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        let key = key.to_vec();
+        self.call(move |store| store.delete(&key)).await
+    }
+
+    /// See `KV::scan`
+    pub async fn scan(&self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let prefix = prefix.to_vec();
+        self.call(move |store| store.scan(&prefix)).await
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("store thread stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn calls_run_on_the_store_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let mut store = KV::open(&path).unwrap();
+        store.insert(b"apple", b"red").unwrap();
+        drop(store);
+
+        let kv = AsyncKV::open(&path).await.unwrap();
+        assert_eq!(kv.get(b"apple").await.unwrap(), Some(b"red".to_vec()));
+        kv.put(b"banana", b"yellow").await.unwrap();
+        kv.delete(b"apple").await.unwrap();
+        assert_eq!(kv.get(b"apple").await.unwrap(), Some(Vec::new()));
+        assert_eq!(kv.get(b"cherry").await.unwrap(), None);
+        let scanned = kv.scan(b"b").await.unwrap();
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned[0].value, b"yellow");
+
+        // Errors come back to the caller, and the store goes on
+        let long_key = vec![0; crate::store::Limits::default().max_key_len + 1];
+        let err = kv.put(&long_key, b"").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(kv.get(b"banana").await.unwrap(), Some(b"yellow".to_vec()));
+
+        // Opening a missing or locked store fails without blocking
+        assert!(AsyncKV::open(&path).await.is_err());
+        assert!(
+            AsyncKV::open(&dir.path().join("missing/store.db"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_callers_share_the_store_until_it_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let kv = AsyncKV::new(KV::open(&path).unwrap());
+
+        let tasks: Vec<_> = (0..16u32)
+            .map(|task| {
+                let kv = kv.clone();
+                tokio::spawn(async move {
+                    for i in 0..50u32 {
+                        let key = [task.to_le_bytes(), i.to_le_bytes()].concat();
+                        kv.put(&key, &i.to_le_bytes()).await.unwrap();
+                        assert_eq!(kv.get(&key).await.unwrap(), Some(i.to_le_bytes().to_vec()));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(kv.scan(b"").await.unwrap().len(), 16 * 50);
+
+        // Dropping the last handle stops the thread, which closes the store and releases
+        // its lock
+        let clone = kv.clone();
+        drop(kv);
+        assert!(KV::open(&path).is_err());
+        drop(clone);
+        let started = Instant::now();
+        let mut store = loop {
+            match KV::open(&path) {
+                Ok(store) => break store,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(started.elapsed() < Duration::from_secs(10));
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(err) => panic!("{err}"),
+            }
+        };
+        store.load().unwrap();
+        assert_eq!(store.index().len(), 16 * 50);
+    }
+}
@@ -0,0 +1,512 @@
+//!
+//! Online backups of the log, and restoring them
+//!
+//! A backup is a directory of segments. Each segment holds a contiguous range of the log:
+//! a full backup writes a single segment starting at offset 0, and every incremental backup
+//! adds one starting where the previous one ended. A segment file is:
+//!
+//! ```text
+//! [magic: 8 bytes][start: u64][end: u64][checksum: u32][log id: u64][generation: u64]
+//! [log bytes: end - start]
+//! ```
+//!
+//! Records are only appended, so recording the end of the last whole record when a backup
+//! starts is enough for a consistent copy, however long copying takes and however many
+//! records are appended meanwhile. A record cut short by a crash after it may be truncated
+//! when loading, but the bytes before it stay.
+//!
+//! Compaction replaces the whole file though, so offsets from before it mean nothing after.
+//! What makes adding to a backup safe is the log id and generation from the file header:
+//! they tell which log, and which rewrite of it, a segment was copied from, and an
+//! incremental backup is only added to segments of the same one. Stores created
+//! before the header recorded them get segments without, using the old magic, and only
+//! full backups.
+//!
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::{
+    format::{Format, LogId},
+    store::KV,
+};
+
+const MAGIC: &[u8; 8] = b"KVBACKUP";
+/// Magic of segments recording the log they were copied from
+const MAGIC_LOG_ID: &[u8; 8] = b"KVBACKLG";
+const SEGMENT_EXTENSION: &str = "kvbak";
+
+/// Bytes copied at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Header of a segment file
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    checksum: u32,
+    log_id: Option<LogId>,
+}
+
+impl Segment {
+    fn read<R: Read>(f: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != MAGIC && &magic != MAGIC_LOG_ID {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a backup segment",
+            ));
+        }
+
+        let start = f.read_u64::<LittleEndian>()?;
+        let end = f.read_u64::<LittleEndian>()?;
+        let checksum = f.read_u32::<LittleEndian>()?;
+        let log_id = match &magic == MAGIC_LOG_ID {
+            true => Some(LogId {
+                id: f.read_u64::<LittleEndian>()?,
+                generation: f.read_u64::<LittleEndian>()?,
+            }),
+            false => None,
+        };
+
+        Ok(Self {
+            start,
+            end,
+            checksum,
+            log_id,
+        })
+    }
+
+    fn write<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        f.write_all(match self.log_id {
+            Some(_) => MAGIC_LOG_ID,
+            None => MAGIC,
+        })?;
+        f.write_u64::<LittleEndian>(self.start)?;
+        f.write_u64::<LittleEndian>(self.end)?;
+        f.write_u32::<LittleEndian>(self.checksum)?;
+        if let Some(log_id) = self.log_id {
+            f.write_u64::<LittleEndian>(log_id.id)?;
+            f.write_u64::<LittleEndian>(log_id.generation)?;
+        }
+        Ok(())
+    }
+
+    fn file_name(start: u64) -> String {
+        // Zero padded, so sorting by name sorts by offset
+        format!("{start:020}.{SEGMENT_EXTENSION}")
+    }
+}
+
+/// Reads the headers of all segments in a backup directory, ordered by offset
+fn segments(dir: &Path) -> io::Result<Vec<(PathBuf, Segment)>> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+            let segment = Segment::read(&mut File::open(&path)?)?;
+            segments.push((path, segment));
+        }
+    }
+
+    segments.sort_by_key(|(_, segment)| segment.start);
+    Ok(segments)
+}
+
+///
+/// A range of the log waiting to be copied into a backup directory
+///
+/// Created by `KV::start_backup`. It doesn't borrow the store, so copying can happen on
+/// another thread while the store keeps taking writes.
+///
+#[derive(Debug)]
+pub struct Backup {
+    source: PathBuf,
+    dest: PathBuf,
+    start: u64,
+    end: u64,
+    log_id: Option<LogId>,
+}
+
+impl Backup {
+    /// Offset the log is backed up to once this backup is written
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    ///
+    /// Copies the range into a new segment, returning the number of bytes copied
+    ///
+    /// The segment is written under a temporary name and renamed once it's on disk, so a
+    /// backup interrupted halfway leaves no segment behind. Fails if the log was compacted
+    /// since the backup started.
+    ///
+    pub fn write(self) -> io::Result<u64> {
+        let len = self.end - self.start;
+        if len == 0 && self.start != 0 {
+            return Ok(0);
+        }
+
+        let path = self.dest.join(Segment::file_name(self.start));
+        let tmp_path = path.with_extension("tmp");
+
+        let mut source = File::open(&self.source)?;
+        if Format::read(&mut source)?.log_id != self.log_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "log was rewritten since the backup started",
+            ));
+        }
+        source.seek(SeekFrom::Start(self.start))?;
+        let mut source = source.take(len);
+
+        let mut f = BufWriter::new(File::create(&tmp_path)?);
+        // The checksum isn't known yet, it's filled in after copying
+        let mut segment = Segment {
+            start: self.start,
+            end: self.end,
+            checksum: 0,
+            log_id: self.log_id,
+        };
+        segment.write(&mut f)?;
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let n = source.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            segment.checksum = crc32::update(segment.checksum, &crc32::IEEE_TABLE, &buffer[..n]);
+            f.write_all(&buffer[..n])?;
+            copied += n as u64;
+        }
+
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "log is shorter than when the backup started",
+            ));
+        }
+
+        let mut f = f.into_inner().map_err(|err| err.into_error())?;
+        f.seek(SeekFrom::Start(0))?;
+        segment.write(&mut f)?;
+        f.sync_all()?;
+        drop(f);
+
+        fs::rename(&tmp_path, &path)?;
+        Ok(copied)
+    }
+}
+
+impl KV {
+    ///
+    /// Records the current end of the log as the end of a backup into `dest`
+    ///
+    /// The store is loaded first, so that's the end of the last whole record.
+    ///
+    /// If `dest` already holds a backup of this log, the new backup is incremental and only
+    /// covers what was appended since. It must be of the same generation of the log, so
+    /// not from before a compaction. Otherwise it's a full backup and `dest` is created
+    /// if needed.
+    ///
+    pub fn start_backup(&mut self, dest: &Path) -> io::Result<Backup> {
+        fs::create_dir_all(dest)?;
+        let last = segments(dest)?.pop().map(|(_, segment)| segment);
+        let log_id = self.format.log_id;
+        if let Some(last) = last {
+            let reason = match (last.log_id, log_id) {
+                (_, None) => Some("this store doesn't record which log it holds"),
+                (Some(backed_up), Some(log_id)) if backed_up.id == log_id.id => {
+                    (backed_up.generation != log_id.generation)
+                        .then_some("it was taken before the log was compacted")
+                }
+                _ => Some("it's a backup of another log"),
+            };
+            if let Some(reason) = reason {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "unable to add to the backup in {}: {reason}, a new full backup is needed",
+                        dest.display()
+                    ),
+                ));
+            }
+        }
+
+        let start = last.map_or(0, |segment| segment.end);
+        // Only whole records are backed up, since one cut short by a crash is dropped once
+        // the store is opened for writing, and others may have appended since the last load
+        self.load()?;
+        let end = self.loaded;
+
+        if end < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} holds a backup up to offset {start}, past the end of this log",
+                    dest.display()
+                ),
+            ));
+        }
+
+        Ok(Backup {
+            source: self.path.clone(),
+            dest: dest.to_path_buf(),
+            start,
+            end,
+            log_id,
+        })
+    }
+
+    ///
+    /// Makes a full backup of the store into the empty or missing directory `dest`
+    ///
+    /// Returns the offset the log is backed up to.
+    ///
+    pub fn backup(&mut self, dest: &Path) -> io::Result<u64> {
+        if dest.exists() && !segments(dest)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a backup", dest.display()),
+            ));
+        }
+
+        let backup = self.start_backup(dest)?;
+        let end = backup.end();
+        backup.write()?;
+        Ok(end)
+    }
+
+    ///
+    /// Adds what was appended to the log since the last backup into `dest` to it
+    ///
+    /// Returns the offset the log is backed up to.
+    ///
+    pub fn backup_incremental(&mut self, dest: &Path) -> io::Result<u64> {
+        if !dest.exists() || segments(dest)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} holds no backup to add to", dest.display()),
+            ));
+        }
+
+        let backup = self.start_backup(dest)?;
+        let end = backup.end();
+        backup.write()?;
+        Ok(end)
+    }
+}
+
+///
+/// Rebuilds a data file at `target` from the backup in `dir`
+///
+/// Segments are checked to follow on from each other, to come from the same generation of
+/// the same log and against their checksums before anything is written. `target` must not
+/// exist yet. Returns the size of the restored log.
+///
+pub fn restore(dir: &Path, target: &Path) -> io::Result<u64> {
+    let segments = segments(dir)?;
+    if segments.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} holds no backup", dir.display()),
+        ));
+    }
+
+    let mut expected_start = 0;
+    for (path, segment) in &segments {
+        if segment.log_id != segments[0].1.log_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is from another log than the first segment",
+                    path.display()
+                ),
+            ));
+        }
+        if segment.start != expected_start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "backup is missing offsets {expected_start} to {}",
+                    segment.start
+                ),
+            ));
+        }
+
+        let mut f = BufReader::new(File::open(path)?);
+        Segment::read(&mut f)?;
+        let mut checksum = 0;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = f.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            checksum = crc32::update(checksum, &crc32::IEEE_TABLE, &buffer[..n]);
+        }
+
+        if checksum != segment.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupted", path.display()),
+            ));
+        }
+
+        expected_start = segment.end;
+    }
+
+    let mut out = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(target)?,
+    );
+
+    for (path, segment) in &segments {
+        let mut f = BufReader::new(File::open(path)?);
+        Segment::read(&mut f)?;
+        let copied = io::copy(&mut f, &mut out)?;
+        debug_assert_eq!(copied, segment.end - segment.start);
+    }
+
+    let f = out.into_inner().map_err(|err| err.into_error())?;
+    f.sync_all()?;
+
+    Ok(expected_start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_range(store: &mut KV, keys: std::ops::Range<u32>) {
+        for i in keys {
+            store.insert(&i.to_le_bytes(), &i.to_be_bytes()).unwrap();
+        }
+    }
+
+    /// How many of the keys in `keys` have a value, deleted ones having an empty one
+    fn live(store: &mut KV, keys: std::ops::Range<u32>) -> usize {
+        keys.filter_map(|i| store.get(&i.to_le_bytes()).unwrap())
+            .filter(|value| !value.is_empty())
+            .count()
+    }
+
+    /// Restores the backup in `dir` to a new file, and loads it
+    fn restored(dir: &Path, target: &Path) -> io::Result<KV> {
+        restore(dir, target)?;
+        let mut store = KV::open(target)?;
+        store.load()?;
+        Ok(store)
+    }
+
+    #[test]
+    fn backups_restore_only_from_a_single_generation_of_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let backup_dir = dir.path().join("backup");
+
+        let mut store = KV::open(&path).unwrap();
+        insert_range(&mut store, 0..50);
+
+        // A full backup restores every key
+        let end = store.backup(&backup_dir).unwrap();
+        assert_eq!(end, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(
+            store.backup(&backup_dir).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let mut copy = restored(&backup_dir, &dir.path().join("full.db")).unwrap();
+        assert_eq!(live(&mut copy, 0..300), 50);
+        assert_eq!(copy.log_id(), store.log_id());
+
+        // An incremental backup adds what was written since
+        insert_range(&mut store, 50..80);
+        store.delete(&3u32.to_le_bytes()).unwrap();
+        store.backup_incremental(&backup_dir).unwrap();
+        let mut copy = restored(&backup_dir, &dir.path().join("incremental.db")).unwrap();
+        assert_eq!(live(&mut copy, 0..300), 79);
+        assert_eq!(
+            copy.get(&79u32.to_le_bytes()).unwrap(),
+            Some(79u32.to_be_bytes().to_vec())
+        );
+        assert_eq!(copy.get(&3u32.to_le_bytes()).unwrap(), Some(Vec::new()));
+        assert_eq!(segments(&backup_dir).unwrap().len(), 2);
+
+        // A record cut short by a crash isn't backed up, since it's dropped from the log
+        insert_range(&mut store, 80..90);
+        let whole = std::fs::metadata(&path).unwrap().len();
+        let torn = KV::encode_record(b"torn", b"value", store.format, None).unwrap();
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&torn[..torn.len() - 2]).unwrap();
+        assert_eq!(store.backup_incremental(&backup_dir).unwrap(), whole);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), whole);
+
+        // Compaction makes a new generation, which can't be added to a backup of the old one
+        let generation = store.log_id().unwrap().generation;
+        store.compact().unwrap();
+        assert_eq!(store.log_id().unwrap().generation, generation + 1);
+        insert_range(&mut store, 100..200);
+        let err = store.backup_incremental(&backup_dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(segments(&backup_dir).unwrap().len(), 3);
+
+        // Nor can a backup started before compacting be written after
+        let backup = store.start_backup(&dir.path().join("started")).unwrap();
+        store.compact().unwrap();
+        assert_eq!(
+            backup.write().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A new full backup has it all
+        let fresh = dir.path().join("fresh");
+        store.backup(&fresh).unwrap();
+        let mut copy = restored(&fresh, &dir.path().join("fresh.db")).unwrap();
+        assert_eq!(live(&mut copy, 0..300), 189);
+        assert_eq!(
+            copy.get(&5u32.to_le_bytes()).unwrap(),
+            Some(5u32.to_be_bytes().to_vec())
+        );
+
+        // Segments from different generations aren't restored together
+        let mixed = dir.path().join("mixed");
+        std::fs::create_dir(&mixed).unwrap();
+        let (first, _) = &segments(&backup_dir).unwrap()[0];
+        std::fs::copy(first, mixed.join(first.file_name().unwrap())).unwrap();
+        insert_range(&mut store, 200..210);
+        store.backup_incremental(&fresh).unwrap();
+        let (last, segment) = segments(&fresh).unwrap().pop().unwrap();
+        std::fs::copy(&last, mixed.join(Segment::file_name(end))).unwrap();
+        assert!(segment.start != end);
+        assert_eq!(
+            restore(&mixed, &dir.path().join("mixed.db"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Nor are corrupted ones, and nothing is written then
+        let mut bytes = std::fs::read(&last).unwrap();
+        let at = bytes.len() - 3;
+        bytes[at] ^= 0xff;
+        std::fs::write(&last, &bytes).unwrap();
+        let target = dir.path().join("corrupted.db");
+        assert_eq!(
+            restore(&fresh, &target).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(!target.exists());
+    }
+}
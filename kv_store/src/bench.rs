@@ -0,0 +1,144 @@
+//!
+//! Rough timings of the store's hot paths, printed to the console
+//!
+
+use std::{
+    collections::HashSet,
+    hint::black_box,
+    io,
+    num::NonZero,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+
+use crate::{
+    format::Checksum,
+    store::{ByteString, KV},
+};
+
+///
+/// Times looking up every key of a loaded store, `rounds` times over, once through
+/// `KV::get` and once through an `MmapReader`
+///
+pub fn compare_reads(store: &mut KV, rounds: usize) -> io::Result<()> {
+    let keys: Vec<ByteString> = store.index.keys().cloned().collect();
+    let lookups = keys.len() * rounds;
+    if lookups == 0 {
+        println!("nothing to read");
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for key in &keys {
+            black_box(store.get(key)?);
+        }
+    }
+    let seek_read = start.elapsed();
+
+    let reader = store.mmap_reader()?;
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for key in &keys {
+            black_box(reader.get(key)?);
+        }
+    }
+    let mmap = start.elapsed();
+
+    println!("{lookups} lookups of {} keys", keys.len());
+    println!(
+        "seek + read: {:>10.0?} ({:>6} ns/lookup)",
+        seek_read,
+        seek_read.as_nanos() / lookups as u128
+    );
+    println!(
+        "mmap:        {:>10.0?} ({:>6} ns/lookup)",
+        mmap,
+        mmap.as_nanos() / lookups as u128
+    );
+
+    Ok(())
+}
+
+///
+/// Times every checksum algorithm over `rounds` random buffers the size of a typical chunk
+/// of records
+///
+pub fn compare_checksums(rounds: usize) -> io::Result<()> {
+    const BUFFER_LEN: usize = 1 << 20;
+
+    let mut data = vec![0; BUFFER_LEN];
+    rand::thread_rng().fill_bytes(&mut data);
+    let bytes = (BUFFER_LEN * rounds) as f64;
+
+    println!("{rounds} rounds over {BUFFER_LEN} bytes");
+    for checksum in Checksum::ALL {
+        let start = Instant::now();
+        for _ in 0..rounds {
+            black_box(checksum.compute(black_box(&data)));
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:<9} {:>10.0?} ({:>8.1} MiB/s)",
+            format!("{checksum}:"),
+            elapsed,
+            bytes / elapsed.as_secs_f64() / (1 << 20) as f64
+        );
+    }
+
+    Ok(())
+}
+
+///
+/// Times loading the log of `store` from scratch, `rounds` times over, once on a single
+/// thread and once split among every core
+///
+/// Threads only check records, and indexing them is left to a single one, see
+/// `parallel_load`, so this tells what splitting the log actually gains.
+///
+pub fn compare_loads(store: &KV, rounds: usize) -> io::Result<()> {
+    let threads = thread::available_parallelism()
+        .map_or(1, NonZero::get)
+        .max(2);
+    let open = || {
+        let mut fresh = KV::open_read_only(&store.path)?;
+        if let Some(cipher) = &store.cipher {
+            fresh.set_cipher(cipher.clone());
+        }
+        Ok::<_, io::Error>(fresh)
+    };
+
+    let (mut serial, mut segmented) = (Duration::ZERO, Duration::ZERO);
+    for _ in 0..rounds {
+        let mut fresh = open()?;
+        let start = Instant::now();
+        fresh.load_serially(false, &mut HashSet::new())?;
+        serial += start.elapsed();
+
+        let mut fresh = open()?;
+        let start = Instant::now();
+        fresh.load_in_segments(threads, false, &mut HashSet::new())?;
+        segmented += start.elapsed();
+    }
+
+    let bytes = (store.f.metadata()?.len() * rounds as u64) as f64;
+    let rate = |elapsed: Duration| bytes / elapsed.as_secs_f64() / (1 << 20) as f64;
+    println!(
+        "{rounds} loads of {} bytes",
+        bytes as u64 / rounds.max(1) as u64
+    );
+    println!("1 thread:   {serial:>10.0?} ({:>8.1} MiB/s)", rate(serial));
+    println!(
+        "{threads} threads: {segmented:>10.0?} ({:>8.1} MiB/s)",
+        rate(segmented)
+    );
+    println!(
+        "speedup:    {:.2}x",
+        serial.as_secs_f64() / segmented.as_secs_f64()
+    );
+
+    Ok(())
+}
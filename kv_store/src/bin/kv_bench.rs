@@ -0,0 +1,26 @@
+//!
+//! Drives a store with a configurable mix of operations and reports how fast they were
+//!
+//! Run `kv_bench --help` for the options.
+//!
+
+use kv_store::loadgen::{self, Config};
+
+fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+
+    println!("{config:?}");
+    match loadgen::run(&config) {
+        Ok(report) => print!("{report}"),
+        Err(err) => {
+            eprintln!("benchmark failed: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//!
+//! Command line interface to a store
+//!
+//! Run `kv_mem` without arguments for the usage.
+//!
+
+use std::{collections::HashMap, net::TcpListener};
+
+use kv_store::{
+    ByteStr, ByteString, Change, Checksum, Cipher, KV, backup, bench, http, parity,
+    replication::{self, Follower, Received},
+};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    kv_mem.exe FILE get KEY
+    kv_mem.exe FILE delete KEY
+    kv_mem.exe FILE insert KEY VALUE
+    kv_mem.exe FILE update KEY VALUE
+    kv_mem.exe FILE tail OFFSET
+    kv_mem.exe FILE lead ADDRESS
+    kv_mem.exe FILE follow ADDRESS
+    kv_mem.exe FILE serve-http ADDRESS
+    kv_mem.exe FILE export-sstable PATH
+    kv_mem.exe FILE export-sstable-decrypted PATH
+    kv_mem.exe FILE backup DIRECTORY
+    kv_mem.exe FILE backup-incremental DIRECTORY
+    kv_mem.exe FILE restore DIRECTORY
+    kv_mem.exe FILE bench-reads ROUNDS
+    kv_mem.exe FILE bench-loads ROUNDS
+    kv_mem.exe FILE stats
+    kv_mem.exe FILE verify
+    kv_mem.exe FILE recover
+    kv_mem.exe FILE compact
+    kv_mem.exe FILE parity
+    kv_mem.exe FILE repair
+    kv_mem.exe FILE bench-checksums ROUNDS
+
+Set KV_KEY_FILE to a file holding a 32 byte key to encrypt new records and read
+encrypted ones. Sorted tables aren't encrypted, so encrypted stores are only
+exported with export-sstable-decrypted. Set KV_CHECKSUM to crc32, crc32c or
+xxhash64 to choose how a new store is checksummed.
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    kv_mem FILE get KEY
+    kv_mem FILE delete KEY
+    kv_mem FILE insert KEY VALUE
+    kv_mem FILE update KEY VALUE
+    kv_mem FILE tail OFFSET
+    kv_mem FILE lead ADDRESS
+    kv_mem FILE follow ADDRESS
+    kv_mem FILE serve-http ADDRESS
+    kv_mem FILE export-sstable PATH
+    kv_mem FILE export-sstable-decrypted PATH
+    kv_mem FILE backup DIRECTORY
+    kv_mem FILE backup-incremental DIRECTORY
+    kv_mem FILE restore DIRECTORY
+    kv_mem FILE bench-reads ROUNDS
+    kv_mem FILE bench-loads ROUNDS
+    kv_mem FILE stats
+    kv_mem FILE verify
+    kv_mem FILE recover
+    kv_mem FILE compact
+    kv_mem FILE parity
+    kv_mem FILE repair
+    kv_mem FILE bench-checksums ROUNDS
+
+Set KV_KEY_FILE to a file holding a 32 byte key to encrypt new records and read
+encrypted ones. Sorted tables aren't encrypted, so encrypted stores are only
+exported with export-sstable-decrypted. Set KV_CHECKSUM to crc32, crc32c or
+xxhash64 to choose how a new store is checksummed.
+";
+
+fn store_index_on_disk(a: &mut KV, index_key: &ByteStr) {
+    let index_as_bytes = bincode::serialize(a.index()).unwrap();
+    // Positions only make sense in this file, so the index is a local key
+    a.insert_local(index_key, &index_as_bytes).unwrap();
+}
+
+fn main() {
+    const INDEX_KEY: &ByteStr = b"index";
+    // Where the index was kept before local keys existed, in the default namespace
+    const LEGACY_INDEX_KEY: &ByteStr = b"+index";
+
+    // Arguments provided via CLI
+    let args: Vec<String> = std::env::args().collect();
+    // file name should be first
+    let file_name = args.get(1).expect(USAGE);
+    // action: get, insert, delete, update
+    let action = args.get(2).expect(USAGE).as_ref();
+    // Key must be specified, except for actions on the whole store
+    let key: &ByteStr = match action {
+        "stats" | "compact" | "parity" | "repair" | "verify" | "recover" => b"",
+        _ => args.get(3).expect(USAGE).as_ref(),
+    };
+    // Value should be there if action is 'insert' or 'update'
+    let maybe_value = args.get(4);
+
+    let path = std::path::Path::new(&file_name);
+
+    // The leader only reads the log and doesn't lock it, so writers can keep using the file
+    if action == "lead" {
+        let listener = TcpListener::bind(&args[3]).expect("Unable to listen");
+        replication::serve(path, listener).unwrap();
+        return;
+    }
+
+    // These work on the file itself, or not on a store at all
+    match action {
+        "parity" => {
+            let covered = parity::write_parity(path).unwrap();
+            println!("parity covers {covered} bytes");
+            return;
+        }
+        "repair" => {
+            let repaired = parity::repair(path).unwrap();
+            println!("repaired {repaired} blocks");
+            return;
+        }
+        "bench-checksums" => {
+            let rounds = args[3].parse().expect(USAGE);
+            bench::compare_checksums(rounds).unwrap();
+            return;
+        }
+        _ => {}
+    }
+
+    // Restoring creates the file, so there is nothing to open yet
+    if action == "restore" {
+        let dir = std::path::Path::new(&args[3]);
+        let size = backup::restore(dir, path).unwrap();
+        println!("restored {size} bytes");
+        return;
+    }
+
+    // Lookups only need a shared lock, so they can run next to other readers
+    let opened = match action {
+        "get"
+        | "tail"
+        | "backup"
+        | "backup-incremental"
+        | "export-sstable"
+        | "export-sstable-decrypted"
+        | "bench-reads"
+        | "bench-loads"
+        | "stats"
+        | "verify" => KV::open_read_only(path),
+        _ => KV::open(path),
+    };
+    let mut store = match opened {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("Unable to open file: {err}");
+            std::process::exit(1);
+        }
+    };
+    // Encryption is opt in, with the key file given through the environment
+    if let Ok(key_file) = std::env::var("KV_KEY_FILE") {
+        let cipher = Cipher::from_key_file(std::path::Path::new(&key_file))
+            .expect("Unable to read key file");
+        store.set_cipher(cipher);
+    }
+
+    // Only new stores can choose, existing ones keep what their header says
+    if let Ok(checksum) = std::env::var("KV_CHECKSUM") {
+        let checksum: Checksum = checksum.parse().expect("Unknown checksum algorithm");
+        if store.checksum() != checksum {
+            store
+                .set_checksum(checksum)
+                .expect("Unable to change checksum");
+        }
+    }
+
+    // Checked before loading, which fails on the first damaged record
+    if action == "verify" {
+        match store.verify() {
+            Ok(records) => println!("{records} records are intact"),
+            Err(err) => {
+                eprintln!("Verification failed: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Damaged records fail loading, unless asked to pass over them and compact them away
+    if action == "recover" {
+        let skipped = store.recover().expect("Unable to load data");
+        for span in &skipped {
+            println!("lost {}..{}", span.start, span.end);
+        }
+        if !skipped.is_empty() {
+            store.compact().unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY);
+        }
+        println!("recovered {} keys", store.index().len());
+        return;
+    }
+
+    store.load().expect("Unable to load data");
+
+    match action {
+        "get" => {
+            let index_as_bytes = match store.get_local(INDEX_KEY).unwrap() {
+                Some(index_as_bytes) => index_as_bytes,
+                None => store.get(LEGACY_INDEX_KEY).unwrap().unwrap(),
+            };
+            let index_decoded = bincode::deserialize(&index_as_bytes);
+            let index: HashMap<ByteString, u64> = index_decoded.unwrap();
+
+            match index.get(key) {
+                None => eprintln!("{key:?} not found"),
+                Some(&i) => {
+                    let kv = store.get_at(i).unwrap();
+                    println!("{:?}", kv.value);
+                }
+            }
+        }
+        "delete" => store.delete(key).unwrap(),
+        "insert" => {
+            let value = maybe_value.expect(USAGE).as_ref();
+            store.insert(key, value).unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY);
+        }
+        "update" => {
+            let value = maybe_value.expect(USAGE).as_ref();
+            store.update(key, value).unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY);
+        }
+        "tail" => {
+            // Key argument is the offset to start reading from
+            let offset: u64 = std::str::from_utf8(key)
+                .ok()
+                .and_then(|offset| offset.parse().ok())
+                .expect(USAGE);
+            let mut tail = store.tail(offset).unwrap();
+
+            for event in tail.by_ref() {
+                let event = event.unwrap();
+                match event.change {
+                    Change::Put(value) => {
+                        println!("{} put {:?} {:?}", event.offset, event.key, value)
+                    }
+                    Change::Delete => println!("{} delete {:?}", event.offset, event.key),
+                    Change::Merge(operand) => {
+                        println!("{} merge {:?} {:?}", event.offset, event.key, operand)
+                    }
+                }
+            }
+
+            // Passing this offset next time continues where this run stopped
+            println!("next offset: {}", tail.position());
+        }
+        "follow" => {
+            let mut follower = Follower::connect(&args[3], &mut store).unwrap();
+
+            loop {
+                // The leader's on-disk index isn't replicated, since its positions only make
+                // sense in the leader's file. `get` needs one, so it's stored locally instead
+                if let Received::CaughtUp(_) = follower.receive().unwrap() {
+                    store_index_on_disk(follower.store(), INDEX_KEY);
+                }
+            }
+        }
+        "export-sstable" => {
+            let table = std::path::Path::new(&args[3]);
+            let entries = store.export_sstable(table).unwrap();
+            println!("exported {entries} keys");
+        }
+        "export-sstable-decrypted" => {
+            let table = std::path::Path::new(&args[3]);
+            let entries = store.export_sstable_decrypted(table).unwrap();
+            println!("exported {entries} keys, decrypted");
+        }
+        "backup" => {
+            let dir = std::path::Path::new(&args[3]);
+            let end = store.backup(dir).unwrap();
+            println!("backed up to offset {end}");
+        }
+        "backup-incremental" => {
+            let dir = std::path::Path::new(&args[3]);
+            let end = store.backup_incremental(dir).unwrap();
+            println!("backed up to offset {end}");
+        }
+        "bench-reads" => {
+            let rounds = args[3].parse().expect(USAGE);
+            bench::compare_reads(&mut store, rounds).unwrap();
+        }
+        "bench-loads" => {
+            let rounds = args[3].parse().expect(USAGE);
+            bench::compare_loads(&store, rounds).unwrap();
+        }
+        "serve-http" => {
+            let listener = TcpListener::bind(&args[3]).expect("Unable to listen");
+            http::serve(store, listener).unwrap();
+        }
+        "stats" => print!("{}", store.stats().unwrap().to_prometheus()),
+        "compact" => {
+            store.compact().unwrap();
+            // Positions changed, so the on-disk index is rewritten
+            store_index_on_disk(&mut store, INDEX_KEY);
+            let stats = store.stats().unwrap();
+            println!("reclaimed {} bytes", stats.bytes_reclaimed);
+        }
+        _ => eprintln!("{}", &USAGE),
+    }
+}
@@ -0,0 +1,165 @@
+//!
+//! Large values, split over several records
+//!
+//! A record's value length is a u32, and records are read into memory whole. Values longer
+//! than `Limits::chunk_len` are instead written as a run of chunk records, each holding the
+//! key and the next part of the value, followed by a manifest record. Only the manifest is
+//! indexed, and its value is:
+//!
+//! ```text
+//! [value length: u64][chunk count: u64][distance back to the first chunk: u64]
+//! ```
+//!
+//! Chunks come right before their manifest. A large value that was only partly written
+//! leaves chunk records without a manifest behind, which are skipped like any other
+//! superseded record.
+//!
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    crypto::Cipher,
+    format::Format,
+    store::{ByteStr, ByteString, FLAG_CHUNK, FLAG_SYNC, KV, KeyValuePair},
+};
+
+/// The value of a manifest record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Manifest {
+    pub value_len: u64,
+    pub chunks: u64,
+    pub distance: u64,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> io::Result<ByteString> {
+        let mut encoded = ByteString::with_capacity(24);
+        encoded.write_u64::<LittleEndian>(self.value_len)?;
+        encoded.write_u64::<LittleEndian>(self.chunks)?;
+        encoded.write_u64::<LittleEndian>(self.distance)?;
+        Ok(encoded)
+    }
+
+    pub fn decode(mut encoded: &ByteStr) -> io::Result<Self> {
+        let manifest = Self {
+            value_len: encoded.read_u64::<LittleEndian>()?,
+            chunks: encoded.read_u64::<LittleEndian>()?,
+            distance: encoded.read_u64::<LittleEndian>()?,
+        };
+
+        if !encoded.is_empty() {
+            return Err(missing_chunks());
+        }
+        Ok(manifest)
+    }
+}
+
+pub(super) fn missing_chunks() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "large value doesn't match its chunks",
+    )
+}
+
+///
+/// Puts the value a manifest record stands for back together
+///
+/// `position` is where the manifest starts, and `manifest` the record read from there.
+/// Leaves `f` right after the last chunk.
+///
+pub(super) fn read_large<R: Read + Seek>(
+    f: &mut R,
+    position: u64,
+    manifest: &KeyValuePair,
+    format: Format,
+    cipher: Option<&Cipher>,
+) -> io::Result<ByteString> {
+    let Manifest {
+        value_len,
+        chunks,
+        distance,
+    } = Manifest::decode(&manifest.value)?;
+
+    let len = usize::try_from(value_len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("value of {value_len} bytes doesn't fit in memory"),
+        )
+    })?;
+    let start = position.checked_sub(distance).ok_or_else(missing_chunks)?;
+    f.seek(SeekFrom::Start(start))?;
+
+    let mut value = ByteString::with_capacity(len);
+    let mut read = 0;
+    while read < chunks {
+        let (header, chunk) =
+            KV::process_record(f, format, cipher).map_err(|err| match err.kind() {
+                // The manifest is only written after its chunks, so they can't be missing
+                io::ErrorKind::UnexpectedEof => missing_chunks(),
+                _ => err,
+            })?;
+
+        // Sync markers can come between chunks
+        if header.flags & FLAG_SYNC != 0 {
+            continue;
+        }
+        if header.flags & FLAG_CHUNK == 0 || chunk.key != manifest.key {
+            return Err(missing_chunks());
+        }
+        value.extend_from_slice(&chunk.value);
+        read += 1;
+    }
+
+    if value.len() != len {
+        return Err(missing_chunks());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Limits;
+
+    use super::*;
+
+    #[test]
+    fn large_values_survive_reopening_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let limits = Limits {
+            chunk_len: 10,
+            ..Limits::default()
+        };
+        let large: ByteString = (0..=255).collect();
+
+        let mut store = KV::open(&path).unwrap();
+        store.set_limits(limits).unwrap();
+        store.insert(b"small", b"value").unwrap();
+        store.insert(b"large", &large).unwrap();
+        store.insert(b"large", &large[..100]).unwrap();
+        assert_eq!(store.get(b"large").unwrap(), Some(large[..100].to_vec()));
+
+        store.insert(b"large", &large).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get(b"large").unwrap(), Some(large.clone()));
+        drop(store);
+
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"small").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(b"large").unwrap(), Some(large.clone()));
+        assert_eq!(store.find(b"large").unwrap().unwrap().1, large);
+
+        store
+            .set_limits(Limits {
+                max_value_len: 255,
+                ..limits
+            })
+            .unwrap();
+        let err = store.insert(b"large", &large).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
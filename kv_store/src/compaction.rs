@@ -0,0 +1,334 @@
+//!
+//! Compaction: dropping superseded records from the log
+//!
+//! Every update leaves the previous record for its key behind. Compaction copies the
+//! latest record of every key into a new file, in log order, and swaps it in for the old
+//! one. Records are copied as stored, checksums and encryption included, so no key is
+//! needed, except to find the chunks of encrypted large values.
+//!
+//! Tombstones are only there to hide older records of their key, which the new file
+//! doesn't have, so they're left out and their key is gone from the index afterwards. The
+//! catalog and local keys are kept whole, since they're read by the store itself.
+//!
+//! Merge operands are folded into the value they apply to, which is written in their
+//! place, so the merge operator must be set if there are any.
+//!
+//! Namespaces are compacted along with the default one, and the records of dropped
+//! namespaces are left out. A single namespace can be compacted on its own too, see
+//! `Namespace::compact`.
+//!
+//! Positions change, so anything holding on to offsets in the old file has to start over:
+//! replication followers, incremental backups and `KV::tail` consumers. The new file is a
+//! new generation of the log, which tells incremental backups apart.
+//!
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    chunked::Manifest,
+    format::Format,
+    merge,
+    namespace::{CATALOG, LOCAL},
+    store::{ByteString, FLAG_MANIFEST, FLAG_MERGE, Header, KV},
+    sync_marker,
+};
+
+impl KV {
+    /// Rewrites the data file with only the latest record of every key
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.ensure_writable()?;
+        let old_len = self.seek_to_end()?;
+
+        // Folded before anything is written, since it fails without a merge operator
+        let mut folded = HashMap::with_capacity(self.operands.len());
+        let merged: Vec<(ByteString, u64)> = self
+            .operands
+            .keys()
+            .map(|key| (key.clone(), self.index[key]))
+            .collect();
+        for (key, position) in merged {
+            let kv = self.read_value(0, &key, position)?;
+            folded.insert(key, kv.value);
+        }
+
+        // Reading in log order keeps the disk access sequential, and the catalog records
+        // naming namespaces ahead of their records
+        let mut live: Vec<(u64, u32, ByteString)> = self
+            .index
+            .iter()
+            .map(|(key, position)| (*position, 0, key.clone()))
+            .collect();
+        live.extend(self.namespaces.positions());
+        live.sort();
+
+        let (format, cipher) = (self.format, self.cipher.clone());
+        let operator = self.merge_operator.clone();
+        // The new file keeps the header of the old one, if it has one
+        let rewritten = self.format.rewritten();
+        let (written, records, mut indexes) = self.write_compacted(|store, f| {
+            let mut records = 0;
+            let mut indexes: HashMap<u32, HashMap<ByteString, u64>> = HashMap::new();
+            let mut written = rewritten.data_start();
+            let mut reader = BufReader::new(&mut store.f);
+            let mut writer = BufWriter::new(f);
+            rewritten.write(&mut writer)?;
+
+            for (position, namespace, key) in live {
+                reader.seek(SeekFrom::Start(position))?;
+                let mut raw = KV::read_raw_record(&mut reader)?;
+                let header = Header::read(&mut &raw[..])?;
+
+                let is_tombstone = header.val_len == 0
+                    && header.flags & (FLAG_MERGE | FLAG_MANIFEST) == 0
+                    && namespace != CATALOG
+                    && namespace != LOCAL;
+                if is_tombstone && !folded.contains_key(&key) {
+                    continue;
+                }
+                records += 1;
+
+                // A lone operand is folded too, unless it can't be and stays as it is
+                if namespace == 0
+                    && header.flags & FLAG_MERGE != 0
+                    && let Some(operator) = &operator
+                    && !folded.contains_key(&key)
+                {
+                    let (_, kv) = KV::process_record(&mut &raw[..], format, cipher.as_ref())?;
+                    let value = merge::fold(Some(&**operator), &key, kv.value, true, Vec::new())?;
+                    folded.insert(key.clone(), value);
+                }
+                if namespace == 0
+                    && let Some(value) = folded.remove(&key)
+                {
+                    raw = KV::encode_record(&key, &value, format, cipher.as_ref())?;
+                }
+
+                // Large values are copied along with the chunks right before their manifest,
+                // keeping the distance to them as it was
+                let mut chunks = 0;
+                if Header::read(&mut &raw[..])?.flags & FLAG_MANIFEST != 0 {
+                    let (_, kv) = KV::process_record(&mut &raw[..], format, cipher.as_ref())?;
+                    chunks = Manifest::decode(&kv.value)?.distance;
+                }
+
+                // Sync markers are written anew, before the chunks if there are any
+                let len = chunks + raw.len() as u64;
+                if let Some(marker) = sync_marker::before(format, written, len)? {
+                    writer.write_all(&marker)?;
+                    written += marker.len() as u64;
+                }
+
+                if chunks > 0 {
+                    reader.seek(SeekFrom::Start(position - chunks))?;
+                    let copied = io::copy(&mut reader.by_ref().take(chunks), &mut writer)?;
+                    if copied != chunks {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+
+                writer.write_all(&raw)?;
+                let index = indexes.entry(namespace).or_default();
+                index.insert(key, written + chunks);
+                written += chunks + raw.len() as u64;
+            }
+
+            writer.flush()?;
+            Ok((written, records, indexes))
+        })?;
+
+        self.loaded = written;
+        self.stats.records = records;
+        self.index = indexes.remove(&0).unwrap_or_default();
+        self.operands.clear();
+        self.namespaces.reindex(indexes);
+
+        self.stats.compactions += 1;
+        self.stats.bytes_reclaimed += old_len.saturating_sub(written);
+
+        Ok(())
+    }
+
+    ///
+    /// Writes a compacted data file with `write` and swaps it in for the old one
+    ///
+    /// The file is written next to the data file, and removed if writing or swapping it in
+    /// fails, so a failed compaction leaves nothing behind for the next one to trip over.
+    ///
+    pub(super) fn write_compacted<T>(
+        &mut self,
+        write: impl FnOnce(&mut Self, &File) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let (tmp_path, f) = self.create_compaction_file()?;
+        let format = self.format.rewritten();
+
+        let written = write(self, &f).and_then(|done| {
+            self.swap_in(&tmp_path, f, format)?;
+            Ok(done)
+        });
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        written
+    }
+
+    /// Creates the file a compaction writes to, next to the data file
+    fn create_compaction_file(&self) -> io::Result<(OsString, File)> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+
+        // Opened for appending, so it can replace the old file handle as is once renamed
+        let f = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&tmp_path)?;
+        // Locked before it's renamed, so no other process can sneak in
+        f.try_lock().map_err(io::Error::from)?;
+        // Compactions only run under the exclusive lock of the store, so a file already
+        // there was left by one that crashed, and is started over
+        f.set_len(0)?;
+
+        Ok((tmp_path, f))
+    }
+
+    /// Makes the compacted file `f` the data file, once it's safely on disk
+    fn swap_in(&mut self, tmp_path: &OsString, f: File, format: Format) -> io::Result<()> {
+        f.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        self.f = f;
+        self.format = format;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{Cipher, KEY_LEN},
+        merge::U64Add,
+        store::{ByteStr, Limits},
+    };
+    use std::path::Path;
+
+    fn check(store: &mut KV, large: &ByteStr) {
+        assert_eq!(store.get(b"apple").unwrap().unwrap(), b"green");
+        assert_eq!(store.get(b"banana").unwrap(), None);
+        assert_eq!(store.get(b"large").unwrap().unwrap(), large);
+        assert_eq!(store.get(b"visits").unwrap().unwrap(), 5u64.to_le_bytes());
+        assert_eq!(store.get(b"revived").unwrap().unwrap(), 2u64.to_le_bytes());
+        assert_eq!(store.get_local(b"note").unwrap().unwrap(), b"");
+        let mut trees = store.namespace(b"trees").unwrap();
+        assert_eq!(trees.get(b"oak").unwrap().unwrap(), b"acorn");
+        assert_eq!(trees.get(b"elm").unwrap(), None);
+        assert!(store.namespace(b"dropped").is_err());
+    }
+
+    #[test]
+    fn compaction_keeps_only_what_is_needed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store.set_merge_operator(U64Add);
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        let large = vec![9; 5000];
+        for round in 0..10u8 {
+            store.insert(b"apple", &[round; 100]).unwrap();
+            store.insert(b"large", &vec![round; 3000]).unwrap();
+        }
+        store.insert(b"apple", b"green").unwrap();
+        store.insert(b"large", &large).unwrap();
+        store.insert(b"banana", b"yellow").unwrap();
+        store.delete(b"banana").unwrap();
+        store.merge(b"visits", &2u64.to_le_bytes()).unwrap();
+        store.merge(b"visits", &3u64.to_le_bytes()).unwrap();
+        // A tombstone with operands after it is a value to fold them into
+        store.insert(b"revived", b"").unwrap();
+        store.merge(b"revived", &2u64.to_le_bytes()).unwrap();
+        // Empty local keys and catalog records aren't tombstones
+        store.insert_local(b"note", b"").unwrap();
+        store.create_namespace(b"trees").unwrap();
+        store.create_namespace(b"dropped").unwrap();
+        let mut trees = store.namespace(b"trees").unwrap();
+        trees.insert(b"oak", b"acorn").unwrap();
+        trees.insert(b"elm", b"seed").unwrap();
+        trees.delete(b"elm").unwrap();
+        let mut dropped = store.namespace(b"dropped").unwrap();
+        dropped.insert(b"gone", b"soon").unwrap();
+        store.drop_namespace(b"dropped").unwrap();
+
+        assert_eq!(store.get(b"banana").unwrap().unwrap(), b"");
+        let before = store.stats().unwrap();
+        assert!(before.dead_records > 20);
+
+        store.compact().unwrap();
+        check(&mut store, &large);
+
+        // Deleted keys are gone from the index along with their tombstone
+        assert!(!store.index().contains_key(&b"banana"[..]));
+        assert!(store.operands.is_empty());
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.dead_records, 0);
+        // apple, large, visits and revived, the note, oak and the catalog record naming trees
+        assert_eq!(stats.live_records, 7);
+        assert_eq!(stats.bytes_reclaimed, before.file_bytes - stats.file_bytes);
+        assert!(stats.file_bytes < before.file_bytes / 4);
+
+        // The compacted file loads to the same state, and compacting again changes nothing
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.set_merge_operator(U64Add);
+        store.load().unwrap();
+        check(&mut store, &large);
+        let len = std::fs::metadata(&path).unwrap().len();
+        store.compact().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        check(&mut store, &large);
+
+        // Compacting a single namespace drops its tombstones as well
+        let mut trees = store.namespace(b"trees").unwrap();
+        trees.insert(b"ash", b"key").unwrap();
+        trees.delete(b"ash").unwrap();
+        assert_eq!(trees.index().len(), 2);
+        trees.compact().unwrap();
+        assert_eq!(trees.index().len(), 1);
+        assert_eq!(trees.get(b"ash").unwrap(), None);
+        check(&mut store, &large);
+
+        // A compaction failing part way, here on the manifest of a value encrypted with
+        // another key, leaves no file behind to stop the next one
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.set_cipher(Cipher::new(&[1; KEY_LEN]));
+        store.insert(b"secret", &vec![5; 3000]).unwrap();
+        store.set_cipher(Cipher::new(&[2; KEY_LEN]));
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".compact");
+        assert!(store.compact().is_err());
+        assert!(!Path::new(&tmp_path).exists());
+
+        // Nor does one left by a crash
+        fs::write(&tmp_path, b"left over").unwrap();
+        store.set_cipher(Cipher::new(&[1; KEY_LEN]));
+        store.compact().unwrap();
+        assert!(!Path::new(&tmp_path).exists());
+        check(&mut store, &large);
+        assert_eq!(store.get(b"secret").unwrap().unwrap(), vec![5; 3000]);
+    }
+}
@@ -0,0 +1,238 @@
+//!
+//! Encryption at rest for record data
+//!
+//! Encrypted records hold `[nonce][ciphertext][tag]` as their data, where the ciphertext is
+//! the key followed by the value, sealed with ChaCha20-Poly1305. The key and value lengths
+//! from the header are authenticated along with it, so changing either is detected too.
+//! Nonces are random, which is safe for far more records than a single store holds.
+//!
+
+use std::{fmt, fs, io, path::Path};
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand::RngCore;
+
+use crate::store::{ByteStr, ByteString, RecordError};
+
+/// Size of an encryption key, in bytes
+pub const KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Bytes an encrypted record takes on top of its key and value
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let aead = ChaCha20Poly1305::new(Key::from_slice(key));
+        Self { aead }
+    }
+
+    ///
+    /// Reads a key from a file holding exactly `KEY_LEN` raw bytes
+    ///
+    /// Such a file can be made with `head -c 32 /dev/urandom > store.key`.
+    ///
+    pub fn from_key_file(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let key: &[u8; KEY_LEN] = bytes.as_slice().try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} must hold exactly {KEY_LEN} bytes, found {}",
+                    path.display(),
+                    bytes.len()
+                ),
+            )
+        })?;
+
+        Ok(Self::new(key))
+    }
+
+    /// Encrypts `plaintext`, authenticating `aad` along with it
+    pub(super) fn seal(&self, plaintext: &ByteStr, aad: &ByteStr) -> ByteString {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        // Encrypting in memory can't fail
+        let ciphertext = self
+            .aead
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .unwrap();
+
+        let mut sealed = ByteString::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts data made by `seal`, failing if it or `aad` changed since
+    pub(super) fn open(&self, sealed: &ByteStr, aad: &ByteStr) -> Result<ByteString, RecordError> {
+        if sealed.len() < OVERHEAD {
+            return Err(RecordError::Tampered);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| RecordError::Tampered)
+    }
+}
+
+impl fmt::Debug for Cipher {
+    // The key stays out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{Checksum, Format},
+        store::{Header, KV},
+    };
+
+    ///
+    /// Changes a record with `change`, checksumming it again
+    ///
+    /// Checksums don't stop whoever can write the file, so this is what authentication is
+    /// for.
+    ///
+    fn altered(
+        record: &ByteStr,
+        format: Format,
+        change: impl FnOnce(&mut Header, &mut ByteString),
+    ) -> ByteString {
+        let mut rest = record;
+        let mut header = Header::read(&mut rest).unwrap();
+        let mut data = rest.to_vec();
+        change(&mut header, &mut data);
+        header.checksum = format.record_checksum(&header.lengths(), &data);
+
+        let mut record = ByteString::new();
+        header.write(&mut record).unwrap();
+        record.extend_from_slice(&data);
+        record
+    }
+
+    fn decode(
+        record: &ByteStr,
+        format: Format,
+        cipher: Option<&Cipher>,
+    ) -> Result<ByteString, RecordError> {
+        KV::process_record(&mut &record[..], format, cipher)
+            .map(|(_, kv)| kv.value)
+            .map_err(|err| RecordError::of(&err).unwrap().clone())
+    }
+
+    #[test]
+    fn tampering_and_wrong_keys_are_caught() {
+        let cipher = Cipher::new(&[7; KEY_LEN]);
+        let other = Cipher::new(&[8; KEY_LEN]);
+
+        // Sealed data only opens with the same key and additional data
+        let sealed = cipher.seal(b"plaintext", b"aad");
+        assert_eq!(sealed.len(), b"plaintext".len() + OVERHEAD);
+        assert_eq!(cipher.open(&sealed, b"aad").unwrap(), b"plaintext");
+        assert_eq!(cipher.open(&sealed, b"aae"), Err(RecordError::Tampered));
+        assert_eq!(other.open(&sealed, b"aad"), Err(RecordError::Tampered));
+        assert_eq!(
+            cipher.open(&sealed[..OVERHEAD - 1], b"aad"),
+            Err(RecordError::Tampered)
+        );
+        for at in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert_eq!(cipher.open(&tampered, b"aad"), Err(RecordError::Tampered));
+        }
+
+        let format = Format::new(Checksum::default());
+        let record = KV::encode_record(b"key", b"value", format, Some(&cipher)).unwrap();
+        assert_eq!(decode(&record, format, Some(&cipher)).unwrap(), b"value");
+
+        // Changed ciphertext is caught by the checksum, and by authentication if the
+        // checksum is changed along with it
+        let mut corrupted = record.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(matches!(
+            decode(&corrupted, format, Some(&cipher)),
+            Err(RecordError::Corrupted { .. })
+        ));
+        let tampered = altered(&record, format, |_, data| data[NONCE_LEN] ^= 1);
+        assert_eq!(
+            decode(&tampered, format, Some(&cipher)),
+            Err(RecordError::Tampered)
+        );
+
+        // So are lengths, which are authenticated as additional data
+        let moved = altered(&record, format, |header, _| {
+            header.key_len += 1;
+            header.val_len -= 1;
+        });
+        assert_eq!(
+            decode(&moved, format, Some(&cipher)),
+            Err(RecordError::Tampered)
+        );
+
+        // Other keys can't open it, and without one it isn't even tried
+        assert_eq!(
+            decode(&record, format, Some(&other)),
+            Err(RecordError::Tampered)
+        );
+        assert_eq!(decode(&record, format, None), Err(RecordError::MissingKey));
+
+        // Stores report the same when loading
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let mut store = KV::open(&path).unwrap();
+        store.set_cipher(cipher.clone());
+        store.insert(b"key", b"value").unwrap();
+        drop(store);
+
+        let mut store = KV::open_read_only(&path).unwrap();
+        let err = store.load().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(RecordError::of(&err), Some(&RecordError::MissingKey));
+
+        let mut store = KV::open_read_only(&path).unwrap();
+        store.set_cipher(other);
+        let err = store.load().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(RecordError::of(&err), Some(&RecordError::Tampered));
+
+        let mut store = KV::open_read_only(&path).unwrap();
+        store.set_cipher(cipher);
+        store.load().unwrap();
+        assert_eq!(store.get(b"key").unwrap().unwrap(), b"value");
+
+        // Key files must hold a key and nothing else
+        let key_path = dir.path().join("store.key");
+        std::fs::write(&key_path, [7; KEY_LEN + 1]).unwrap();
+        let err = Cipher::from_key_file(&key_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&key_path, [7; KEY_LEN]).unwrap();
+        let from_file = Cipher::from_key_file(&key_path).unwrap();
+        assert_eq!(from_file.open(&sealed, b"aad").unwrap(), b"plaintext");
+    }
+}
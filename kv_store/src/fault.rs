@@ -0,0 +1,328 @@
+//!
+//! Fault injection for testing how the store copes with crashes and damaged files
+//!
+//! `Faults` are handed to a real store, and every append it makes goes through them: the
+//! file header is written first, then records are added with `KV::insert` and
+//! `KV::delete`. Faults happen at chosen offsets of the file. What ends up on disk is then
+//! handed to another store to load.
+//!
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use proptest::prelude::*;
+
+use crate::{
+    format::{Checksum, Format},
+    store::{ByteString, KV},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    /// The process dies once the file reaches this length: nothing more is written
+    Crash { at: u64 },
+    /// A write reaching this offset stops there, and the caller has to write the rest
+    ShortWrite { at: u64 },
+    /// The byte at this offset is silently damaged, as a bad disk would
+    Corrupt { at: u64, mask: u8 },
+}
+
+/// Faults waiting to happen to the writes at the end of a file, see `KV::write_at_end`
+#[derive(Debug, Default)]
+pub(super) struct Faults {
+    faults: Vec<Fault>,
+    crashed: bool,
+}
+
+impl Faults {
+    fn new(faults: Vec<Fault>) -> Self {
+        Self {
+            faults,
+            ..Self::default()
+        }
+    }
+
+    /// Appends to `f`, misbehaving as told
+    pub(super) fn writer<'a>(&'a mut self, f: &'a mut File) -> FaultyWriter<'a> {
+        FaultyWriter { faults: self, f }
+    }
+}
+
+pub(super) struct FaultyWriter<'a> {
+    faults: &'a mut Faults,
+    f: &'a mut File,
+}
+
+impl Write for FaultyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.faults.crashed {
+            return Err(io::Error::other("crashed"));
+        }
+
+        let start = self.f.metadata()?.len();
+        let end = start + buf.len() as u64;
+        let mut n = buf.len();
+
+        for fault in &self.faults.faults {
+            match *fault {
+                Fault::Crash { at } if at < end => {
+                    n = n.min(at.saturating_sub(start) as usize);
+                    self.faults.crashed = true;
+                }
+                // Stopping at the very start would be a write of zero bytes, which means
+                // the file can't take any more
+                Fault::ShortWrite { at } if start < at && at < end => {
+                    n = n.min((at - start) as usize);
+                }
+                _ => {}
+            }
+        }
+
+        let mut bytes = buf[..n].to_vec();
+        for fault in &self.faults.faults {
+            if let Fault::Corrupt { at, mask } = *fault
+                && (start..start + n as u64).contains(&at)
+            {
+                bytes[(at - start) as usize] ^= mask;
+            }
+        }
+        self.f.write_all(&bytes)?;
+
+        match self.faults.crashed {
+            true => Err(io::Error::other("crashed")),
+            false => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert(ByteString, ByteString),
+    Delete(ByteString),
+}
+
+/// What `get` returns for every key after applying `ops`. Deleted keys have empty values
+fn model(ops: &[Op]) -> HashMap<ByteString, ByteString> {
+    let mut state = HashMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => state.insert(key.clone(), value.clone()),
+            Op::Delete(key) => state.insert(key.clone(), ByteString::new()),
+        };
+    }
+    state
+}
+
+fn apply(store: &mut KV, op: &Op) -> io::Result<()> {
+    match op {
+        Op::Insert(key, value) => store.insert(key, value),
+        Op::Delete(key) => store.delete(key),
+    }
+}
+
+///
+/// Writes `ops` to a new store suffering `faults`, returning the file and how many writes
+/// were acknowledged
+///
+/// Writing stops at the first failure, like a process that crashed would.
+///
+fn write_with_faults(ops: &[Op], checksum: Checksum, faults: Vec<Fault>) -> (ByteString, usize) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.db");
+    let mut faults = Faults::new(faults);
+
+    // The header is written like `KV::open` does for new files, with a single write
+    let mut f = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(&path)
+        .unwrap();
+    let written = Format::new(checksum).write(&mut faults.writer(&mut f));
+    drop(f);
+    if written.is_err() {
+        return (std::fs::read(&path).unwrap(), 0);
+    }
+
+    // A damaged file header can keep the store from opening at all
+    let Ok(mut store) = KV::open(&path) else {
+        return (std::fs::read(&path).unwrap(), 0);
+    };
+    store.faults = Some(faults);
+    let acknowledged = ops
+        .iter()
+        .take_while(|op| apply(&mut store, op).is_ok())
+        .count();
+    drop(store);
+
+    (std::fs::read(&path).unwrap(), acknowledged)
+}
+
+/// What `get` gives for every indexed key
+fn state(store: &mut KV) -> HashMap<ByteString, ByteString> {
+    let keys: Vec<ByteString> = store.index.keys().cloned().collect();
+    let mut state = HashMap::new();
+    for key in keys {
+        let value = store.get(&key).expect("indexed keys can be read");
+        state.insert(key, value.unwrap());
+    }
+    state
+}
+
+///
+/// Loads a store from `bytes`, returning what `get` gives for every indexed key
+///
+/// Loading may fail on damaged files, in which case the keys indexed before the failure
+/// are returned. Returns `None` if the store can't be opened.
+///
+fn recover(bytes: &[u8]) -> Option<HashMap<ByteString, ByteString>> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.db");
+    std::fs::write(&path, bytes).unwrap();
+
+    // A damaged file header can keep the store from opening at all, which is fine
+    let mut store = KV::open_read_only(&path).ok()?;
+    let _ = store.load();
+    Some(state(&mut store))
+}
+
+///
+/// Opens the store at `path` for writing, and applies `ops` once it's loaded
+///
+/// Returns the state of the store read back afterwards by another one.
+///
+fn write_after_recovery(path: &Path, ops: &[Op]) -> HashMap<ByteString, ByteString> {
+    let mut store = KV::open(path).unwrap();
+    store.load().unwrap();
+    for op in ops {
+        apply(&mut store, op).unwrap();
+    }
+    drop(store);
+
+    let mut store = KV::open_read_only(path).unwrap();
+    store.load().unwrap();
+    state(&mut store)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    // Few keys, so they get overwritten and deleted often
+    let key = prop::collection::vec(b'a'..=b'd', 1..3);
+    let value = prop::collection::vec(any::<u8>(), 1..40);
+
+    prop_oneof![
+        3 => (key.clone(), value).prop_map(|(key, value)| Op::Insert(key, value)),
+        1 => key.prop_map(Op::Delete),
+    ]
+}
+
+fn checksum() -> impl Strategy<Value = Checksum> {
+    prop::sample::select(Checksum::ALL.to_vec())
+}
+
+proptest! {
+    #[test]
+    fn crashes_lose_only_unacknowledged_writes(
+        ops in prop::collection::vec(op(), 0..30),
+        checksum in checksum(),
+        crash_at in 0..2000u64,
+        short_writes in prop::collection::vec(0..2000u64, 0..5),
+    ) {
+        let mut faults = vec![Fault::Crash { at: crash_at }];
+        faults.extend(short_writes.into_iter().map(|at| Fault::ShortWrite { at }));
+
+        let (bytes, acknowledged) = write_with_faults(&ops, checksum, faults);
+        let recovered = recover(&bytes);
+
+        // A crash inside the file header leaves nothing that was acknowledged
+        if acknowledged > 0 {
+            prop_assert_eq!(recovered, Some(model(&ops[..acknowledged])));
+        } else {
+            prop_assert!(recovered.is_none_or(|state| state.is_empty()));
+        }
+    }
+
+    #[test]
+    fn crashed_stores_take_writes_after_recovery(
+        ops in prop::collection::vec(op(), 0..30),
+        more in prop::collection::vec(op(), 1..10),
+        checksum in checksum(),
+        crash_at in 0..2000u64,
+    ) {
+        let (bytes, acknowledged) = write_with_faults(&ops, checksum, vec![Fault::Crash { at: crash_at }]);
+        // Without a whole file header there's no store to write to
+        prop_assume!(bytes.len() as u64 >= Format::new(checksum).data_start());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        std::fs::write(&path, &bytes).unwrap();
+        let state = write_after_recovery(&path, &more);
+
+        let written: Vec<Op> = ops[..acknowledged].iter().chain(&more).cloned().collect();
+        prop_assert_eq!(state, model(&written));
+    }
+
+    #[test]
+    fn damaged_files_recover_a_prefix_of_the_writes(
+        ops in prop::collection::vec(op(), 1..30),
+        checksum in checksum(),
+        damage in prop::collection::vec((0..2000u64, 1..=255u8), 1..4),
+    ) {
+        let faults = damage
+            .into_iter()
+            .map(|(at, mask)| Fault::Corrupt { at, mask })
+            .collect();
+
+        let (bytes, acknowledged) = write_with_faults(&ops, checksum, faults);
+        let recovered = recover(&bytes);
+        if acknowledged < ops.len() {
+            // Only a damaged file header keeps the writes from being made
+            prop_assert_eq!(acknowledged, 0);
+            prop_assert!(recovered.is_none_or(|state| state.is_empty()));
+            return Ok(());
+        }
+
+        // Whatever is recovered must be the state after some prefix of the writes, never
+        // a state mixing in damaged records
+        if let Some(recovered) = recovered {
+            let is_prefix = (0..=ops.len()).any(|len| model(&ops[..len]) == recovered);
+            prop_assert!(is_prefix, "recovered {:?}", recovered);
+        }
+    }
+}
+
+#[test]
+fn torn_tails_are_dropped_before_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.db");
+
+    let mut store = KV::open(&path).unwrap();
+    store.insert(b"k1", b"v1").unwrap();
+    store.insert(b"k2", b"v2").unwrap();
+    drop(store);
+    let len = std::fs::metadata(&path).unwrap().len();
+    let f = OpenOptions::new().write(true).open(&path).unwrap();
+    f.set_len(len - 2).unwrap();
+    drop(f);
+
+    // Readers leave the file as it is
+    let mut store = KV::open_read_only(&path).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.index.len(), 1);
+    drop(store);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len - 2);
+
+    let more = [
+        Op::Insert(b"k3".to_vec(), b"v3".to_vec()),
+        Op::Insert(b"k4".to_vec(), b"v4".to_vec()),
+    ];
+    let written = [Op::Insert(b"k1".to_vec(), b"v1".to_vec())];
+    let written: Vec<Op> = written.iter().chain(&more).cloned().collect();
+    assert_eq!(write_after_recovery(&path, &more), model(&written));
+}
@@ -0,0 +1,373 @@
+//!
+//! The file header, and the checksum algorithms it can choose between
+//!
+//! Stores created from now on start with a header recording how their records are
+//! checksummed:
+//!
+//! ```text
+//! [magic: "KVSTORE"][version: u8][checksum algorithm: u8][reserved: 7 zero bytes]
+//! ```
+//!
+//! Version 2 adds sync markers to the log, see `sync_marker`. From it on, the checksum of a
+//! record covers its key and value lengths along with its data, flags included, so damage
+//! to the header of a record is caught too. Stores of version 1 go on checksumming the data
+//! only and without markers, so that older builds can still read them.
+//!
+//! Version 3 adds which log the file holds, so offsets into it are never taken for offsets
+//! into another one, as incremental backups do. New stores are created with it:
+//!
+//! ```text
+//! [version 2 header: 16 bytes][log id: u64][generation: u64]
+//! ```
+//!
+//! The log id is chosen at random when the store is created. Rewriting the log, as
+//! compaction does, moves records around, so the generation goes up by one every time.
+//!
+//! Files without one were written before the header existed. Their records start right at
+//! the beginning and use CRC32 (IEEE) over the data only, so they're read just like before.
+//!
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use crc::crc32;
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::store::ByteStr;
+
+const MAGIC: &[u8; 7] = b"KVSTORE";
+/// Versions this build reads. The last one is written to new stores
+const VERSIONS: [u8; 3] = [1, 2, 3];
+
+/// Size of the file header of versions 1 and 2, which later ones start with
+pub const FILE_HEADER_LEN: usize = 16;
+
+/// Size of what version 3 adds to the file header
+const LOG_ID_LEN: usize = 16;
+
+///
+/// How records are checked for corruption
+///
+/// Record headers have room for 32 bits, so xxHash64 is truncated to its low half.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC32 with the IEEE polynomial, the only choice before the file header existed
+    #[default]
+    Crc32,
+    /// CRC32 with the Castagnoli polynomial, computed in hardware on most CPUs
+    Crc32c,
+    XxHash64,
+}
+
+impl Checksum {
+    pub const ALL: [Self; 3] = [Self::Crc32, Self::Crc32c, Self::XxHash64];
+
+    pub fn compute(self, data: &ByteStr) -> u32 {
+        self.compute_parts(&[data])
+    }
+
+    /// Checksum of `parts` one after another, without copying them together first
+    pub fn compute_parts(self, parts: &[&ByteStr]) -> u32 {
+        match self {
+            Self::Crc32 => parts
+                .iter()
+                .fold(0, |crc, part| crc32::update(crc, &crc32::IEEE_TABLE, part)),
+            Self::Crc32c => parts
+                .iter()
+                .fold(0, |crc, part| crc32c::crc32c_append(crc, part)),
+            Self::XxHash64 => {
+                let mut hasher = Xxh64::new(0);
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.digest() as u32
+            }
+        }
+    }
+
+    pub(super) fn id(self) -> u8 {
+        match self {
+            Self::Crc32 => 0,
+            Self::Crc32c => 1,
+            Self::XxHash64 => 2,
+        }
+    }
+
+    pub(super) fn from_id(id: u8) -> io::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|checksum| checksum.id() == id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown checksum algorithm {id}"),
+                )
+            })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Crc32 => "crc32",
+            Self::Crc32c => "crc32c",
+            Self::XxHash64 => "xxhash64",
+        })
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|checksum| checksum.to_string() == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown checksum algorithm {name:?}"),
+                )
+            })
+    }
+}
+
+/// Which log a file holds, and how many times it was rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogId {
+    /// Chosen at random when the store is created
+    pub id: u64,
+    pub generation: u64,
+}
+
+/// Everything the file header records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Format {
+    pub checksum: Checksum,
+    /// Whether the file starts with a header. If not, it's a file from before headers
+    pub has_header: bool,
+    /// Whether sync markers are written to the log, in files of version 2 and later
+    pub sync_markers: bool,
+    /// Which log the file holds, in files of version 3
+    pub log_id: Option<LogId>,
+}
+
+impl Format {
+    /// Format of new stores, with a header recording `checksum` and a new log id
+    pub fn new(checksum: Checksum) -> Self {
+        Self {
+            checksum,
+            has_header: true,
+            sync_markers: true,
+            log_id: Some(LogId {
+                id: rand::random(),
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Format of the same log once rewritten, which is a new generation of it
+    pub fn rewritten(&self) -> Self {
+        Self {
+            log_id: self.log_id.map(|log_id| LogId {
+                generation: log_id.generation + 1,
+                ..log_id
+            }),
+            ..*self
+        }
+    }
+
+    /// Version recorded in the header, 0 for files without one
+    pub fn version(&self) -> u8 {
+        match (self.has_header, self.log_id, self.sync_markers) {
+            (false, _, _) => 0,
+            (true, Some(_), _) => 3,
+            (true, None, true) => 2,
+            (true, None, false) => 1,
+        }
+    }
+
+    /// Checksum of a record with the given length word and data
+    pub fn record_checksum(&self, lengths: &ByteStr, data: &ByteStr) -> u32 {
+        match self.version() {
+            0 | 1 => self.checksum.compute(data),
+            _ => self.checksum.compute_parts(&[lengths, data]),
+        }
+    }
+
+    /// Position of the first record
+    pub fn data_start(&self) -> u64 {
+        match (self.has_header, self.log_id) {
+            (false, _) => 0,
+            (true, None) => FILE_HEADER_LEN as u64,
+            (true, Some(_)) => (FILE_HEADER_LEN + LOG_ID_LEN) as u64,
+        }
+    }
+
+    ///
+    /// Reads the format from the start of a file
+    ///
+    /// Files too short to hold a header, or starting with anything else, are taken to be
+    /// from before headers existed.
+    ///
+    pub fn read<R: Read>(f: &mut R) -> io::Result<Self> {
+        let mut header = [0; FILE_HEADER_LEN];
+        let mut read = 0;
+        while read < FILE_HEADER_LEN {
+            match f.read(&mut header[read..])? {
+                0 => return Ok(Self::default()),
+                n => read += n,
+            }
+        }
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Ok(Self::default());
+        }
+
+        let version = header[MAGIC.len()];
+        if !VERSIONS.contains(&version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported file version {version}"),
+            ));
+        }
+
+        let log_id = match version {
+            3.. => {
+                let mut log_id = [0; LOG_ID_LEN];
+                f.read_exact(&mut log_id)?;
+                Some(LogId {
+                    id: u64::from_le_bytes(log_id[..8].try_into().unwrap()),
+                    generation: u64::from_le_bytes(log_id[8..].try_into().unwrap()),
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            checksum: Checksum::from_id(header[MAGIC.len() + 1])?,
+            has_header: true,
+            sync_markers: version >= 2,
+            log_id,
+        })
+    }
+
+    /// Writes the file header, which is nothing for files from before headers
+    pub fn write<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        if !self.has_header {
+            return Ok(());
+        }
+
+        let mut header = [0; FILE_HEADER_LEN + LOG_ID_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = self.version();
+        header[MAGIC.len() + 1] = self.checksum.id();
+        if let Some(log_id) = self.log_id {
+            header[FILE_HEADER_LEN..FILE_HEADER_LEN + 8].copy_from_slice(&log_id.id.to_le_bytes());
+            header[FILE_HEADER_LEN + 8..].copy_from_slice(&log_id.generation.to_le_bytes());
+        }
+        f.write_all(&header[..self.data_start() as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::KV;
+
+    #[test]
+    fn every_version_reads_back() {
+        let formats = [
+            Format::default(),
+            Format {
+                sync_markers: false,
+                log_id: None,
+                ..Format::new(Checksum::Crc32c)
+            },
+            Format {
+                log_id: None,
+                ..Format::new(Checksum::XxHash64)
+            },
+            Format::new(Checksum::Crc32),
+        ];
+        for (version, format) in formats.into_iter().enumerate() {
+            assert_eq!(format.version(), version as u8);
+            let mut header = Vec::new();
+            format.write(&mut header).unwrap();
+            assert_eq!(header.len() as u64, format.data_start());
+            // Files without a header start right away with records
+            header.extend_from_slice(&[0xff; 40]);
+            assert_eq!(Format::read(&mut &header[..]).unwrap(), format);
+        }
+
+        let rewritten = formats[3].rewritten();
+        assert_eq!(rewritten.log_id.unwrap().id, formats[3].log_id.unwrap().id);
+        assert_eq!(rewritten.log_id.unwrap().generation, 1);
+        assert_ne!(Format::new(Checksum::Crc32).log_id, formats[3].log_id);
+
+        let mut header = Vec::new();
+        formats[3].write(&mut header).unwrap();
+        header[MAGIC.len()] = 4;
+        let err = Format::read(&mut &header[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Version 3 headers can't be cut short
+        assert!(Format::read(&mut &header[..FILE_HEADER_LEN + 4]).is_err());
+    }
+
+    #[test]
+    fn version_1_checksums_the_data_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        // A store as builds from before version 2 wrote it
+        let format = Format {
+            sync_markers: false,
+            log_id: None,
+            ..Format::new(Checksum::Crc32)
+        };
+        let mut bytes = Vec::new();
+        format.write(&mut bytes).unwrap();
+        for (key, value) in [(&b"apple"[..], &b"red"[..]), (b"banana", b"yellow")] {
+            let data = [key, value].concat();
+            bytes.extend_from_slice(&crc32::checksum_ieee(&data).to_le_bytes());
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&data);
+        }
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"apple").unwrap().unwrap(), b"red");
+        // Records appended to it follow the same rule
+        store.insert(b"cherry", b"dark red").unwrap();
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"banana").unwrap().unwrap(), b"yellow");
+        assert_eq!(store.get(b"cherry").unwrap().unwrap(), b"dark red");
+        assert_eq!(store.format.version(), 1);
+
+        // Later versions cover the lengths too
+        let lengths = [5, 0, 0, 0, 3, 0, 0, 0];
+        let data = b"applered";
+        assert_eq!(
+            format.record_checksum(&lengths, data),
+            crc32::checksum_ieee(data)
+        );
+        for format in [
+            Format::new(Checksum::Crc32),
+            Format {
+                log_id: None,
+                ..Format::new(Checksum::Crc32)
+            },
+        ] {
+            let covered = crc32::update(crc32::checksum_ieee(&lengths), &crc32::IEEE_TABLE, data);
+            assert_eq!(format.record_checksum(&lengths, data), covered);
+        }
+    }
+}
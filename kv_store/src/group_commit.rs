@@ -0,0 +1,299 @@
+//!
+//! Group commit: durable writes shared between many concurrent writers
+//!
+//! Making a write durable takes an fsync, which costs about as much for one record as for a
+//! thousand. Callers queue their records, and whichever of them gets hold of the store
+//! first takes every record queued while the previous fsync was running, appends them all
+//! with one `write()`, and makes them durable with one fsync. The others find their record
+//! written once it's their turn. Each caller is only answered once its record is on disk.
+//!
+//! Records are written through the store, so they're indexed and delivered to subscribers
+//! just like those written by `KV::insert`. Values longer than `Limits::chunk_len` are split
+//! into chunks like it does too, and written and synced on their own.
+//!
+
+use std::{
+    io::{self, Seek, SeekFrom},
+    mem,
+    sync::{
+        Mutex, MutexGuard,
+        mpsc::{self, Sender},
+    },
+};
+
+use crate::{
+    crypto::Cipher,
+    format::Format,
+    store::{ByteStr, ByteString, KV, index_record},
+    sync_marker,
+    watch::Event,
+};
+
+/// A record waiting to be written, with where to send its position once it's durable
+struct Request {
+    key: ByteString,
+    value: ByteString,
+    record: ByteString,
+    done: Sender<io::Result<u64>>,
+}
+
+///
+/// Handle for appending records durably from any number of threads
+///
+/// It borrows the store, so the store can't be written to any other way meanwhile. Share
+/// it between threads by reference, e.g. with `std::thread::scope`.
+///
+pub struct GroupCommit<'a> {
+    store: Mutex<&'a mut KV>,
+    queue: Mutex<Vec<Request>>,
+    format: Format,
+    cipher: Option<Cipher>,
+    // Values longer than this are written in chunks, see `Limits::chunk_len`
+    chunk_len: usize,
+}
+
+/// A thread panicking mid-batch leaves the store as consistent as a crash would
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl GroupCommit<'_> {
+    ///
+    /// Appends a record, returning its position once it's durable
+    ///
+    /// Blocks until the batch the record ended up in has been written and synced.
+    ///
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
+        if value.len() > self.chunk_len {
+            let mut guard = lock(&self.store);
+            let store = &mut **guard;
+            let position = store.write_value(0, key, value)?;
+            index_record(&mut store.index, &mut store.operands, 0, key, position);
+            store.index_value(key, value);
+            store.f.sync_data()?;
+            return Ok(position);
+        }
+
+        // Encrypting and checksumming is done by every caller, without holding the store
+        let record = KV::encode_record(key, value, self.format, self.cipher.as_ref())?;
+        let (done, position) = mpsc::channel();
+        lock(&self.queue).push(Request {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            record,
+            done,
+        });
+
+        let mut store = lock(&self.store);
+        // Written along with the batch of whoever held the store before
+        if let Ok(result) = position.try_recv() {
+            return result;
+        }
+
+        let batch = mem::take(&mut *lock(&self.queue));
+        store.write_batch(batch);
+        position
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("record was left out of its batch")))
+    }
+
+    #[inline]
+    pub fn delete(&self, key: &ByteStr) -> io::Result<u64> {
+        self.insert(key, b"")
+    }
+}
+
+impl KV {
+    /// Starts taking writes from many threads at once, see `GroupCommit`
+    pub fn group_commit(&mut self) -> io::Result<GroupCommit<'_>> {
+        self.ensure_writable()?;
+
+        Ok(GroupCommit {
+            format: self.format,
+            cipher: self.cipher.clone(),
+            chunk_len: self.limits.chunk_len,
+            store: Mutex::new(self),
+            queue: Mutex::new(Vec::new()),
+        })
+    }
+
+    ///
+    /// Appends every record of a batch with a single write and sync, and indexes them
+    ///
+    /// Sync markers are written among them like `append` would. Every request is answered,
+    /// with its position or with why it wasn't written.
+    ///
+    fn write_batch(&mut self, batch: Vec<Request>) {
+        let mut valid = Vec::with_capacity(batch.len());
+        for request in batch {
+            let len = request.value.len() as u64;
+            match self
+                .check_key(&request.key)
+                .and_then(|_| self.check_value_len(len))
+            {
+                Ok(()) => valid.push(request),
+                // The caller may have given up waiting, that's fine
+                Err(err) => drop(request.done.send(Err(err))),
+            }
+        }
+
+        match self.append_batch(&valid) {
+            Ok(positions) => {
+                for (request, position) in valid.into_iter().zip(positions) {
+                    index_record(
+                        &mut self.index,
+                        &mut self.operands,
+                        0,
+                        &request.key,
+                        position,
+                    );
+                    self.index_value(&request.key, &request.value);
+                    self.stats.records += 1;
+                    self.stats.writes += 1;
+                    if !self.subscribers.is_empty() {
+                        let event = Event::new(position, request.key, request.value.clone());
+                        self.notify(event);
+                    }
+                    let _ = request.done.send(Ok(position));
+                }
+            }
+            Err(err) => {
+                for request in valid {
+                    let err = io::Error::new(err.kind(), err.to_string());
+                    let _ = request.done.send(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Writes the records of `batch` and syncs them, returning their positions
+    fn append_batch(&mut self, batch: &[Request]) -> io::Result<Vec<u64>> {
+        let end = self.f.seek(SeekFrom::End(0))?;
+        let len = batch.iter().map(|request| request.record.len()).sum();
+
+        let mut position = end;
+        let mut buffer = ByteString::with_capacity(len);
+        let mut positions = Vec::with_capacity(batch.len());
+        for request in batch {
+            let len = request.record.len() as u64;
+            if let Some(marker) = sync_marker::before(self.format, position, len)? {
+                position += marker.len() as u64;
+                buffer.extend_from_slice(&marker);
+            }
+            positions.push(position);
+            position += len;
+            buffer.extend_from_slice(&request.record);
+        }
+
+        self.write_at_end(&buffer)?;
+        // Metadata such as the modification time isn't needed to read the records back
+        self.f.sync_data()?;
+
+        if self.loaded == end {
+            self.loaded = position;
+        }
+        self.stats.bytes_written += position - end;
+
+        Ok(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{FLAG_MANIFEST, Header, Limits};
+    use std::thread;
+
+    #[test]
+    fn acknowledged_writes_are_indexed_and_durable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let (threads, writes) = (8u32, 200u32);
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 100,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.insert(b"before", b"group commit").unwrap();
+        let events = store.subscribe(b"");
+        let commit = store.group_commit().unwrap();
+
+        // Every thread writes its own keys, and a key shared with all the others
+        let acknowledged: Vec<(u32, u32, u64)> = thread::scope(|scope| {
+            let writers: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let commit = &commit;
+                    scope.spawn(move || {
+                        (0..writes)
+                            .map(|i| {
+                                let key = [thread.to_le_bytes(), i.to_le_bytes()].concat();
+                                let position = commit.insert(&key, &i.to_le_bytes()).unwrap();
+                                commit.insert(b"shared", &thread.to_le_bytes()).unwrap();
+                                (thread, i, position)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            writers
+                .into_iter()
+                .flat_map(|writer| writer.join().unwrap())
+                .collect()
+        });
+
+        // Invalid records are refused on their own
+        let long_key = vec![0; Limits::default().max_key_len + 1];
+        assert_eq!(
+            commit.insert(&long_key, b"").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // Values too long for a record are written in chunks, like `KV::insert` does
+        let large = vec![7; 1000];
+        let large_at = commit.insert(b"large", &large).unwrap();
+        drop(commit);
+        let bytes = std::fs::read(&path).unwrap();
+        let header = Header::read(&mut &bytes[large_at as usize..]).unwrap();
+        assert_ne!(header.flags & FLAG_MANIFEST, 0);
+        assert_eq!(store.get(b"large").unwrap().unwrap(), large);
+
+        // Everything acknowledged is readable right away, where it was said to be
+        let positions: std::collections::HashSet<u64> = acknowledged
+            .iter()
+            .map(|&(_, _, position)| position)
+            .collect();
+        assert_eq!(positions.len(), acknowledged.len());
+        for &(thread, i, position) in &acknowledged {
+            let key = [thread.to_le_bytes(), i.to_le_bytes()].concat();
+            assert_eq!(store.index()[&key], position);
+            assert_eq!(store.get(&key).unwrap().unwrap(), i.to_le_bytes());
+        }
+        let shared = store.get(b"shared").unwrap().unwrap();
+        assert!(u32::from_le_bytes(shared.try_into().unwrap()) < threads);
+        assert_eq!(
+            std::iter::from_fn(|| events.try_next()).count(),
+            2 * acknowledged.len() + 1
+        );
+        assert_eq!(store.stats.writes, 2 + 2 * acknowledged.len() as u64);
+
+        // And after loading the file again, nothing is new to the store
+        let loaded = store.loaded;
+        store.load().unwrap();
+        assert_eq!(store.loaded, loaded);
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index().len(), acknowledged.len() + 3);
+        for &(thread, i, position) in &acknowledged {
+            let key = [thread.to_le_bytes(), i.to_le_bytes()].concat();
+            assert_eq!(store.index()[&key], position);
+        }
+        assert_eq!(store.get(b"before").unwrap().unwrap(), b"group commit");
+        assert_eq!(store.get(b"large").unwrap().unwrap(), large);
+    }
+}
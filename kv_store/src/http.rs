@@ -0,0 +1,596 @@
+//!
+//! HTTP API over a store, for tooling that doesn't speak the TCP protocols
+//!
+//! A minimal HTTP/1.1 server, with one thread per connection and the store behind a mutex.
+//! Connections, the time spent waiting on a client and the length of bodies are capped, see
+//! `ServerLimits`:
+//!
+//! ```text
+//! GET    /keys/{key}         value of key, 404 if there is none
+//! PUT    /keys/{key}         sets key to the request body
+//! DELETE /keys/{key}         deletes key, 404 if there is none
+//! GET    /keys?prefix=P      JSON array of {"key", "value"} objects, sorted by key
+//! GET    /stats              statistics in the Prometheus text format
+//! ```
+//!
+//! Keys are percent-decoded from the path. Values are sent and received as raw bytes,
+//! unless `encoding=base64` is given in the query. Scans hold keys and values as JSON
+//! strings, so without it they must be UTF-8.
+//!
+//! Requests need a `Content-Length` to have a body; chunked bodies aren't supported.
+//!
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::{Value, json};
+
+use crate::store::{ByteStr, ByteString, KV};
+
+/// Longest request line or header accepted
+const MAX_LINE_LEN: u64 = 8 << 10;
+
+/// How much of the resources of the server its clients can take up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Connections served at once. Clients connecting beyond that wait for one to end
+    pub connections: usize,
+    /// How long a read from a client or a write to it may wait, idle connections included
+    pub timeout: Duration,
+    /// Longest request body accepted, since bodies are read into memory
+    pub body_len: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            connections: 64,
+            timeout: Duration::from_secs(30),
+            body_len: 1 << 20,
+        }
+    }
+}
+
+/// Serves the HTTP API for `store` to every client connecting to `listener`, with the
+/// default `ServerLimits`
+pub fn serve(store: KV, listener: TcpListener) -> io::Result<()> {
+    serve_with_limits(store, listener, ServerLimits::default())
+}
+
+///
+/// Serves the HTTP API like `serve`, within `limits`
+///
+/// Each connection is served on its own thread, taking turns with the store. This function
+/// only returns if accepting connections fails.
+///
+pub fn serve_with_limits(store: KV, listener: TcpListener, limits: ServerLimits) -> io::Result<()> {
+    let store = Arc::new(Mutex::new(store));
+    let slots = Arc::new(Slots::default());
+
+    loop {
+        // Connections beyond the limit are left waiting to be accepted
+        let slot = slots.take(limits.connections);
+        let (stream, _) = listener.accept()?;
+        // A client that stops reading or writing only ties up its own connection
+        stream.set_read_timeout(Some(limits.timeout))?;
+        stream.set_write_timeout(Some(limits.timeout))?;
+
+        let store = Arc::clone(&store);
+        thread::spawn(move || {
+            let _slot = slot;
+            let peer = stream.peer_addr();
+            // Clients going away is business as usual, so errors are only reported
+            if let Err(err) = handle(&store, stream, limits.body_len) {
+                eprintln!("HTTP connection from {peer:?} failed: {err}");
+            }
+        });
+    }
+}
+
+/// How many connections are being served
+#[derive(Default)]
+struct Slots {
+    taken: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// Waits until fewer than `limit` connections are served, and counts in one more
+    fn take(self: &Arc<Self>, limit: usize) -> Slot {
+        let mut taken = self.taken.lock().unwrap_or_else(|err| err.into_inner());
+        while *taken >= limit.max(1) {
+            taken = self
+                .freed
+                .wait(taken)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+        *taken += 1;
+        Slot(Arc::clone(self))
+    }
+}
+
+/// A connection being served, until it's dropped, panics included
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut taken = self.0.taken.lock().unwrap_or_else(|err| err.into_inner());
+        *taken -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, ByteString>,
+    body: ByteString,
+    close: bool,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: ByteString,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: ByteString) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn empty(status: u16) -> Self {
+        Self::new(status, "text/plain", Vec::new())
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        let mut body = message.to_string().into_bytes();
+        body.push(b'\n');
+        Self::new(status, "text/plain", body)
+    }
+
+    fn write<W: Write>(&self, out: &mut W, close: bool) -> io::Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        if close {
+            out.write_all(b"Connection: close\r\n")?;
+        }
+        out.write_all(b"\r\n")?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves the requests of a single connection until the client closes it
+fn handle(store: &Mutex<KV>, stream: TcpStream, max_body_len: usize) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut out = BufWriter::new(&stream);
+
+    loop {
+        let request = match read_request(&mut reader, max_body_len)? {
+            None => return Ok(()),
+            Some(Ok(request)) => request,
+            // The rest of a malformed request can't be told apart from the next one
+            Some(Err(response)) => return response.write(&mut out, true),
+        };
+
+        let response = {
+            // A thread panicking mid-request leaves the store as consistent as a crash would
+            let mut store = store
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            route(&mut store, &request)
+        };
+        response.write(&mut out, request.close)?;
+
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a line ending in CRLF, without the line ending, or `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Result<String, Response>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Ok(Some(Err(Response::error(431, "line too long"))));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(
+        String::from_utf8(line).map_err(|_| Response::error(400, "request isn't UTF-8")),
+    ))
+}
+
+///
+/// Reads the next request from `reader`, or `None` if the client closed the connection
+///
+/// Requests that can't be served come back as the error response to send instead.
+///
+fn read_request<R: BufRead>(
+    reader: &mut R,
+    max_body_len: usize,
+) -> io::Result<Option<Result<Request, Response>>> {
+    let line = match read_line(reader)? {
+        None => return Ok(None),
+        Some(Err(response)) => return Ok(Some(Err(response))),
+        Some(Ok(line)) => line,
+    };
+
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Some(Err(Response::error(400, "malformed request line"))));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Ok(Some(Err(Response::error(
+            400,
+            "only HTTP/1.x is supported",
+        ))));
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: HashMap::new(),
+        body: Vec::new(),
+        // HTTP/1.0 closes after every request unless asked not to
+        close: version == "HTTP/1.0",
+    };
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match (percent_decode(name), percent_decode(value)) {
+            (Some(name), Some(value)) => {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                request.query.insert(name, value);
+            }
+            _ => return Ok(Some(Err(Response::error(400, "malformed query")))),
+        }
+    }
+
+    let mut content_len = None;
+    loop {
+        let line = match read_line(reader)? {
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Some(Err(response)) => return Ok(Some(Err(response))),
+            Some(Ok(line)) => line,
+        };
+        if line.is_empty() {
+            break;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(Some(Err(Response::error(400, "malformed header"))));
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) => content_len = Some(len),
+                Err(_) => return Ok(Some(Err(Response::error(400, "bad Content-Length")))),
+            },
+            "transfer-encoding" => {
+                let message = "chunked bodies aren't supported";
+                return Ok(Some(Err(Response::error(501, message))));
+            }
+            "connection" => match value.to_ascii_lowercase().as_str() {
+                "close" => request.close = true,
+                "keep-alive" => request.close = false,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    match content_len {
+        None if request.method == "PUT" => {
+            return Ok(Some(Err(Response::error(
+                411,
+                "Content-Length is required",
+            ))));
+        }
+        None => {}
+        Some(len) if len > max_body_len => {
+            let message = format!("body is {len} bytes, at most {max_body_len} are allowed");
+            return Ok(Some(Err(Response::error(413, message))));
+        }
+        Some(len) => {
+            // Read as it arrives, so memory is only taken up by what the client sent
+            reader.take(len as u64).read_to_end(&mut request.body)?;
+            if request.body.len() != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    Ok(Some(Ok(request)))
+}
+
+/// Decodes `%XX` escapes, or `None` if one is malformed
+fn percent_decode(s: &str) -> Option<ByteString> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+
+    Some(decoded)
+}
+
+/// Turns an error of the store into the response telling about it
+fn failed(err: io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => Response::error(400, err),
+        io::ErrorKind::NotFound => Response::error(404, err),
+        _ => Response::error(500, err),
+    }
+}
+
+fn route(store: &mut KV, request: &Request) -> Response {
+    let base64 = match request.query.get("encoding").map(Vec::as_slice) {
+        None | Some(b"raw") => false,
+        Some(b"base64") => true,
+        Some(other) => {
+            let other = String::from_utf8_lossy(other);
+            return Response::error(400, format!("unknown encoding {other:?}"));
+        }
+    };
+    let method = request.method.as_str();
+
+    if let Some(key) = request.path.strip_prefix("/keys/") {
+        let Some(key) = percent_decode(key).filter(|key| !key.is_empty()) else {
+            return Response::error(400, "malformed key");
+        };
+        let result = match method {
+            "GET" => get(store, &key, base64),
+            "PUT" => put(store, &key, &request.body, base64),
+            "DELETE" => delete(store, &key),
+            _ => return Response::error(405, "use GET, PUT or DELETE"),
+        };
+        return result.unwrap_or_else(failed);
+    }
+
+    match (method, request.path.as_str()) {
+        ("GET", "/keys") => {
+            let prefix = request.query.get("prefix").map(Vec::as_slice);
+            scan(store, prefix.unwrap_or_default(), base64).unwrap_or_else(failed)
+        }
+        ("GET", "/stats") => match store.stats() {
+            Ok(stats) => {
+                let content_type = "text/plain; version=0.0.4";
+                Response::new(200, content_type, stats.to_prometheus().into_bytes())
+            }
+            Err(err) => failed(err),
+        },
+        (_, "/keys" | "/stats") => Response::error(405, "use GET"),
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+/// Deleted keys are stored as empty values, which the API treats as missing
+fn get_live(store: &mut KV, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    Ok(store.get(key)?.filter(|value| !value.is_empty()))
+}
+
+fn get(store: &mut KV, key: &ByteStr, base64: bool) -> io::Result<Response> {
+    Ok(match get_live(store, key)? {
+        None => Response::error(404, "key not found"),
+        Some(value) if base64 => Response::new(200, "text/plain", BASE64.encode(value).into()),
+        Some(value) => Response::new(200, "application/octet-stream", value),
+    })
+}
+
+fn put(store: &mut KV, key: &ByteStr, body: &ByteStr, base64: bool) -> io::Result<Response> {
+    let value = match base64 {
+        true => match BASE64.decode(body.trim_ascii()) {
+            Ok(value) => value,
+            Err(err) => return Ok(Response::error(400, format!("invalid base64: {err}"))),
+        },
+        false => body.to_vec(),
+    };
+    // An empty value would delete the key instead
+    if value.is_empty() {
+        return Ok(Response::error(400, "values can't be empty, use DELETE"));
+    }
+
+    store.insert(key, &value)?;
+    Ok(Response::empty(204))
+}
+
+fn delete(store: &mut KV, key: &ByteStr) -> io::Result<Response> {
+    if get_live(store, key)?.is_none() {
+        return Ok(Response::error(404, "key not found"));
+    }
+
+    store.delete(key)?;
+    Ok(Response::empty(204))
+}
+
+fn scan(store: &mut KV, prefix: &ByteStr, base64: bool) -> io::Result<Response> {
+    let found = store.scan(prefix)?;
+
+    let encode = |bytes: ByteString| match base64 {
+        true => Some(BASE64.encode(bytes)),
+        false => String::from_utf8(bytes).ok(),
+    };
+    let mut entries = Vec::with_capacity(found.len());
+    for kv in found {
+        let (Some(key), Some(value)) = (encode(kv.key), encode(kv.value)) else {
+            let message = "a key or value isn't UTF-8, scan with encoding=base64";
+            return Ok(Response::error(406, message));
+        };
+        entries.push(json!({ "key": key, "value": value }));
+    }
+
+    let body = serde_json::to_vec(&Value::Array(entries)).map_err(io::Error::other)?;
+    Ok(Response::new(200, "application/json", body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Sends `request` on a connection of its own, returning the status and the body
+    fn send(address: &str, request: &str) -> (u16, ByteString) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..end]);
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[end + 4..].to_vec())
+    }
+
+    fn request(method: &str, target: &str, body: &str) -> String {
+        format!(
+            "{method} {target} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn keys_can_be_written_read_and_scanned_over_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KV::open(&dir.path().join("store.db")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(store, listener));
+
+        assert_eq!(
+            send(&address, &request("PUT", "/keys/fruit%2Fapple", "red")).0,
+            204
+        );
+        let put = request("PUT", "/keys/fruit%2Fkiwi?encoding=base64", "Z3JlZW4=");
+        assert_eq!(send(&address, &put).0, 204);
+        assert_eq!(send(&address, &request("PUT", "/keys/veg", "leek")).0, 204);
+
+        let got = send(&address, &request("GET", "/keys/fruit%2Fkiwi", ""));
+        assert_eq!(got, (200, b"green".to_vec()));
+        let got = send(&address, &request("GET", "/keys/veg?encoding=base64", ""));
+        assert_eq!(got, (200, b"bGVlaw==".to_vec()));
+
+        let (status, body) = send(&address, &request("GET", "/keys?prefix=fruit%2F", ""));
+        assert_eq!(status, 200);
+        let found: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            found,
+            json!([
+                {"key": "fruit/apple", "value": "red"},
+                {"key": "fruit/kiwi", "value": "green"},
+            ])
+        );
+
+        assert_eq!(send(&address, &request("DELETE", "/keys/veg", "")).0, 204);
+        assert_eq!(send(&address, &request("GET", "/keys/veg", "")).0, 404);
+        assert_eq!(send(&address, &request("DELETE", "/keys/veg", "")).0, 404);
+        assert_eq!(send(&address, &request("POST", "/keys/veg", "")).0, 405);
+        assert_eq!(send(&address, &request("GET", "/nowhere", "")).0, 404);
+
+        let (status, body) = send(&address, &request("GET", "/stats", ""));
+        assert_eq!(status, 200);
+        assert!(
+            String::from_utf8(body)
+                .unwrap()
+                .contains("kv_writes_total 4")
+        );
+
+        // Several requests on one connection are served in turn
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let pipelined = "GET /keys/fruit%2Fapple HTTP/1.1\r\n\r\n".to_string()
+            + &request("GET", "/keys/fruit%2Fkiwi", "");
+        stream.write_all(pipelined.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\nred") && response.ends_with("\r\n\r\ngreen"));
+    }
+
+    #[test]
+    fn clients_are_held_to_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KV::open(&dir.path().join("store.db")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let limits = ServerLimits {
+            connections: 1,
+            timeout: Duration::from_millis(300),
+            body_len: 10,
+        };
+        thread::spawn(move || serve_with_limits(store, listener, limits));
+
+        // Bodies longer than the limit are refused before they're read
+        let long = format!(
+            "PUT /keys/a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            1 << 30
+        );
+        assert_eq!(send(&address, &long).0, 413);
+        assert_eq!(
+            send(&address, &request("PUT", "/keys/a", "0123456789")).0,
+            204
+        );
+
+        // A client sending nothing holds the only connection, until it times out
+        let mut idle = TcpStream::connect(&address).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        let got = send(&address, &request("GET", "/keys/a", ""));
+        assert_eq!(got, (200, b"0123456789".to_vec()));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let mut response = Vec::new();
+        idle.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,56 @@
+//!
+//! A log-structured key value store
+//!
+//! Every write appends a record to a single data file, and an in-memory index maps each key
+//! to its latest record. `KV` is the store itself: open it, `load` the index, then read and
+//! write through it. Deleted keys are written as empty values. Compaction drops the records
+//! superseded since.
+//!
+//! The modules made public hold what isn't a method of `KV`: backups, parity, replication,
+//! the HTTP API, merge operators and the load generator behind `kv_bench`. The `kv_mem`
+//! binary is a command line interface to all of it.
+//!
+
+#[cfg(feature = "async")]
+mod async_kv;
+pub mod backup;
+pub mod bench;
+mod chunked;
+mod compaction;
+mod crypto;
+#[cfg(test)]
+mod fault;
+mod format;
+mod group_commit;
+pub mod http;
+pub mod loadgen;
+pub mod merge;
+mod mmap;
+#[cfg(test)]
+mod model;
+mod namespace;
+mod parallel_load;
+pub mod parity;
+mod record_ref;
+pub mod replication;
+mod secondary;
+mod sstable;
+mod stats;
+mod store;
+mod stream;
+mod sync_marker;
+mod watch;
+
+#[cfg(feature = "async")]
+pub use async_kv::AsyncKV;
+pub use crypto::{Cipher, KEY_LEN};
+pub use format::{Checksum, LogId};
+pub use group_commit::GroupCommit;
+pub use mmap::MmapReader;
+pub use namespace::Namespace;
+pub use secondary::Extractor;
+pub use sstable::{SsTableRange, SsTableReader};
+pub use stats::Stats;
+pub use store::{ByteStr, ByteString, KV, KeyValuePair, Limits, MAX_KEY_LEN, RecordError};
+pub use stream::{ValueReader, ValueWriter};
+pub use watch::{Change, Event, Subscription, Tail};
@@ -0,0 +1,465 @@
+//!
+//! Load generator behind the `kv_bench` binary
+//!
+//! Fills a store with a fixed set of keys, then runs a mix of reads and writes against it
+//! and reports throughput and latency percentiles for each kind of operation.
+//!
+
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, RngCore, SeedableRng, rngs::StdRng};
+
+use crate::{
+    format::Checksum,
+    store::{ByteString, KV},
+};
+
+const USAGE: &str = "
+Usage:
+    kv_bench [OPTIONS]
+
+Options:
+    --file PATH            Data file to create, which must not exist yet [default: a new
+                           file in the temp dir, removed afterwards]
+    --keys N               Distinct keys, all inserted before measuring [default: 10000]
+    --ops N                Operations measured [default: 100000]
+    --key-size BYTES       At least the digits of the largest key number [default: 16]
+    --value-size BYTES     [default: 100]
+    --read-ratio RATIO     Share of operations that are reads, 0 to 1 [default: 0.9]
+    --distribution NAME    uniform, or zipfian for a few hot keys [default: uniform]
+    --durability MODE      none, or sync to sync every write, or batch:N to sync every N
+                           writes [default: none]
+    --checksum NAME        crc32, crc32c or xxhash64 [default: crc32]
+    --seed N               Seed for the random choices [default: 0]
+";
+
+/// How keys are picked for each operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Uniform,
+    /// A few keys get most operations, as in many real workloads
+    Zipfian,
+}
+
+/// When writes are made durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Left to the OS
+    None,
+    /// Every write is synced before the next operation
+    Sync,
+    /// Every N writes are synced together
+    Batch(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Where the store is created. A temporary file if not given
+    pub file: Option<PathBuf>,
+    pub keys: usize,
+    pub ops: usize,
+    pub key_size: usize,
+    pub value_size: usize,
+    pub read_ratio: f64,
+    pub distribution: Distribution,
+    pub durability: Durability,
+    pub checksum: Checksum,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            file: None,
+            keys: 10_000,
+            ops: 100_000,
+            key_size: 16,
+            value_size: 100,
+            read_ratio: 0.9,
+            distribution: Distribution::Uniform,
+            durability: Durability::None,
+            checksum: Checksum::default(),
+            seed: 0,
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{message}\n{USAGE}"))
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value {value:?} for {name}")))
+}
+
+impl Config {
+    /// Reads the options given on the command line, without the program name
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> io::Result<Self> {
+        let mut config = Self::default();
+
+        while let Some(name) = args.next() {
+            if name == "--help" {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE));
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| invalid(format!("missing value for {name}")))?;
+
+            match name.as_str() {
+                "--file" => config.file = Some(PathBuf::from(value)),
+                "--keys" => config.keys = parse(&name, &value)?,
+                "--ops" => config.ops = parse(&name, &value)?,
+                "--key-size" => config.key_size = parse(&name, &value)?,
+                "--value-size" => config.value_size = parse(&name, &value)?,
+                "--read-ratio" => config.read_ratio = parse(&name, &value)?,
+                "--distribution" => {
+                    config.distribution = match value.as_str() {
+                        "uniform" => Distribution::Uniform,
+                        "zipfian" => Distribution::Zipfian,
+                        _ => return Err(invalid(format!("unknown distribution {value:?}"))),
+                    }
+                }
+                "--durability" => {
+                    config.durability = match value.as_str() {
+                        "none" => Durability::None,
+                        "sync" => Durability::Sync,
+                        _ => match value.strip_prefix("batch:") {
+                            Some(n) => Durability::Batch(parse(&name, n)?),
+                            None => return Err(invalid(format!("unknown durability {value:?}"))),
+                        },
+                    }
+                }
+                "--checksum" => config.checksum = value.parse()?,
+                "--seed" => config.seed = parse(&name, &value)?,
+                _ => return Err(invalid(format!("unknown option {name}"))),
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fails if the options contradict each other, or can't be run
+    pub fn validate(&self) -> io::Result<()> {
+        if self.keys == 0 || !(0.0..=1.0).contains(&self.read_ratio) {
+            return Err(invalid(
+                "keys must be positive, and the read ratio within 0 and 1".into(),
+            ));
+        }
+        if self.durability == Durability::Batch(0) {
+            return Err(invalid("batches must hold at least one write".into()));
+        }
+
+        // Keys are their number in decimal, so every key must have room for all digits
+        let digits = (self.keys - 1).to_string().len();
+        if self.key_size < digits {
+            return Err(invalid(format!(
+                "{} keys need keys of at least {digits} bytes, not {}",
+                self.keys, self.key_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+///
+/// Picks key numbers following a Zipf distribution with an exponent of 0.99
+///
+/// The cumulative distribution is computed up front, so sampling is a binary search.
+///
+struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    const EXPONENT: f64 = 0.99;
+
+    fn new(n: usize) -> Self {
+        let mut sum = 0.0;
+        let mut cdf: Vec<f64> = (1..=n)
+            .map(|rank| {
+                sum += 1.0 / (rank as f64).powf(Self::EXPONENT);
+                sum
+            })
+            .collect();
+        for p in &mut cdf {
+            *p /= sum;
+        }
+
+        Self { cdf }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let p: f64 = rng.r#gen();
+        self.cdf.partition_point(|&c| c < p).min(self.cdf.len() - 1)
+    }
+}
+
+/// Latencies of one kind of operation
+#[derive(Debug, Default)]
+pub struct Latencies {
+    samples: Vec<Duration>,
+}
+
+impl Latencies {
+    fn record(&mut self, latency: Duration) {
+        self.samples.push(latency);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The latency `quantile` of all operations were at most, e.g. 0.99 for p99
+    pub fn percentile(&mut self, quantile: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+
+        self.samples.sort_unstable();
+        let rank = (quantile * (self.samples.len() - 1) as f64).round() as usize;
+        self.samples[rank]
+    }
+}
+
+/// What a run measured
+#[derive(Debug, Default)]
+pub struct Report {
+    pub elapsed: Duration,
+    pub reads: Latencies,
+    pub writes: Latencies,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops = self.reads.len() + self.writes.len();
+        writeln!(
+            f,
+            "{ops} operations in {:.2?}: {:.0} ops/s",
+            self.elapsed,
+            ops as f64 / self.elapsed.as_secs_f64()
+        )?;
+
+        // Sorting needs mutable access, so the samples are copied
+        for (name, latencies) in [("reads", &self.reads), ("writes", &self.writes)] {
+            if latencies.is_empty() {
+                continue;
+            }
+
+            let mut latencies = Latencies {
+                samples: latencies.samples.clone(),
+            };
+            writeln!(
+                f,
+                "{name:<6} {:>8}  p50 {:>9.1?}  p90 {:>9.1?}  p99 {:>9.1?}  p99.9 {:>9.1?}  max {:>9.1?}",
+                latencies.len(),
+                latencies.percentile(0.5),
+                latencies.percentile(0.9),
+                latencies.percentile(0.99),
+                latencies.percentile(0.999),
+                latencies.percentile(1.0),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Key number `n`, padded to `size` bytes, which must fit its digits
+fn key(n: usize, size: usize) -> ByteString {
+    format!("{n:0size$}").into_bytes()
+}
+
+///
+/// Fills a new store as configured, then measures the configured mix of operations
+///
+/// The data file is created, so an existing file is never written to. A temporary one is
+/// removed once the run is over.
+///
+pub fn run(config: &Config) -> io::Result<Report> {
+    config.validate()?;
+
+    let path = match &config.file {
+        Some(path) => path.clone(),
+        None => std::env::temp_dir().join(format!("kv_bench-{:016x}.db", rand::random::<u64>())),
+    };
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+
+    let report = measure(config, &path);
+    if config.file.is_none() {
+        let _ = fs::remove_file(&path);
+    }
+    report
+}
+
+/// Fills the new store at `path`, then measures
+fn measure(config: &Config, path: &std::path::Path) -> io::Result<Report> {
+    let mut store = KV::open(path)?;
+    store.set_checksum(config.checksum)?;
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut value = vec![0; config.value_size];
+
+    // Every key exists before measuring, so every read finds something
+    let keys: Vec<ByteString> = (0..config.keys).map(|n| key(n, config.key_size)).collect();
+    for key in &keys {
+        rng.fill_bytes(&mut value);
+        store.insert(key, &value)?;
+    }
+    store.sync()?;
+
+    let zipf = match config.distribution {
+        Distribution::Uniform => None,
+        Distribution::Zipfian => Some(Zipf::new(config.keys)),
+    };
+
+    let mut report = Report::default();
+    let mut unsynced = 0;
+    let start = Instant::now();
+
+    for _ in 0..config.ops {
+        let n = match &zipf {
+            Some(zipf) => zipf.sample(&mut rng),
+            None => rng.gen_range(0..config.keys),
+        };
+        let key = &keys[n];
+
+        if rng.gen_bool(config.read_ratio) {
+            let op = Instant::now();
+            std::hint::black_box(store.get(key)?);
+            report.reads.record(op.elapsed());
+            continue;
+        }
+
+        rng.fill_bytes(&mut value);
+        let op = Instant::now();
+        store.insert(key, &value)?;
+        unsynced += 1;
+        match config.durability {
+            Durability::None => {}
+            Durability::Sync => store.sync()?,
+            Durability::Batch(n) => {
+                if unsynced >= n {
+                    store.sync()?;
+                    unsynced = 0;
+                }
+            }
+        }
+        report.writes.record(op.elapsed());
+    }
+
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> io::Result<Config> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options_are_checked_and_files_are_never_overwritten() {
+        let config = args(&[]).unwrap();
+        assert_eq!(
+            (config.file, config.keys, config.key_size),
+            (None, 10_000, 16)
+        );
+
+        let config = args(&[
+            "--keys",
+            "100",
+            "--key-size",
+            "2",
+            "--durability",
+            "batch:8",
+            "--distribution",
+            "zipfian",
+            "--checksum",
+            "xxhash64",
+        ])
+        .unwrap();
+        assert_eq!(config.durability, Durability::Batch(8));
+        assert_eq!(config.distribution, Distribution::Zipfian);
+        assert_eq!(config.checksum, Checksum::XxHash64);
+
+        // Keys too short to tell all of them apart are refused
+        for invalid in [
+            &["--keys", "101", "--key-size", "2"][..],
+            &["--key-size", "0"],
+            &["--keys", "0"],
+            &["--read-ratio", "1.5"],
+            &["--durability", "batch:0"],
+            &["--distribution", "normal"],
+            &["--keys"],
+            &["--bogus", "1"],
+        ] {
+            let err = args(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{invalid:?}");
+        }
+        let keys: Vec<ByteString> = (0..100).map(|n| key(n, 2)).collect();
+        assert!(keys.iter().all(|key| key.len() == 2));
+        assert_eq!(
+            keys.iter().collect::<std::collections::HashSet<_>>().len(),
+            100
+        );
+
+        // Existing files are left alone
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("precious.txt");
+        std::fs::write(&path, b"not a store").unwrap();
+        let mut config = Config {
+            file: Some(path.clone()),
+            keys: 50,
+            ops: 200,
+            ..Config::default()
+        };
+        let err = run(&config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a store");
+
+        // New ones are kept for inspection
+        config.file = Some(dir.path().join("bench.db"));
+        let report = run(&config).unwrap();
+        assert_eq!(report.reads.len() + report.writes.len(), 200);
+        let mut store = KV::open(config.file.as_ref().unwrap()).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index().len(), 50);
+
+        // And temporary ones are removed
+        let temporary = || -> std::collections::HashSet<_> {
+            std::fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name())
+                .filter(|name| name.to_string_lossy().starts_with("kv_bench-"))
+                .collect()
+        };
+        let before = temporary();
+        config.file = None;
+        config.durability = Durability::Batch(10);
+        let report = run(&config).unwrap();
+        assert_eq!(report.reads.len() + report.writes.len(), 200);
+        assert!(temporary().is_subset(&before));
+    }
+}
@@ -0,0 +1,372 @@
+//!
+//! Merge operators: read-modify-write without the read
+//!
+//! `KV::merge` appends a merge operand for a key, such as an amount to add to a counter,
+//! instead of reading the value, changing it and writing it back. Operand records have
+//! `FLAG_MERGE` set. The index keeps pointing to the value they apply to, and the operands
+//! written since are kept in a list beside it. `get` folds them into the value with the
+//! store's `MergeOperator` every time, until compaction writes the folded value in their
+//! place.
+//!
+//! The operator isn't recorded in the file, so a store holding operands must be given the
+//! same one every time it's opened. Merges only work in the default namespace.
+//!
+
+use std::{fmt, io, sync::Arc};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde_json::Value;
+
+use crate::{
+    store::{self, ByteStr, ByteString, FLAG_MERGE, KV, KeyValuePair},
+    watch::Event,
+};
+
+///
+/// Folds merge operands into the value they apply to
+///
+/// `existing` is `None` if the key had no value before the operands, or was deleted.
+/// Operands are given oldest first.
+///
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    fn merge(
+        &self,
+        key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> io::Result<ByteString>;
+}
+
+fn invalid_operand(key: &ByteStr, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "merge operand of {:?} isn't {what}",
+            String::from_utf8_lossy(key)
+        ),
+    )
+}
+
+/// Adds little endian u64 operands to a little endian u64 counter, wrapping on overflow
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Add;
+
+impl MergeOperator for U64Add {
+    fn merge(
+        &self,
+        key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> io::Result<ByteString> {
+        let read = |mut bytes: &ByteStr| match bytes.len() {
+            8 => bytes.read_u64::<LittleEndian>(),
+            _ => Err(invalid_operand(key, "a u64")),
+        };
+
+        let mut sum = existing.map(read).transpose()?.unwrap_or(0);
+        for operand in operands {
+            sum = sum.wrapping_add(read(operand)?);
+        }
+        Ok(sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Appends operands to the value, with a delimiter between elements
+#[derive(Debug, Clone, Default)]
+pub struct ListAppend {
+    pub delimiter: ByteString,
+}
+
+impl MergeOperator for ListAppend {
+    fn merge(
+        &self,
+        _key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> io::Result<ByteString> {
+        let mut list = existing.map(<[u8]>::to_vec).unwrap_or_default();
+        for operand in operands {
+            if !list.is_empty() {
+                list.extend_from_slice(&self.delimiter);
+            }
+            list.extend_from_slice(operand);
+        }
+        Ok(list)
+    }
+}
+
+/// Applies operands as JSON merge patches (RFC 7386) to a JSON document
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonMergePatch;
+
+impl JsonMergePatch {
+    fn apply(target: &mut Value, patch: Value) {
+        let Value::Object(patch) = patch else {
+            *target = patch;
+            return;
+        };
+
+        if !target.is_object() {
+            *target = Value::Object(Default::default());
+        }
+        let Value::Object(target) = target else {
+            unreachable!()
+        };
+
+        for (name, value) in patch {
+            match value {
+                Value::Null => {
+                    target.remove(&name);
+                }
+                value => Self::apply(target.entry(name).or_insert(Value::Null), value),
+            }
+        }
+    }
+}
+
+impl MergeOperator for JsonMergePatch {
+    fn merge(
+        &self,
+        key: &ByteStr,
+        existing: Option<&ByteStr>,
+        operands: &[ByteString],
+    ) -> io::Result<ByteString> {
+        let parse = |bytes: &ByteStr| -> io::Result<Value> {
+            serde_json::from_slice(bytes).map_err(|_| invalid_operand(key, "JSON"))
+        };
+
+        let mut document = existing.map(parse).transpose()?.unwrap_or(Value::Null);
+        for operand in operands {
+            Self::apply(&mut document, parse(operand)?);
+        }
+        serde_json::to_vec(&document).map_err(io::Error::other)
+    }
+}
+
+///
+/// Folds the operands of `key` into the value they apply to
+///
+/// `first` is the value of the record the index points to, which is itself an operand if
+/// `first_is_operand`. Empty values are deleted keys, which operands apply to as if there
+/// was no value.
+///
+pub(super) fn fold(
+    operator: Option<&dyn MergeOperator>,
+    key: &ByteStr,
+    first: ByteString,
+    first_is_operand: bool,
+    mut later: Vec<ByteString>,
+) -> io::Result<ByteString> {
+    let operator = operator.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "value has merge operands, but no merge operator was set",
+        )
+    })?;
+
+    let existing = match first_is_operand {
+        true => {
+            later.insert(0, first);
+            None
+        }
+        false => Some(first).filter(|value| !value.is_empty()),
+    };
+    operator.merge(key, existing.as_deref(), &later)
+}
+
+impl KV {
+    /// Folds merge operands with `operator` from now on
+    pub fn set_merge_operator<M: MergeOperator + 'static>(&mut self, operator: M) {
+        self.merge_operator = Some(Arc::new(operator));
+    }
+
+    ///
+    /// Appends a merge operand for `key`, folded into its value on `get`
+    ///
+    /// Operands must fit in a single record, see `Limits::chunk_len`. Subscribers get the
+    /// operand, see `Change::Merge`. Secondary indexes get the folded value, so it's read
+    /// back if there are any.
+    ///
+    pub fn merge(&mut self, key: &ByteStr, operand: &ByteStr) -> io::Result<()> {
+        self.merge_after(None, key, operand).map(drop)
+    }
+
+    ///
+    /// Like `merge`, writing the encoded record `before` right before the operand, with the
+    /// same write
+    ///
+    /// Returns the position of `before`, which is left for the caller to index, or of the
+    /// operand if there's none.
+    ///
+    pub(super) fn merge_after(
+        &mut self,
+        before: Option<&ByteStr>,
+        key: &ByteStr,
+        operand: &ByteStr,
+    ) -> io::Result<u64> {
+        self.ensure_writable()?;
+        self.check_key(key)?;
+        if operand.len() > self.limits.chunk_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "merge operand is {} bytes, at most {} are allowed",
+                    operand.len(),
+                    self.limits.chunk_len
+                ),
+            ));
+        }
+
+        let (format, cipher) = (self.format, self.cipher.as_ref());
+        let record = Self::encode_record_in(key, operand, FLAG_MERGE, 0, format, cipher)?;
+        let records: Vec<&ByteStr> = before.into_iter().chain([&record[..]]).collect();
+        let positions = self.append_together(&records)?;
+        let position = *positions.last().expect("the operand was written");
+        let (index, operands) = (&mut self.index, &mut self.operands);
+        store::index_record(index, operands, FLAG_MERGE, key, position);
+        self.stats.records += 1;
+        self.stats.writes += 1;
+
+        if !self.subscribers.is_empty() {
+            self.notify(Event::merge(position, key.to_vec(), operand.to_vec()));
+        }
+        self.update_secondary([key.to_vec()])?;
+        Ok(positions[0])
+    }
+
+    ///
+    /// Reads the value of `key` in the namespace with id `namespace`, stored at `position`
+    ///
+    /// Merge operands are folded into the value.
+    ///
+    pub(super) fn read_value(
+        &mut self,
+        namespace: u32,
+        key: &ByteStr,
+        position: u64,
+    ) -> io::Result<KeyValuePair> {
+        let (header, mut kv) = self.get_record_at(position)?;
+        let is_operand = header.flags & FLAG_MERGE != 0;
+        let later = match namespace {
+            0 => self.operands.get(key).cloned().unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        if !is_operand && later.is_empty() {
+            return Ok(kv);
+        }
+
+        let mut operands = Vec::with_capacity(later.len());
+        for position in later {
+            operands.push(self.get_at(position)?.value);
+        }
+
+        let operator = self.merge_operator.as_deref();
+        kv.value = fold(operator, key, kv.value, is_operand, operands)?;
+        Ok(kv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watch::Change;
+
+    #[test]
+    fn operands_are_folded_on_get_and_by_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store.set_merge_operator(U64Add);
+        let events = store.subscribe(b"visits");
+        store.merge(b"visits", &3u64.to_le_bytes()).unwrap();
+        store.merge(b"visits", &4u64.to_le_bytes()).unwrap();
+        store.insert(b"likes", &10u64.to_le_bytes()).unwrap();
+        store.merge(b"likes", &1u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            store.get(b"visits").unwrap(),
+            Some(7u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get(b"likes").unwrap(),
+            Some(11u64.to_le_bytes().to_vec())
+        );
+
+        // Operands are delivered as they were written, and replayed the same way
+        let merged = |operand: u64| Change::Merge(operand.to_le_bytes().to_vec());
+        let delivered: Vec<Event> = std::iter::from_fn(|| events.try_next()).collect();
+        assert_eq!(
+            delivered
+                .iter()
+                .map(|event| &event.change)
+                .collect::<Vec<_>>(),
+            [&merged(3), &merged(4)]
+        );
+        let replayed: Vec<Event> = store
+            .tail(0)
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|event| event.key == b"visits")
+            .collect();
+        assert_eq!(replayed, delivered);
+
+        // Deleting drops the operands along with the value
+        store.delete(b"likes").unwrap();
+        store.merge(b"likes", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            store.get(b"likes").unwrap(),
+            Some(2u64.to_le_bytes().to_vec())
+        );
+
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.set_merge_operator(U64Add);
+        store.load().unwrap();
+        store.merge(b"visits", &5u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            store.get(b"visits").unwrap(),
+            Some(12u64.to_le_bytes().to_vec())
+        );
+        let reader = store.mmap_reader().unwrap();
+        assert_eq!(
+            reader.get(b"visits").unwrap().unwrap(),
+            &12u64.to_le_bytes()[..]
+        );
+
+        store.compact().unwrap();
+        assert!(store.operands.is_empty());
+        assert_eq!(store.scan(b"").unwrap().len(), 2);
+        assert_eq!(
+            store.get(b"visits").unwrap(),
+            Some(12u64.to_le_bytes().to_vec())
+        );
+
+        // A store without the operator can't read the operands back
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        store.merge(b"visits", &1u64.to_le_bytes()).unwrap();
+        assert!(store.get(b"visits").is_err());
+    }
+
+    #[test]
+    fn built_in_operators() {
+        let list = ListAppend {
+            delimiter: b",".to_vec(),
+        };
+        let operands = [b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(list.merge(b"k", None, &operands).unwrap(), b"b,c");
+        assert_eq!(list.merge(b"k", Some(b"a"), &operands).unwrap(), b"a,b,c");
+
+        let patch = [br#"{"name":"Ada","address":{"city":null,"zip":"123"}}"#.to_vec()];
+        let document = br#"{"address":{"city":"London"},"age":36}"#;
+        let merged = JsonMergePatch.merge(b"k", Some(document), &patch).unwrap();
+        let merged: Value = serde_json::from_slice(&merged).unwrap();
+        assert_eq!(
+            merged,
+            serde_json::json!({"name": "Ada", "address": {"zip": "123"}, "age": 36})
+        );
+
+        assert!(U64Add.merge(b"k", None, &[b"short".to_vec()]).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//!
+//! Zero-copy reads from a memory map of the data file
+//!
+//! Appending leaves everything up to the current end of the file as it is: it's sealed.
+//! `MmapReader` maps that part once and answers lookups with slices pointing straight into
+//! the mapping, instead of seeking and copying for every call like `KV::get` does.
+//!
+//! Not everything only appends though. Compaction swaps in a new file, and choosing the
+//! checksum or loading a store with a torn tail truncates it, which makes the mapped bytes
+//! go away under the reader. So a reader borrows the store, and nothing can be written to it
+//! while the reader lives. Making a new reader afterwards maps the file anew.
+//!
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, Cursor},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::{
+    chunked,
+    crypto::Cipher,
+    format::Format,
+    merge::{self, MergeOperator},
+    record_ref,
+    store::{
+        ByteStr, ByteString, FLAG_ENCRYPTED, FLAG_MANIFEST, FLAG_MERGE, Header, KV, RecordError,
+    },
+};
+
+///
+/// Lookups against the sealed part of a store
+///
+/// Holds a copy of the index as it was when the reader was made, and can be shared between
+/// threads. It borrows the store, so the store can't be written to until it's dropped.
+///
+#[derive(Debug)]
+pub struct MmapReader<'a> {
+    // Empty files can't be mapped, so there's no mapping for those
+    map: Option<Mmap>,
+    index: HashMap<ByteString, u64>,
+    operands: HashMap<ByteString, Vec<u64>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    format: Format,
+    cipher: Option<Cipher>,
+    // Ties the reader to the borrow of the store. Only the lifetime is kept, since the
+    // store itself can't be shared between threads
+    store: PhantomData<&'a ()>,
+}
+
+impl MmapReader<'_> {
+    /// The mapped part of the log
+    pub fn bytes(&self) -> &ByteStr {
+        self.map.as_deref().unwrap_or_default()
+    }
+
+    ///
+    /// Looks up the latest value of `key`
+    ///
+    /// Values of plain records are borrowed from the mapping. Encrypted ones have to be
+    /// decrypted into a new buffer, and so do values with merge operands to fold, so those
+    /// are owned.
+    ///
+    pub fn get(&self, key: &ByteStr) -> io::Result<Option<Cow<'_, ByteStr>>> {
+        let Some(&position) = self.index.get(key) else {
+            return Ok(None);
+        };
+
+        let value = self.get_at(position)?;
+        let record = &self.bytes()[position as usize..];
+        let is_operand = Header::read(&mut &record[..])?.flags & FLAG_MERGE != 0;
+        let later = self.operands.get(key);
+        if !is_operand && later.is_none() {
+            return Ok(Some(value));
+        }
+
+        let later = later
+            .into_iter()
+            .flatten()
+            .map(|&position| Ok(self.get_at(position)?.into_owned()))
+            .collect::<io::Result<Vec<ByteString>>>()?;
+        let operator = self.merge_operator.as_deref();
+        let value = merge::fold(operator, key, value.into_owned(), is_operand, later)?;
+        Ok(Some(Cow::Owned(value)))
+    }
+
+    /// Reads the value of the record starting at `position`, checking it along the way
+    pub fn get_at(&self, position: u64) -> io::Result<Cow<'_, ByteStr>> {
+        let cipher = self.cipher.as_ref();
+        let record = usize::try_from(position)
+            .ok()
+            .and_then(|position| self.bytes().get(position..))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+        let header = Header::read(&mut &record[..])?;
+        let data = record
+            .get(header.len()..header.len() + header.data_len()?)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+        record_ref::check(&header, data, self.format)?;
+
+        // Large values are spread over several records, so they can't be borrowed
+        if header.flags & FLAG_MANIFEST != 0 {
+            let (_, manifest) = KV::process_record(&mut &record[..], self.format, cipher)?;
+            let mut f = Cursor::new(self.bytes());
+            let value = chunked::read_large(&mut f, position, &manifest, self.format, cipher)?;
+            return Ok(Cow::Owned(value));
+        }
+
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            let cipher = cipher.ok_or(RecordError::MissingKey)?;
+            let mut data = cipher.open(data, &header.lengths())?;
+            let value = data.split_off(header.key_len as usize);
+            return Ok(Cow::Owned(value));
+        }
+
+        Ok(Cow::Borrowed(&data[header.key_len as usize..]))
+    }
+}
+
+impl KV {
+    ///
+    /// Maps the data file as it is now, for zero-copy lookups with `MmapReader`
+    ///
+    /// The index must be loaded first, since the reader takes a copy of it.
+    ///
+    pub fn mmap_reader(&self) -> io::Result<MmapReader<'_>> {
+        let end = self.f.metadata()?.len();
+        let len = usize::try_from(end)
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "file too large to map"))?;
+
+        // SAFETY: The mapping is only valid as long as the mapped bytes don't change or go
+        // away. Everything changing them takes the store mutably, which the reader's borrow
+        // rules out while it lives, and the lock taken when opening the store keeps other
+        // well-behaved processes from writing. So the first `len` bytes stay as they are.
+        let map = match len {
+            0 => None,
+            _ => Some(unsafe { MmapOptions::new().len(len).map(&self.f)? }),
+        };
+
+        Ok(MmapReader {
+            map,
+            index: self.index.clone(),
+            operands: self.operands.clone(),
+            merge_operator: self.merge_operator.clone(),
+            format: self.format,
+            cipher: self.cipher.clone(),
+            store: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::KEY_LEN, store::Limits};
+
+    fn check(reader: &MmapReader, large: &ByteStr) {
+        let value = reader.get(b"plain").unwrap().unwrap();
+        assert!(matches!(value, Cow::Borrowed(_)));
+        assert_eq!(value, &b"value"[..]);
+        // Borrowed values point into the mapping
+        let bytes = reader.bytes().as_ptr_range();
+        assert!(bytes.contains(&value.as_ptr()));
+
+        let value = reader.get(b"secret").unwrap().unwrap();
+        assert!(matches!(value, Cow::Owned(_)));
+        assert_eq!(value, &b"hidden"[..]);
+        assert_eq!(reader.get(b"large").unwrap().unwrap(), large);
+        assert_eq!(reader.get(b"deleted").unwrap().unwrap(), &b""[..]);
+        assert_eq!(reader.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn readers_see_the_store_as_it_was_mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        let large = vec![3; 5000];
+        store.insert(b"plain", b"old").unwrap();
+        store.insert(b"plain", b"value").unwrap();
+        store.insert(b"large", &large).unwrap();
+        store.insert(b"deleted", b"value").unwrap();
+        store.delete(b"deleted").unwrap();
+        store.set_cipher(Cipher::new(&[1; KEY_LEN]));
+        store.insert(b"secret", b"hidden").unwrap();
+
+        // Readers can be shared between threads
+        let reader = store.mmap_reader().unwrap();
+        assert_eq!(
+            reader.bytes().len() as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| check(&reader, &large));
+            }
+        });
+
+        // Damage is caught like reading any other way
+        let position = store.index()[&b"plain"[..]];
+        assert!(reader.get_at(position + 1).is_err());
+        assert_eq!(
+            reader.get_at(u64::MAX).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        drop(reader);
+
+        // Once the reader is gone, writes and compaction can go on. A new reader maps the
+        // file they left behind
+        store.insert(b"plain", b"newer").unwrap();
+        store.compact().unwrap();
+        store.insert(b"after", b"compaction").unwrap();
+        let reader = store.mmap_reader().unwrap();
+        assert_eq!(
+            reader.bytes().len() as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+        assert_eq!(reader.get(b"plain").unwrap().unwrap(), &b"newer"[..]);
+        assert_eq!(reader.get(b"after").unwrap().unwrap(), &b"compaction"[..]);
+        assert_eq!(reader.get(b"large").unwrap().unwrap(), &large[..]);
+        assert_eq!(reader.get(b"secret").unwrap().unwrap(), &b"hidden"[..]);
+    }
+}
@@ -0,0 +1,149 @@
+//!
+//! Model checking: random operations on a store and on a `HashMap`, which must agree
+//!
+
+use std::{collections::HashMap, path::Path};
+
+use proptest::prelude::*;
+
+use crate::{
+    crypto::Cipher,
+    format::Checksum,
+    store::{ByteString, KV, Limits},
+};
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert(ByteString, ByteString),
+    Update(ByteString, ByteString),
+    Delete(ByteString),
+    Get(ByteString),
+    Scan(ByteString),
+    /// Closes the store and opens it again, rebuilding the index from the file
+    Reopen,
+    Compact,
+}
+
+/// How the store under test is set up, kept across reopening
+#[derive(Debug, Clone)]
+struct Setup {
+    checksum: Checksum,
+    encrypted: bool,
+}
+
+impl Setup {
+    fn open(&self, path: &Path) -> KV {
+        let mut store = KV::open(path).unwrap();
+        store.set_checksum(self.checksum).unwrap();
+        // Small chunks, so some values are split over several records
+        store
+            .set_limits(Limits {
+                chunk_len: 16,
+                ..Limits::default()
+            })
+            .unwrap();
+        if self.encrypted {
+            store.set_cipher(Cipher::new(&[7; 32]));
+        }
+
+        store.load().unwrap();
+        store
+    }
+}
+
+fn key() -> impl Strategy<Value = ByteString> {
+    // Few keys with shared prefixes, so they're overwritten and scanned together often
+    prop::collection::vec(b'a'..=b'c', 1..4)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let value = prop::collection::vec(any::<u8>(), 1..50);
+
+    prop_oneof![
+        4 => (key(), value.clone()).prop_map(|(key, value)| Op::Insert(key, value)),
+        2 => (key(), value).prop_map(|(key, value)| Op::Update(key, value)),
+        2 => key().prop_map(Op::Delete),
+        4 => key().prop_map(Op::Get),
+        1 => prop::collection::vec(b'a'..=b'c', 0..2).prop_map(Op::Scan),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn setup() -> impl Strategy<Value = Setup> {
+    (prop::sample::select(Checksum::ALL.to_vec()), any::<bool>()).prop_map(
+        |(checksum, encrypted)| Setup {
+            checksum,
+            encrypted,
+        },
+    )
+}
+
+proptest! {
+    #[test]
+    fn store_behaves_like_a_hash_map(setup in setup(), ops in prop::collection::vec(op(), 1..60)) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let mut store = setup.open(&path);
+        // Deleted keys keep an empty value until compacted, just like in the store
+        let mut model: HashMap<ByteString, ByteString> = HashMap::new();
+
+        for op in ops {
+            match op {
+                Op::Insert(key, value) => {
+                    store.insert(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+                Op::Update(key, value) => {
+                    store.update(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+                Op::Delete(key) => {
+                    store.delete(&key).unwrap();
+                    model.insert(key, ByteString::new());
+                }
+                Op::Get(key) => {
+                    prop_assert_eq!(store.get(&key).unwrap(), model.get(&key).cloned());
+                }
+                Op::Scan(prefix) => {
+                    let found: Vec<(ByteString, ByteString)> = store
+                        .scan(&prefix)
+                        .unwrap()
+                        .into_iter()
+                        .map(|kv| (kv.key, kv.value))
+                        .collect();
+
+                    let mut expected: Vec<(ByteString, ByteString)> = model
+                        .iter()
+                        .filter(|(key, value)| key.starts_with(&prefix) && !value.is_empty())
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect();
+                    expected.sort();
+                    prop_assert_eq!(found, expected);
+                }
+                Op::Reopen => {
+                    drop(store);
+                    store = setup.open(&path);
+                }
+                Op::Compact => {
+                    store.compact().unwrap();
+                    // Tombstones are dropped, and their key with them
+                    model.retain(|_, value| !value.is_empty());
+                }
+            }
+
+            let mut keys: Vec<&ByteString> = store.index.keys().collect();
+            let mut expected: Vec<&ByteString> = model.keys().collect();
+            keys.sort();
+            expected.sort();
+            prop_assert_eq!(keys, expected);
+        }
+
+        // Everything written must survive a final reopen too
+        drop(store);
+        let mut store = setup.open(&path);
+        for (key, value) in &model {
+            prop_assert_eq!(store.get(key).unwrap(), Some(value.clone()));
+        }
+    }
+}
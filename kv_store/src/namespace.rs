@@ -0,0 +1,816 @@
+//!
+//! Namespaces: separate sets of keys sharing one data file
+//!
+//! Every namespace has an index of its own, so the same key can hold unrelated values in
+//! different namespaces. Records outside the default namespace have `FLAG_NAMESPACE` set
+//! and carry the id of their namespace in their header, so `load` rebuilds every index in
+//! a single pass over the file.
+//!
+//! Names are given to ids by records in a namespace of their own, the catalog. The key of
+//! a catalog record is the name of a namespace, and its value is the id as a little endian
+//! u32, or empty once the namespace is dropped. Dropping a namespace only writes to the
+//! catalog; its records stay in the file until the store is compacted, and its id isn't
+//! given to another namespace meanwhile.
+//!
+//! `KV::index`, `KV::get` and the like are about the default namespace, and so are
+//! subscriptions, `KV::tail` and `KV::find`.
+//!
+//! Local keys, see `KV::get_local`, are a namespace of their own too. It has a reserved id
+//! and no name, and replication followers leave its records out.
+//!
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, BufWriter, Seek, SeekFrom, Write},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{
+    chunked::Manifest,
+    record_ref::RecordRef,
+    store::{
+        self, ByteStr, ByteString, FLAG_CHUNK, FLAG_MANIFEST, FLAG_SYNC, Header, KV, KeyValuePair,
+    },
+    sync_marker,
+};
+
+/// Id of the catalog, holding the names of the other namespaces
+pub(super) const CATALOG: u32 = u32::MAX;
+
+/// Id of the local keys, which belong to the data file they're in
+pub(super) const LOCAL: u32 = u32::MAX - 1;
+
+/// A namespace other than the default one
+#[derive(Debug)]
+struct Entry {
+    name: ByteString,
+    // Position of the catalog record naming it
+    position: u64,
+    index: HashMap<ByteString, u64>,
+}
+
+/// The indexes of the namespaces other than the default one
+#[derive(Debug)]
+pub(super) struct Namespaces {
+    by_id: HashMap<u32, Entry>,
+    // Index of the local keys, see `KV::get_local`
+    local: HashMap<ByteString, u64>,
+    // Lowest id not used by any namespace in the file, dropped ones included
+    next_id: u32,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            by_id: HashMap::new(),
+            local: HashMap::new(),
+            // 0 is the default namespace
+            next_id: 1,
+        }
+    }
+}
+
+impl Namespaces {
+    fn id(&self, name: &ByteStr) -> Option<u32> {
+        self.by_id
+            .iter()
+            .find(|(_, entry)| entry.name == name)
+            .map(|(id, _)| *id)
+    }
+
+    fn entry(&self, id: u32) -> &Entry {
+        &self.by_id[&id]
+    }
+
+    fn entry_mut(&mut self, id: u32) -> io::Result<&mut Entry> {
+        self.by_id.get_mut(&id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no namespace with id {id}"),
+            )
+        })
+    }
+
+    ///
+    /// Indexes a record of the namespace with id `namespace`, read at `position`
+    ///
+    /// Records of dropped namespaces are left out.
+    ///
+    pub fn index_record(
+        &mut self,
+        namespace: u32,
+        position: u64,
+        record: RecordRef<'_>,
+    ) -> io::Result<()> {
+        if namespace == CATALOG {
+            return self.name(position, record.key.to_vec(), record.value);
+        }
+
+        let index = match namespace {
+            LOCAL => Some(&mut self.local),
+            _ => self.by_id.get_mut(&namespace).map(|entry| &mut entry.index),
+        };
+        if let Some(index) = index {
+            match index.get_mut(record.key) {
+                Some(indexed) => *indexed = position,
+                None => {
+                    index.insert(record.key.to_vec(), position);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the catalog record at `position`, naming or dropping a namespace
+    fn name(&mut self, position: u64, name: ByteString, value: &ByteStr) -> io::Result<()> {
+        if value.is_empty() {
+            self.by_id.retain(|_, entry| entry.name != name);
+            return Ok(());
+        }
+
+        let id = match value {
+            [_, _, _, _] => (&value[..]).read_u32::<LittleEndian>()?,
+            _ => 0,
+        };
+        if id == 0 || id >= LOCAL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("catalog record at {position} doesn't hold a namespace id"),
+            ));
+        }
+
+        // A namespace named again with the same id keeps its records
+        if let Some(entry) = self.by_id.get_mut(&id)
+            && entry.name == name
+        {
+            entry.position = position;
+            return Ok(());
+        }
+
+        self.by_id.retain(|_, entry| entry.name != name);
+        self.by_id.insert(
+            id,
+            Entry {
+                name,
+                position,
+                index: HashMap::new(),
+            },
+        );
+        self.next_id = self.next_id.max(id + 1);
+        Ok(())
+    }
+
+    /// Number of keys indexed, catalog records and local keys included
+    pub fn live_records(&self) -> u64 {
+        let keys: usize = self.by_id.values().map(|entry| entry.index.len() + 1).sum();
+        (keys + self.local.len()) as u64
+    }
+
+    /// Position, namespace and key of every indexed record, catalog records and local keys
+    /// included
+    pub fn positions(&self) -> Vec<(u64, u32, ByteString)> {
+        let mut positions: Vec<(u64, u32, ByteString)> = self
+            .local
+            .iter()
+            .map(|(key, position)| (*position, LOCAL, key.clone()))
+            .collect();
+        for (id, entry) in &self.by_id {
+            positions.push((entry.position, CATALOG, entry.name.clone()));
+            positions.extend(
+                entry
+                    .index
+                    .iter()
+                    .map(|(key, position)| (*position, *id, key.clone())),
+            );
+        }
+        positions
+    }
+
+    ///
+    /// Replaces the indexes with those of a compacted file, catalog included
+    ///
+    /// Records of dropped namespaces are gone from such a file, so their ids are free again.
+    ///
+    pub fn reindex(&mut self, mut indexes: HashMap<u32, HashMap<ByteString, u64>>) {
+        let catalog = indexes.remove(&CATALOG).unwrap_or_default();
+        self.local = indexes.remove(&LOCAL).unwrap_or_default();
+        for (id, entry) in &mut self.by_id {
+            entry.position = catalog[&entry.name];
+            entry.index = indexes.remove(id).unwrap_or_default();
+        }
+        self.next_id = self.by_id.keys().max().map_or(1, |id| id + 1);
+    }
+
+    /// Moves every indexed position with `moved`, after records were taken out of the file
+    fn remap<F: Fn(u64) -> u64>(&mut self, moved: F) {
+        for position in self.local.values_mut() {
+            *position = moved(*position);
+        }
+        for entry in self.by_id.values_mut() {
+            entry.position = moved(entry.position);
+            for position in entry.index.values_mut() {
+                *position = moved(*position);
+            }
+        }
+    }
+}
+
+///
+/// A namespace of a store, see `KV::namespace`
+///
+/// Works like the store itself, with keys of its own.
+///
+pub struct Namespace<'a> {
+    store: &'a mut KV,
+    id: u32,
+}
+
+impl KV {
+    ///
+    /// Creates the namespace `name`, unless there already is one
+    ///
+    /// Names follow the same limits as keys, and can't be empty.
+    ///
+    pub fn create_namespace(&mut self, name: &ByteStr) -> io::Result<()> {
+        self.ensure_writable()?;
+        if self.namespaces.id(name).is_some() {
+            return Ok(());
+        }
+
+        self.check_key(name)?;
+        if name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "namespace names can't be empty",
+            ));
+        }
+
+        let id = self.namespaces.next_id;
+        if id >= LOCAL {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "no namespace ids left, compact the store to free those of dropped namespaces",
+            ));
+        }
+        self.write_catalog(name, &id.to_le_bytes())
+    }
+
+    ///
+    /// Drops the namespace `name` along with all of its keys
+    ///
+    /// The space its records take is reclaimed by the next `KV::compact`.
+    ///
+    pub fn drop_namespace(&mut self, name: &ByteStr) -> io::Result<()> {
+        self.ensure_writable()?;
+        if self.namespaces.id(name).is_none() {
+            return Err(no_namespace(name));
+        }
+
+        self.write_catalog(name, b"")
+    }
+
+    /// Names of the namespaces besides the default one, sorted
+    pub fn namespaces(&self) -> Vec<ByteString> {
+        let mut names: Vec<ByteString> = self
+            .namespaces
+            .by_id
+            .values()
+            .map(|entry| entry.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The namespace `name`, which must have been created first
+    pub fn namespace(&mut self, name: &ByteStr) -> io::Result<Namespace<'_>> {
+        let id = self.namespaces.id(name).ok_or_else(|| no_namespace(name))?;
+        Ok(Namespace { store: self, id })
+    }
+
+    ///
+    /// Looks up `key` among the local keys, which belong to this data file alone
+    ///
+    /// Local keys are kept apart from every namespace, and replication followers leave them
+    /// out, so they hold what only makes sense for this file, such as positions in it.
+    ///
+    pub fn get_local(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let position = match self.namespaces.local.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let kv = self.get_at(position)?;
+        Ok(Some(kv.value))
+    }
+
+    /// Sets the local key `key` to `value`, see `KV::get_local`
+    pub fn insert_local(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let position = self.write_value(LOCAL, key, value)?;
+        self.namespaces.local.insert(key.to_vec(), position);
+        Ok(())
+    }
+
+    ///
+    /// Like `KV::merge`, setting the local key `local` to `value` with the same write
+    ///
+    /// The local key is written first, so that the operand is never on disk without it.
+    ///
+    pub(super) fn merge_after_local(
+        &mut self,
+        local: &ByteStr,
+        value: &ByteStr,
+        key: &ByteStr,
+        operand: &ByteStr,
+    ) -> io::Result<()> {
+        self.check_key(local)?;
+        let (format, cipher) = (self.format, self.cipher.as_ref());
+        let record = Self::encode_record_in(local, value, 0, LOCAL, format, cipher)?;
+        let position = self.merge_after(Some(&record), key, operand)?;
+        self.namespaces.local.insert(local.to_vec(), position);
+        self.stats.records += 1;
+        self.stats.writes += 1;
+        Ok(())
+    }
+
+    /// Where the record holding the local key `key` ends, if it's set
+    pub(super) fn local_end(&mut self, key: &ByteStr) -> io::Result<Option<u64>> {
+        let Some(&position) = self.namespaces.local.get(key) else {
+            return Ok(None);
+        };
+
+        self.f.seek(SeekFrom::Start(position))?;
+        let raw = KV::read_raw_record(&mut BufReader::new(&mut self.f))?;
+        Ok(Some(position + raw.len() as u64))
+    }
+
+    /// Writes a catalog record and applies it
+    fn write_catalog(&mut self, name: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let (format, cipher) = (self.format, self.cipher.as_ref());
+        let record = Self::encode_record_in(name, value, 0, CATALOG, format, cipher)?;
+        let position = self.append(&record)?;
+        self.stats.records += 1;
+        self.stats.writes += 1;
+
+        self.namespaces.name(position, name.to_vec(), value)
+    }
+
+    ///
+    /// Applies a catalog record received from a replication leader
+    ///
+    /// Namespaces keep the ids they have on the leader, so records received later can be
+    /// told apart by id alone. Fails if the store gave the id to another namespace.
+    ///
+    pub(super) fn replicate_catalog(&mut self, name: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        if value.is_empty() {
+            return match self.namespaces.id(name) {
+                Some(_) => self.write_catalog(name, value),
+                None => Ok(()),
+            };
+        }
+
+        let id = (&value[..]).read_u32::<LittleEndian>()?;
+        if let Some(entry) = self.namespaces.by_id.get(&id) {
+            if entry.name == name {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "namespace id {id} is taken by {:?}",
+                    String::from_utf8_lossy(&entry.name)
+                ),
+            ));
+        }
+
+        self.write_catalog(name, value)
+    }
+
+    ///
+    /// Stores `value` for `key` in the namespace with id `namespace`, and indexes it
+    ///
+    /// Records of namespaces that don't exist, e.g. because they were dropped, are ignored.
+    ///
+    pub(super) fn insert_in(
+        &mut self,
+        namespace: u32,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> io::Result<()> {
+        if namespace == 0 {
+            return self.insert(key, value);
+        }
+        if !self.namespaces.by_id.contains_key(&namespace) {
+            return Ok(());
+        }
+
+        let position = self.write_value(namespace, key, value)?;
+        let entry = self.namespaces.entry_mut(namespace)?;
+        entry.index.insert(key.to_vec(), position);
+        Ok(())
+    }
+
+    ///
+    /// Rewrites the data file without the superseded records of the namespace with id `id`
+    ///
+    /// Everything else is copied as it is, including the superseded records of other
+    /// namespaces, and positions are moved back by what was left out before them. The
+    /// tombstones of the namespace are left out too, like `KV::compact` does. Sync markers
+    /// are written anew for the positions records end up at.
+    ///
+    fn compact_namespace(&mut self, id: u32) -> io::Result<()> {
+        self.ensure_writable()?;
+        let (format, cipher) = (self.format, self.cipher.clone());
+        let old_len = self.seek_to_end()?;
+
+        // Ranges of the file holding the live records of the namespace, large values
+        // together with their chunks
+        let mut live = Vec::new();
+        let mut tombstones = Vec::new();
+        {
+            let mut reader = BufReader::new(&mut self.f);
+            for (key, &position) in &self.namespaces.entry(id).index {
+                reader.seek(SeekFrom::Start(position))?;
+                let raw = KV::read_raw_record(&mut reader)?;
+                let header = Header::read(&mut &raw[..])?;
+                if header.val_len == 0 && header.flags & FLAG_MANIFEST == 0 {
+                    tombstones.push(key.clone());
+                    continue;
+                }
+
+                let mut start = position;
+                if header.flags & FLAG_MANIFEST != 0 {
+                    let (_, kv) = KV::process_record(&mut &raw[..], format, cipher.as_ref())?;
+                    start -= Manifest::decode(&kv.value)?.distance;
+                }
+                live.push((start, position + raw.len() as u64));
+            }
+        }
+        live.sort();
+
+        // The records of the file in order, with large values together with their chunks
+        let mut groups: Vec<Group> = Vec::new();
+        let mut chunks: Option<Group> = None;
+        let records_end = {
+            let mut reader = BufReader::new(&mut self.f);
+            let mut position = format.data_start();
+            reader.seek(SeekFrom::Start(position))?;
+            let mut live = live.iter().peekable();
+
+            loop {
+                let raw = match KV::read_raw_record(&mut reader) {
+                    Ok(raw) => raw,
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                };
+                let header = Header::read(&mut &raw[..])?;
+                let end = position + raw.len() as u64;
+
+                while live
+                    .next_if(|(_, live_end)| *live_end <= position)
+                    .is_some()
+                {}
+                let is_live = live.peek().is_some_and(|(start, _)| *start <= position);
+                let kept = header.namespace != id || is_live;
+
+                if header.flags & FLAG_SYNC != 0 {
+                    // Markers between chunks stay where they are, the others are written anew
+                    if let Some(group) = &mut chunks {
+                        group.markers.push((position, raw.len() as u64));
+                        group.end = end;
+                    }
+                } else {
+                    let group = chunks.get_or_insert_with(|| Group::new(position));
+                    group.end = end;
+                    group.kept |= kept;
+                    group.records += 1;
+                    if header.flags & FLAG_CHUNK == 0 {
+                        // The manifest decides for its chunks
+                        group.kept = kept;
+                        groups.extend(chunks.take());
+                    }
+                }
+                position = end;
+            }
+            // Chunks whose manifest was never written are copied as they are
+            groups.extend(chunks.take());
+            position
+        };
+
+        let (relocated, records, written) = self.write_compacted(|store, f| {
+            // Where groups of records were moved, in file order
+            let mut relocated = Vec::with_capacity(groups.len() + 1);
+            let mut records = 0;
+            let mut written = format.data_start();
+            let mut reader = BufReader::new(&mut store.f);
+            let mut writer = BufWriter::new(f);
+            format.rewritten().write(&mut writer)?;
+
+            for group in &groups {
+                if !group.kept {
+                    records += group.records;
+                    continue;
+                }
+
+                let len = group.end - group.start;
+                if let Some(marker) = sync_marker::before(format, written, len)? {
+                    writer.write_all(&marker)?;
+                    written += marker.len() as u64;
+                }
+                relocated.push((group.start, written));
+
+                reader.seek(SeekFrom::Start(group.start))?;
+                let mut copied = group.start;
+                for &(marker, marker_len) in &group.markers {
+                    copy_exactly(&mut reader, &mut writer, marker - copied)?;
+                    let position = written + (marker - group.start);
+                    writer.write_all(&sync_marker::encode(format, position)?)?;
+                    reader.seek_relative(marker_len as i64)?;
+                    copied = marker + marker_len;
+                }
+                copy_exactly(&mut reader, &mut writer, group.end - copied)?;
+                written += len;
+            }
+
+            // Whatever follows the last complete record, such as one still being written
+            relocated.push((records_end, written));
+            reader.seek(SeekFrom::Start(records_end))?;
+            written += io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            Ok((relocated, records, written))
+        })?;
+
+        let moved = |position: u64| {
+            let after = relocated.partition_point(|(start, _)| *start <= position);
+            match after {
+                0 => position,
+                _ => {
+                    let (start, moved_to) = relocated[after - 1];
+                    moved_to + (position - start)
+                }
+            }
+        };
+        for position in self.index.values_mut() {
+            *position = moved(*position);
+        }
+        for position in self.operands.values_mut().flatten() {
+            *position = moved(*position);
+        }
+        let index = &mut self.namespaces.entry_mut(id)?.index;
+        for key in tombstones {
+            index.remove(&key);
+        }
+        self.namespaces.remap(moved);
+        self.loaded = moved(self.loaded);
+
+        self.stats.records -= records.min(self.stats.records);
+        self.stats.compactions += 1;
+        self.stats.bytes_reclaimed += old_len.saturating_sub(written);
+
+        Ok(())
+    }
+}
+
+/// Records copied together by `compact_namespace`: a value, or a large one with its chunks
+struct Group {
+    start: u64,
+    end: u64,
+    kept: bool,
+    /// Records besides sync markers
+    records: u64,
+    /// Positions and lengths of the sync markers between the chunks
+    markers: Vec<(u64, u64)>,
+}
+
+impl Group {
+    fn new(start: u64) -> Self {
+        Self {
+            start,
+            end: start,
+            kept: false,
+            records: 0,
+            markers: Vec::new(),
+        }
+    }
+}
+
+fn copy_exactly<R: io::Read, W: Write>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<()> {
+    if io::copy(&mut io::Read::take(reader, len), writer)? != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn no_namespace(name: &ByteStr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no namespace named {:?}", String::from_utf8_lossy(name)),
+    )
+}
+
+impl Namespace<'_> {
+    pub fn name(&self) -> &ByteStr {
+        &self.store.namespaces.entry(self.id).name
+    }
+
+    /// Mapping between the keys of the namespace and file locations
+    pub fn index(&self) -> &HashMap<ByteString, u64> {
+        &self.store.namespaces.entry(self.id).index
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let position = match self.index().get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let kv = self.store.get_at(position)?;
+        Ok(Some(kv.value))
+    }
+
+    /// Like `KV::scan`, within the namespace
+    pub fn scan(&mut self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let matches = store::matching(self.index(), prefix);
+        self.store.read_live(self.id, matches)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.store.insert_in(self.id, key, value)
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+        self.insert(key, b"")
+    }
+
+    ///
+    /// Drops the superseded records of this namespace from the data file
+    ///
+    /// Other namespaces are left as they are, so compacting a small namespace doesn't
+    /// rewrite the index of a large one. The whole file is still copied though.
+    ///
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.store.compact_namespace(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::{Cipher, KEY_LEN},
+        store::Limits,
+    };
+
+    use super::*;
+
+    #[test]
+    fn namespaces_keep_their_keys_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 4,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.insert(b"fruit", b"apple").unwrap();
+        store.create_namespace(b"colors").unwrap();
+        store.create_namespace(b"sizes").unwrap();
+        {
+            let mut colors = store.namespace(b"colors").unwrap();
+            colors.insert(b"fruit", b"red").unwrap();
+            colors
+                .insert(b"fruit", b"green and long enough to be chunked")
+                .unwrap();
+            colors.insert(b"sky", b"blue").unwrap();
+        }
+        for n in 0..10u8 {
+            let mut sizes = store.namespace(b"sizes").unwrap();
+            sizes.insert(b"fruit", &[n]).unwrap();
+        }
+        store.insert(b"sky", b"up").unwrap();
+
+        let check = |store: &mut KV| {
+            assert_eq!(store.get(b"fruit").unwrap(), Some(b"apple".to_vec()));
+            assert_eq!(store.get(b"sky").unwrap(), Some(b"up".to_vec()));
+            let mut colors = store.namespace(b"colors").unwrap();
+            assert_eq!(
+                colors.get(b"fruit").unwrap(),
+                Some(b"green and long enough to be chunked".to_vec())
+            );
+            assert_eq!(colors.scan(b"s").unwrap()[0].value, b"blue");
+            let mut sizes = store.namespace(b"sizes").unwrap();
+            assert_eq!(sizes.get(b"fruit").unwrap(), Some(vec![9]));
+            assert_eq!(sizes.get(b"sky").unwrap(), None);
+        };
+        check(&mut store);
+
+        // Compacting a namespace leaves the records of the others where they were
+        let len = store.seek_to_end().unwrap();
+        store.namespace(b"sizes").unwrap().compact().unwrap();
+        assert!(store.seek_to_end().unwrap() < len);
+        check(&mut store);
+
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.namespaces(), [b"colors".to_vec(), b"sizes".to_vec()]);
+        check(&mut store);
+
+        // Dropped namespaces stay dropped, and their ids aren't reused for new ones
+        store.drop_namespace(b"colors").unwrap();
+        store.create_namespace(b"shapes").unwrap();
+        assert!(store.namespace(b"colors").is_err());
+        assert_eq!(
+            store.namespace(b"shapes").unwrap().get(b"sky").unwrap(),
+            None
+        );
+
+        store.compact().unwrap();
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.namespaces(), [b"shapes".to_vec(), b"sizes".to_vec()]);
+        assert_eq!(store.get(b"sky").unwrap(), Some(b"up".to_vec()));
+        let mut sizes = store.namespace(b"sizes").unwrap();
+        assert_eq!(sizes.get(b"fruit").unwrap(), Some(vec![9]));
+
+        // Records move, and sync markers are written anew for where they end up, between
+        // chunks too
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        let large: ByteString = (0..150_000u32).map(|n| n as u8).collect();
+        for n in 0..200u32 {
+            let mut sizes = store.namespace(b"sizes").unwrap();
+            sizes.insert(&n.to_le_bytes(), &[n as u8; 1000]).unwrap();
+            if n == 100 {
+                store.insert(b"large", &large).unwrap();
+            }
+        }
+        let mut sizes = store.namespace(b"sizes").unwrap();
+        for n in 0..200u32 {
+            sizes.delete(&n.to_le_bytes()).unwrap();
+        }
+        sizes.compact().unwrap();
+
+        let markers = |store: &mut KV| {
+            let format = store.format;
+            let mut reader = BufReader::new(&mut store.f);
+            let mut position = format.data_start();
+            reader.seek(SeekFrom::Start(position)).unwrap();
+            let mut markers = 0;
+            while let Ok(raw) = KV::read_raw_record(&mut reader) {
+                if Header::read(&mut &raw[..]).unwrap().flags & FLAG_SYNC != 0 {
+                    let mut f = reader.get_mut();
+                    assert_eq!(
+                        sync_marker::find(&mut f, position, format).unwrap(),
+                        Some(position)
+                    );
+                    markers += 1;
+                }
+                position += raw.len() as u64;
+                reader.seek(SeekFrom::Start(position)).unwrap();
+            }
+            markers
+        };
+        assert!(markers(&mut store) >= 2);
+        assert_eq!(store.get(b"large").unwrap(), Some(large.clone()));
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"large").unwrap(), Some(large));
+        let mut sizes = store.namespace(b"sizes").unwrap();
+        assert_eq!(sizes.get(b"fruit").unwrap(), Some(vec![9]));
+        assert!(sizes.index().len() == 1);
+
+        // Failing part way leaves no file behind to stop the next compaction
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.set_cipher(Cipher::new(&[1; KEY_LEN]));
+        let mut sizes = store.namespace(b"sizes").unwrap();
+        sizes.insert(b"secret", &[4; 3000]).unwrap();
+        sizes.store.set_cipher(Cipher::new(&[2; KEY_LEN]));
+        assert!(sizes.compact().is_err());
+        let mut tmp_path = path.into_os_string();
+        tmp_path.push(".compact");
+        assert!(!std::path::Path::new(&tmp_path).exists());
+        sizes.store.set_cipher(Cipher::new(&[1; KEY_LEN]));
+        sizes.compact().unwrap();
+        assert_eq!(sizes.get(b"secret").unwrap(), Some(vec![4; 3000]));
+    }
+}
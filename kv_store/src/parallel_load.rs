@@ -0,0 +1,405 @@
+//!
+//! Loading large logs on several threads
+//!
+//! A record doesn't say where the one before it starts, so there's no knowing where records
+//! begin in the middle of the log, except at sync markers. `load` cuts what's left to read
+//! into segments of about the same length, and a thread checks the records of each, from
+//! the first marker in it on, until one starts past the segment. Only where they start and
+//! end is kept, so memory doesn't grow with the number of records. This thread then indexes
+//! the records in log order, reading them again without checking them.
+//!
+//! Markers are only taken for what they are if they match their position, so bytes in the
+//! value of another record, such as a copy of a store, are rarely taken for one. Still,
+//! segments are put together in order, and one is only taken as it is if it starts right
+//! where the segment before it ended. Otherwise, it's checked again from there. The index
+//! ends up as if the log was read from start to end either way.
+//!
+//! Only checking records is spread over threads. Indexing them stays on this thread, since
+//! what a record means depends on the ones before it: the catalog names namespaces, merge
+//! operands pile up on the value before them and chunks wait for their manifest. Reading
+//! them again is cheaper than checking them, as checksums are left out, but it's still a
+//! pass over the whole log on a single thread, which bounds the speedup however many
+//! threads check. `kv_mem FILE bench-loads ROUNDS` measures it on a given store.
+//!
+//! Files without markers are read on a single thread.
+//!
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    num::NonZero,
+    ops::Range,
+    panic, thread,
+};
+
+use crate::{
+    format::Format,
+    record_ref::RecordBlocks,
+    store::{ByteString, KV},
+    sync_marker,
+};
+
+/// Logs shorter than this per thread are read by a single one
+const MIN_SEGMENT_LEN: u64 = 8 << 20;
+
+/// Number of segments to split `len` bytes of log into, 1 to read them on this thread
+pub(super) fn segments(format: Format, len: u64) -> usize {
+    if !cfg!(any(unix, windows)) || !format.sync_markers {
+        return 1;
+    }
+
+    let threads = thread::available_parallelism().map_or(1, NonZero::get);
+    let segments = usize::try_from(len / MIN_SEGMENT_LEN).unwrap_or(usize::MAX);
+    threads.min(segments).max(1)
+}
+
+///
+/// Reads a file from a position of its own, leaving its cursor alone for threads to share it
+///
+/// The file seems to end at `end`.
+///
+struct ReadAt<'a> {
+    f: &'a File,
+    position: u64,
+    end: u64,
+}
+
+impl<'a> ReadAt<'a> {
+    fn new(f: &'a File, position: u64) -> Self {
+        Self {
+            f,
+            position,
+            end: u64::MAX,
+        }
+    }
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = usize::try_from(self.end.saturating_sub(self.position)).unwrap_or(usize::MAX);
+        let len = left.min(buf.len());
+        let buf = &mut buf[..len];
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.f, buf, self.position)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.f, buf, self.position)?;
+        #[cfg(not(any(unix, windows)))]
+        let read = {
+            let _ = buf;
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ReadAt<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.f.metadata()?.len().checked_add_signed(offset),
+        }
+        .ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// What checking the records of a segment found
+#[derive(Default)]
+struct Segment {
+    // `None` if no sync marker was found in it
+    start: Option<u64>,
+    // Where the records checked end
+    end: u64,
+    // Why the records stopped before the end of the segment, unless the log ended
+    error: Option<io::Error>,
+    // Damage passed over, see `RecordBlocks::resyncing`
+    skipped: Vec<Range<u64>>,
+    skipped_bytes: u64,
+    checksum_failures: u64,
+}
+
+///
+/// Checks the records starting from `start` on, up to the first starting at `until` or later
+///
+/// Damaged records are passed over if `resync` is set, see `KV::recover`.
+///
+fn check_segment(f: &File, start: u64, until: u64, format: Format, resync: bool) -> Segment {
+    let mut segment = Segment {
+        start: Some(start),
+        end: start,
+        ..Segment::default()
+    };
+    // Checksums don't need the key of encrypted records
+    let reader = ReadAt::new(f, start);
+    let mut records = match RecordBlocks::starting_at(reader, start, format, None) {
+        Ok(records) if resync => records.resyncing(),
+        Ok(records) => records,
+        Err(err) => {
+            segment.error = Some(err);
+            return segment;
+        }
+    };
+
+    while records.position() < until {
+        match records.skip() {
+            None => break,
+            Some(Err(err)) => {
+                segment.error = Some(err);
+                break;
+            }
+            Some(Ok(_)) => {}
+        }
+    }
+
+    segment.end = records.position();
+    segment.skipped = records.skipped;
+    segment.skipped_bytes = records.skipped_bytes;
+    segment.checksum_failures = records.checksum_failures;
+    segment
+}
+
+impl KV {
+    ///
+    /// Indexes the records following `loaded`, checking them on as many threads as
+    /// `segments`
+    ///
+    /// Keys and positions end up the same as if the records were read one after another,
+    /// and so does the position loading stops at, on failure too. Returns the spans passed
+    /// over if `resync` is set, see `KV::recover`.
+    ///
+    pub(super) fn load_in_segments(
+        &mut self,
+        segments: usize,
+        resync: bool,
+        changed: &mut HashSet<ByteString>,
+    ) -> io::Result<Vec<Range<u64>>> {
+        let (format, cipher, loaded) = (self.format, self.cipher.clone(), self.loaded);
+        let (f, mut indexes) = self.split_for_load(changed);
+        let f = &*f;
+
+        // Segment i goes from bounds[i] to bounds[i + 1]
+        let len = f.metadata()?.len().max(loaded) - loaded;
+        let bounds: Vec<u64> = (0..=segments as u128)
+            .map(|i| loaded + (len as u128 * i / segments as u128) as u64)
+            .collect();
+
+        let found: Vec<Segment> = thread::scope(|scope| {
+            let threads: Vec<_> = bounds
+                .windows(2)
+                .map(|bounds| {
+                    let (from, until) = (bounds[0], bounds[1]);
+                    scope.spawn(move || {
+                        let start = match from == loaded {
+                            true => Some(from),
+                            // Not finding one leaves it to be read from the segment before
+                            false => sync_marker::find(&mut ReadAt::new(f, from), from, format)
+                                .ok()
+                                .flatten()
+                                .filter(|&start| start < until),
+                        };
+                        match start {
+                            Some(start) => check_segment(f, start, until, format, resync),
+                            None => Segment::default(),
+                        }
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .collect()
+        });
+
+        let mut position = loaded;
+        let mut skipped = Vec::new();
+        let (mut skipped_bytes, mut checksum_failures) = (0, 0);
+        let mut result = Ok(());
+        for (segment, &until) in found.into_iter().zip(&bounds[1..]) {
+            // A record of an earlier segment reached past this one
+            if position >= until {
+                continue;
+            }
+            let segment = match segment.start == Some(position) {
+                true => segment,
+                false => check_segment(f, position, until, format, resync),
+            };
+
+            position = segment.end;
+            skipped.extend(segment.skipped);
+            skipped_bytes += segment.skipped_bytes;
+            checksum_failures += segment.checksum_failures;
+            if let Some(err) = segment.error {
+                result = Err(err);
+                break;
+            }
+            // The log ends before the segment, with a record cut short
+            if position < until {
+                break;
+            }
+        }
+
+        // The records checked are indexed in order, keys borrowed from the blocks read
+        let reader = ReadAt {
+            end: position,
+            ..ReadAt::new(f, loaded)
+        };
+        let mut records = RecordBlocks::starting_at(reader, loaded, format, cipher)?
+            .checked_already(skipped.clone());
+        while let Some(record) = records.next() {
+            let indexed = record
+                .and_then(|(position, header, record)| indexes.add(position, &header, record));
+            if let Err(err) = indexed {
+                result = Err(err);
+                break;
+            }
+        }
+
+        self.loaded = records.position();
+        self.stats.skipped_bytes += skipped_bytes;
+        self.stats.checksum_failures += checksum_failures;
+        result.map(|()| skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{Cipher, KEY_LEN},
+        merge::U64Add,
+        store::Limits,
+    };
+    use std::collections::HashMap;
+
+    /// Everything `load` indexes, to compare stores loaded both ways
+    fn loaded(store: &mut KV) -> impl PartialEq + std::fmt::Debug + use<> {
+        let namespaces: HashMap<ByteString, HashMap<ByteString, u64>> = store
+            .namespaces()
+            .into_iter()
+            .map(|name| {
+                let index = store.namespace(&name).unwrap().index().clone();
+                (name, index)
+            })
+            .collect();
+        (
+            store.index.clone(),
+            store.operands.clone(),
+            namespaces,
+            store.stats.records,
+            store.stats.skipped_bytes,
+            store.loaded,
+        )
+    }
+
+    #[test]
+    fn segments_index_like_a_single_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 200,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.set_merge_operator(U64Add);
+        store.create_namespace(b"other").unwrap();
+        for i in 0..300u32 {
+            let value = vec![i as u8 | 1; (i as usize * 13) % 500 + 1];
+            store.insert(&(i % 120).to_le_bytes(), &value).unwrap();
+            store.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+            if i % 7 == 0 {
+                store.delete(&(i % 50).to_le_bytes()).unwrap();
+                store
+                    .namespace(b"other")
+                    .unwrap()
+                    .insert(b"key", &value)
+                    .unwrap();
+            }
+            // Copies of the log so far hold records for the threads to stumble on
+            if i % 100 == 99 {
+                let log = std::fs::read(&path).unwrap();
+                store.insert(b"copy", &log).unwrap();
+            }
+        }
+        store.set_cipher(Cipher::new(&[7; KEY_LEN]));
+        store.insert(b"secret", b"value").unwrap();
+        drop(store);
+
+        let open = |path: &std::path::Path| {
+            let mut store = KV::open_read_only(path).unwrap();
+            store.set_merge_operator(U64Add);
+            store.set_cipher(Cipher::new(&[7; KEY_LEN]));
+            store
+        };
+        let mut expected = open(&path);
+        expected.load().unwrap();
+        let expected = loaded(&mut expected);
+
+        for segments in [2, 3, 7, 16, 100] {
+            let mut store = open(&path);
+            store
+                .load_in_segments(segments, true, &mut HashSet::new())
+                .unwrap();
+            assert_eq!(loaded(&mut store), expected, "{segments} segments");
+        }
+
+        // Loading stops after a record cut short
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(len as usize - 3);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut serial = open(&path);
+        serial.load().unwrap();
+        let expected = loaded(&mut serial);
+        for segments in [2, 5, 64] {
+            let mut store = open(&path);
+            store
+                .load_in_segments(segments, false, &mut HashSet::new())
+                .unwrap();
+            assert_eq!(loaded(&mut store), expected, "{segments} segments");
+        }
+
+        // A damaged record fails loading where it starts, unless recovering, which passes
+        // over it up to the next sync marker
+        bytes[len as usize / 2] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut serial = open(&path);
+        assert!(serial.load().is_err());
+        let expected = loaded(&mut serial);
+        for segments in [2, 5, 64] {
+            let mut store = open(&path);
+            assert!(
+                store
+                    .load_in_segments(segments, false, &mut HashSet::new())
+                    .is_err()
+            );
+            assert_eq!(loaded(&mut store), expected, "{segments} segments");
+        }
+
+        let mut serial = open(&path);
+        let skipped = serial.recover().unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].contains(&(len / 2)));
+        let expected = loaded(&mut serial);
+        for segments in [2, 5, 64] {
+            let mut store = open(&path);
+            let spans = store
+                .load_in_segments(segments, true, &mut HashSet::new())
+                .unwrap();
+            assert_eq!(spans, skipped, "{segments} segments");
+            assert_eq!(loaded(&mut store), expected, "{segments} segments");
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//!
+//! Parity for archival stores, to repair damaged blocks instead of only detecting them
+//!
+//! A parity bit tells that something changed, not what. Once a checksum points out which
+//! block of a group is damaged though, the XOR of all blocks of the group is enough to
+//! rebuild it from the others. The parity of a data file is kept in a sidecar file next to
+//! it, named like the data file with `.parity` added:
+//!
+//! ```text
+//! [magic: 8 bytes][block size: u32][group size: u32][covered length: u64]
+//! then for every group: [CRC32C of each block: u32 × group size][XOR of the blocks]
+//! ```
+//!
+//! The last block is padded with zeroes, and missing blocks of the last group count as all
+//! zeroes. Parity only covers the file as it was when it was written, so it's meant for
+//! stores that are done being written to. Anything appended afterwards isn't covered.
+//!
+
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::format::Checksum;
+
+const MAGIC: &[u8; 8] = b"KVPARITY";
+const BLOCK_SIZE: usize = 4096;
+/// Blocks sharing a parity block. Parity costs 1/16th of the file, and a group can recover
+/// from a single damaged block
+const GROUP_SIZE: usize = 16;
+const GROUP_LEN: usize = BLOCK_SIZE * GROUP_SIZE;
+
+/// Where the parity of the data file at `path` is kept
+pub fn parity_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".parity");
+    PathBuf::from(name)
+}
+
+/// Reads up to a group of blocks, padding what's missing with zeroes
+fn read_group<R: Read>(f: &mut R, group: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < group.len() {
+        match f.read(&mut group[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    group[read..].fill(0);
+    Ok(read)
+}
+
+/// The XOR of all blocks of a group
+fn parity_of(group: &[u8]) -> Vec<u8> {
+    let mut parity = vec![0; BLOCK_SIZE];
+    for block in group.chunks(BLOCK_SIZE) {
+        for (p, byte) in parity.iter_mut().zip(block) {
+            *p ^= byte;
+        }
+    }
+    parity
+}
+
+///
+/// Writes the parity of the data file at `path` into its sidecar file
+///
+/// Takes a shared lock, so the store can't be written meanwhile. Returns the number of
+/// bytes covered.
+///
+pub fn write_parity(path: &Path) -> io::Result<u64> {
+    let f = File::open(path)?;
+    f.try_lock_shared().map_err(io::Error::from)?;
+    let len = f.metadata()?.len();
+    let mut f = BufReader::new(f);
+
+    let tmp_path = parity_path(path).with_extension("parity.tmp");
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(MAGIC)?;
+    out.write_u32::<LittleEndian>(BLOCK_SIZE as u32)?;
+    out.write_u32::<LittleEndian>(GROUP_SIZE as u32)?;
+    out.write_u64::<LittleEndian>(len)?;
+
+    let mut group = vec![0; GROUP_LEN];
+    while read_group(&mut f, &mut group)? > 0 {
+        for block in group.chunks(BLOCK_SIZE) {
+            out.write_u32::<LittleEndian>(Checksum::Crc32c.compute(block))?;
+        }
+        out.write_all(&parity_of(&group))?;
+    }
+
+    let out = out.into_inner().map_err(|err| err.into_error())?;
+    out.sync_all()?;
+    std::fs::rename(&tmp_path, parity_path(path))?;
+
+    Ok(len)
+}
+
+///
+/// Rebuilds the blocks of the data file at `path` that don't match their checksum
+///
+/// Takes an exclusive lock, so the store must not be open. Fails without writing anything
+/// to a group if it has more than one damaged block. Returns the number of blocks
+/// repaired.
+///
+pub fn repair(path: &Path) -> io::Result<u64> {
+    let mut parity = BufReader::new(File::open(parity_path(path))?);
+    let mut magic = [0; 8];
+    parity.read_exact(&mut magic)?;
+    let block_size = parity.read_u32::<LittleEndian>()? as usize;
+    let group_size = parity.read_u32::<LittleEndian>()? as usize;
+    let covered = parity.read_u64::<LittleEndian>()?;
+
+    if &magic != MAGIC || block_size != BLOCK_SIZE || group_size != GROUP_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a parity file, or one using a different layout",
+        ));
+    }
+
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    f.try_lock().map_err(io::Error::from)?;
+    if f.metadata()?.len() < covered {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is shorter than when its parity was written",
+                path.display()
+            ),
+        ));
+    }
+
+    let mut repaired = 0;
+    let mut group = vec![0; GROUP_LEN];
+    let mut expected = [0; GROUP_SIZE];
+    let mut stored_parity = vec![0; BLOCK_SIZE];
+
+    for start in (0..covered).step_by(GROUP_LEN) {
+        f.seek(SeekFrom::Start(start))?;
+        let len = (covered - start).min(GROUP_LEN as u64) as usize;
+        read_group(&mut (&mut f).take(len as u64), &mut group)?;
+        parity.read_u32_into::<LittleEndian>(&mut expected)?;
+        parity.read_exact(&mut stored_parity)?;
+
+        let damaged: Vec<usize> = group
+            .chunks(BLOCK_SIZE)
+            .zip(expected)
+            .enumerate()
+            .filter(|(_, (block, checksum))| Checksum::Crc32c.compute(block) != *checksum)
+            .map(|(index, _)| index)
+            .collect();
+
+        let index = match damaged[..] {
+            [] => continue,
+            [index] => index,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} blocks damaged in the group at offset {start}, only one can be repaired",
+                        damaged.len()
+                    ),
+                ));
+            }
+        };
+
+        // XORing the parity with every other block leaves the damaged block as it was
+        let block = index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE;
+        group[block.clone()].fill(0);
+        let mut rebuilt = parity_of(&group);
+        for (byte, p) in rebuilt.iter_mut().zip(&stored_parity) {
+            *byte ^= p;
+        }
+
+        if Checksum::Crc32c.compute(&rebuilt) != expected[index] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("parity of the group at offset {start} is damaged too"),
+            ));
+        }
+
+        // Only the part of the block inside the file is written back, not the padding
+        let block_len = len.saturating_sub(block.start).min(BLOCK_SIZE);
+        f.seek(SeekFrom::Start(start + block.start as u64))?;
+        f.write_all(&rebuilt[..block_len])?;
+        repaired += 1;
+    }
+
+    f.sync_all()?;
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_one_damaged_block_per_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let data: Vec<u8> = (0..GROUP_LEN * 2 + 1000).map(|i| (i * 7) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        write_parity(&path).unwrap();
+
+        let mut damaged = data.clone();
+        damaged[10] ^= 0xff;
+        damaged[GROUP_LEN * 2 + 999] ^= 0x01;
+        std::fs::write(&path, &damaged).unwrap();
+        assert_eq!(repair(&path).unwrap(), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        damaged = data.clone();
+        damaged[10] ^= 0xff;
+        damaged[BLOCK_SIZE + 10] ^= 0xff;
+        std::fs::write(&path, &damaged).unwrap();
+        assert!(repair(&path).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+//!
+//! Zero-copy record parsing
+//!
+//! `KV::process_record` reads every record into a buffer of its own. Where the bytes are
+//! in memory already, such as in a memory map or a block read from the file, a record can
+//! be checked where it is instead, and its key and value borrowed from there as a
+//! `RecordRef`. `RecordBlocks` reads the log in large blocks to parse it this way, which is
+//! how `load`, `find` and `verify` go through the whole log without allocating for every
+//! record.
+//!
+//! Encrypted records are the exception: they're decrypted into a buffer kept for the
+//! purpose, which their key and value borrow from instead.
+//!
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use crate::{
+    crypto::Cipher,
+    format::Format,
+    store::{
+        ByteStr, ByteString, FLAG_ENCRYPTED, HEADER_LEN, Header, KV, KeyValuePair, RecordError,
+    },
+    sync_marker,
+};
+
+/// Bytes read from the file at once, unless a record needs more. The buffer starts out
+/// smaller and doubles up to it, so that loading a few new records stays cheap
+const BLOCK_LEN: usize = 1 << 20;
+const FIRST_BLOCK_LEN: usize = 8 << 10;
+
+/// A record parsed in place, borrowing its key and value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RecordRef<'a> {
+    pub key: &'a ByteStr,
+    pub value: &'a ByteStr,
+}
+
+impl From<RecordRef<'_>> for KeyValuePair {
+    fn from(record: RecordRef<'_>) -> Self {
+        Self {
+            key: record.key.to_vec(),
+            value: record.value.to_vec(),
+        }
+    }
+}
+
+/// Checks `data`, the bytes stored after `header`, against the checksum of the record
+pub(super) fn check(header: &Header, data: &ByteStr, format: Format) -> io::Result<()> {
+    let found = format.record_checksum(&header.lengths(), data);
+    if found != header.checksum {
+        return Err(RecordError::Corrupted {
+            expected: header.checksum,
+            found,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+impl<'a> RecordRef<'a> {
+    ///
+    /// Splits the checked `data` of a record into its key and value
+    ///
+    /// Encrypted data is decrypted into `plaintext` first, and split there.
+    ///
+    pub(super) fn decode(
+        header: &Header,
+        data: &'a ByteStr,
+        cipher: Option<&Cipher>,
+        plaintext: &'a mut ByteString,
+    ) -> io::Result<Self> {
+        let data = match header.flags & FLAG_ENCRYPTED {
+            0 => data,
+            _ => {
+                let cipher = cipher.ok_or(RecordError::MissingKey)?;
+                *plaintext = cipher.open(data, &header.lengths())?;
+                &plaintext[..]
+            }
+        };
+
+        let (key, value) = data
+            .split_at_checked(header.key_len as usize)
+            .ok_or(io::ErrorKind::InvalidData)?;
+        Ok(Self { key, value })
+    }
+}
+
+///
+/// Reads records one after another in large blocks, parsing them in place
+///
+/// Works like `Records`, returning every record, chunks and other namespaces included, with
+/// the same handling of the end of the log: a record cut short ends it cleanly. Items
+/// borrow the reader, so it's walked with `while let` rather than as an `Iterator`.
+///
+/// Once `resyncing`, damaged records are passed over up to the next sync marker, and so is
+/// a record cut short if a marker follows it. Records failing to decrypt still fail.
+///
+/// Records checked already, such as by the threads of `load_in_segments`, can be read
+/// again without checking them, see `checked_already`.
+///
+pub(super) struct RecordBlocks<R> {
+    f: R,
+    format: Format,
+    cipher: Option<Cipher>,
+    block_len: usize,
+    buf: ByteString,
+    // Part of `buf` read from the file but not parsed yet
+    start: usize,
+    end: usize,
+    plaintext: ByteString,
+    // Where the record at `start` is in the file
+    position: u64,
+    done: bool,
+    resync: bool,
+    // Whether checksums are checked, and the spans to pass over if they aren't
+    check: bool,
+    pass_over: VecDeque<Range<u64>>,
+    /// Bytes passed over to resync, and how many of the records there failed their checksum
+    pub skipped_bytes: u64,
+    pub checksum_failures: u64,
+    /// The spans of the file passed over to resync, in file order
+    pub skipped: Vec<Range<u64>>,
+}
+
+impl<R: Read + Seek> RecordBlocks<R> {
+    pub(super) fn starting_at(
+        mut f: R,
+        position: u64,
+        format: Format,
+        cipher: Option<Cipher>,
+    ) -> io::Result<Self> {
+        f.seek(SeekFrom::Start(position))?;
+        Ok(Self {
+            f,
+            format,
+            cipher,
+            block_len: BLOCK_LEN,
+            buf: ByteString::new(),
+            start: 0,
+            end: 0,
+            plaintext: ByteString::new(),
+            position,
+            done: false,
+            resync: false,
+            check: true,
+            pass_over: VecDeque::new(),
+            skipped_bytes: 0,
+            checksum_failures: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Makes damaged records be passed over instead of ending the log, see `sync_marker`
+    pub(super) fn resyncing(mut self) -> Self {
+        self.resync = true;
+        self
+    }
+
+    ///
+    /// Makes records be read without checking their checksums, passing over `skipped`
+    ///
+    /// For records checked already, which went on past the spans in `skipped` after
+    /// resyncing.
+    ///
+    pub(super) fn checked_already(mut self, skipped: Vec<Range<u64>>) -> Self {
+        self.check = false;
+        self.pass_over = skipped.into();
+        self
+    }
+
+    /// Position right after the last record read, where the next one would start
+    pub(super) fn position(&self) -> u64 {
+        self.position
+    }
+
+    ///
+    /// Reads until at least `len` bytes are waiting to be parsed, or the file ends
+    ///
+    /// Returns whether there are enough.
+    ///
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        // Whatever is left is moved to the front, making room behind it
+        if self.start + len > self.buf.len() {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        while self.end - self.start < len {
+            // Grown as bytes come in, so a damaged length can't make it allocate much more
+            // than the file holds
+            if self.end == self.buf.len() {
+                let min = FIRST_BLOCK_LEN.min(self.block_len);
+                let max = len.max(self.block_len);
+                self.buf.resize((self.buf.len() * 2).clamp(min, max), 0);
+            }
+            match self.f.read(&mut self.buf[self.end..]) {
+                Ok(0) => return Ok(false),
+                Ok(read) => self.end += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    ///
+    /// Checks the next record, returning its header and where its data is in `buf`, or
+    /// `None` at the end of the log
+    ///
+    /// Doesn't move past the record, so that a record failing to decode is read again.
+    ///
+    fn next_checked(&mut self) -> io::Result<Option<(Header, Range<usize>)>> {
+        // The longest header, since its flags tell how long it is
+        if !self.fill(HEADER_LEN + 4)? && self.end - self.start < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = match Header::read(&mut &self.buf[self.start..self.end]) {
+            Ok(header) => header,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let len = header.len() + header.data_len()?;
+        if !self.fill(len)? {
+            return Ok(None);
+        }
+        let data = self.start + header.len()..self.start + len;
+        if self.check {
+            check(&header, &self.buf[data.clone()], self.format)?;
+        }
+        Ok(Some((header, data)))
+    }
+
+    ///
+    /// Like `next_checked`, resyncing if it fails, and marking the end of the log
+    ///
+    /// Only checksum failures, and the log ending before the file does, are resynced.
+    /// Failing to read the file fails as it is.
+    ///
+    fn next_checked_or_resync(&mut self) -> Option<io::Result<(Header, Range<usize>)>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if self.pass_over.front().map(|span| span.start) == Some(self.position) {
+                let span = self.pass_over.pop_front().expect("a span starts here");
+                if let Err(err) = self.move_to(span.end) {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+
+            let err = match self.next_checked() {
+                Ok(Some(next)) => return Some(Ok(next)),
+                Ok(None) => None,
+                Err(err)
+                    if matches!(RecordError::of(&err), Some(RecordError::Corrupted { .. })) =>
+                {
+                    Some(err)
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            };
+            match self.resync() {
+                Ok(true) => {
+                    self.checksum_failures += err.is_some() as u64;
+                }
+                Ok(false) => {
+                    self.done = true;
+                    return err.map(Err);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Moves on to the first sync marker after the current record, if there is one
+    fn resync(&mut self) -> io::Result<bool> {
+        if !self.resync {
+            return Ok(false);
+        }
+        let Some(marker) = sync_marker::find(&mut self.f, self.position + 1, self.format)? else {
+            return Ok(false);
+        };
+
+        self.skipped_bytes += marker - self.position;
+        self.skipped.push(self.position..marker);
+        self.move_to(marker)?;
+        Ok(true)
+    }
+
+    /// Goes on reading from `position`, dropping what was read ahead
+    fn move_to(&mut self, position: u64) -> io::Result<()> {
+        self.f.seek(SeekFrom::Start(position))?;
+        (self.start, self.end, self.position) = (0, 0, position);
+        Ok(())
+    }
+
+    ///
+    /// Checks the next record without decoding it, returning its position and header
+    ///
+    /// Encrypted records don't need the key for this.
+    ///
+    pub(super) fn skip(&mut self) -> Option<io::Result<(u64, Header)>> {
+        let (header, data) = match self.next_checked_or_resync()? {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let position = self.position;
+        self.position += (data.end - self.start) as u64;
+        self.start = data.end;
+        Some(Ok((position, header)))
+    }
+
+    /// Parses the next record, returning its position and header along with it
+    pub(super) fn next(&mut self) -> Option<io::Result<(u64, Header, RecordRef<'_>)>> {
+        let (header, data) = match self.next_checked_or_resync()? {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let (bytes, cipher) = (&self.buf[data.clone()], self.cipher.as_ref());
+        match RecordRef::decode(&header, bytes, cipher, &mut self.plaintext) {
+            Ok(record) => {
+                let position = self.position;
+                self.position += (data.end - self.start) as u64;
+                self.start = data.end;
+                Some(Ok((position, header, record)))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl KV {
+    ///
+    /// Checks every record of the log against its checksum, returning how many there are
+    ///
+    /// Encrypted records are authenticated too if the store has a key. Stops at the first
+    /// record failing, returning why. Nothing is kept, so it's cheap on memory however large
+    /// the log is.
+    ///
+    pub fn verify(&mut self) -> io::Result<u64> {
+        let (start, format, cipher) = (self.format.data_start(), self.format, self.cipher.clone());
+        let decode = cipher.is_some();
+        let mut records = RecordBlocks::starting_at(&mut self.f, start, format, cipher)?;
+
+        let mut count = 0;
+        let result = loop {
+            let checked = match decode {
+                true => records.next().map(|record| record.map(|_| ())),
+                false => records.skip().map(|record| record.map(|_| ())),
+            };
+            match checked {
+                None => break Ok(count),
+                Some(Ok(())) => count += 1,
+                Some(Err(err)) => break Err(err),
+            }
+        };
+        self.count_failure(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::KEY_LEN, store::Limits};
+
+    #[test]
+    fn blocks_match_records_read_one_by_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 100,
+                ..Limits::default()
+            })
+            .unwrap();
+        for i in 0..50u32 {
+            let value = vec![i as u8 + 1; (i as usize * 7) % 300 + 1];
+            store.insert(&i.to_le_bytes(), &value).unwrap();
+        }
+        store.set_cipher(Cipher::new(&[7; KEY_LEN]));
+        store.insert(b"secret", b"value").unwrap();
+        store.delete(b"secret").unwrap();
+
+        let (format, cipher) = (store.format, store.cipher.clone());
+        let start = format.data_start();
+        let expected: Vec<KeyValuePair> = {
+            let mut f = io::BufReader::new(&mut store.f);
+            f.seek(SeekFrom::Start(start)).unwrap();
+            let mut records = Vec::new();
+            while let Ok((_, kv)) = KV::process_record(&mut f, format, cipher.as_ref()) {
+                records.push(kv);
+            }
+            records
+        };
+
+        // Blocks far smaller than some records, so records straddle them and the buffer
+        // has to grow
+        let mut blocks = RecordBlocks::starting_at(&mut store.f, start, format, cipher).unwrap();
+        blocks.block_len = 64;
+        let mut found = Vec::new();
+        while let Some(record) = blocks.next() {
+            found.push(KeyValuePair::from(record.unwrap().2));
+        }
+        assert_eq!(found, expected);
+        assert_eq!(blocks.position(), std::fs::metadata(&path).unwrap().len());
+
+        assert_eq!(store.verify().unwrap(), expected.len() as u64);
+        store.load().unwrap();
+        assert_eq!(store.find(b"secret").unwrap().unwrap().1, b"");
+
+        // A record cut short ends the log, and a damaged one fails verification
+        let len = std::fs::metadata(&path).unwrap().len();
+        drop(store);
+        let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(len - 3).unwrap();
+        let mut store = KV::open(&path).unwrap();
+        assert_eq!(store.verify().unwrap(), expected.len() as u64 - 1);
+
+        // The first byte of the first key
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[start as usize + HEADER_LEN] ^= 0xff;
+        drop(store);
+        std::fs::write(&path, bytes).unwrap();
+        let mut store = KV::open(&path).unwrap();
+        let err = store.verify().unwrap_err();
+        assert!(matches!(
+            RecordError::of(&err),
+            Some(RecordError::Corrupted { .. })
+        ));
+    }
+}
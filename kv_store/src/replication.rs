@@ -0,0 +1,520 @@
+//!
+//! Leader-follower replication by shipping the log over TCP
+//!
+//! A follower connects and sends the leader offset it has applied up to. The leader answers
+//! with the header of its data file, telling how its records are checksummed, which is all
+//! zeroes if the file has none. Then it sends every record stored from that offset on, and
+//! keeps sending new ones as they're appended. Each frame on the wire is:
+//!
+//! ```text
+//! [offset: u64][length: u32][record: length bytes]
+//! ```
+//!
+//! where `offset` is the position of the record in the leader's data file. A frame with a
+//! length of zero carries no record; the leader sends it when the follower has caught up,
+//! with `offset` being the end of the leader's log.
+//!
+//! Offsets only make sense in a single generation of the leader's log, which compaction
+//! rewrites. The header carries the id and generation of the log, which the follower keeps
+//! next to its offset, and it refuses to resume from a different one. The leader ends the
+//! connection once its file is replaced.
+//!
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    chunked::Manifest,
+    format::{FILE_HEADER_LEN, Format, LogId},
+    namespace::{CATALOG, LOCAL},
+    store::{ByteStr, ByteString, FLAG_CHUNK, FLAG_MANIFEST, FLAG_MERGE, FLAG_SYNC, KV},
+};
+
+/// How long the leader waits before looking for new records once a follower has caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Local key under which a follower stores the leader offset it has applied up to, see
+/// `KV::get_local`
+pub const OFFSET_KEY: &ByteStr = b"replication_offset";
+
+/// Local key under which a follower stores the id and generation of the leader's log, which
+/// its offset is in
+pub const LOG_ID_KEY: &ByteStr = b"replication_log_id";
+
+/// Where followers stored the offset before local keys existed, in the default namespace
+const LEGACY_OFFSET_KEY: &ByteStr = b"+replication_offset";
+
+///
+/// Ships the log at `path` to every follower connecting to `listener`
+///
+/// The file is only read, without taking a lock, so the process writing to it keeps
+/// working as usual. Each follower is served on its own thread; this function only returns
+/// if accepting connections fails.
+///
+pub fn serve(path: &Path, listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let path = path.to_path_buf();
+
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            // A follower going away is business as usual, so errors are only reported
+            if let Err(err) = ship(path, stream) {
+                eprintln!("replication to {peer:?} stopped: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Sends records to a single follower until it disconnects
+fn ship(path: PathBuf, stream: TcpStream) -> io::Result<()> {
+    let mut offset = (&stream).read_u64::<LittleEndian>()?;
+    let mut f = BufReader::new(File::open(&path)?);
+    let mut out = BufWriter::new(&stream);
+
+    let format = Format::read(&mut f)?;
+    let mut header = Vec::new();
+    format.write(&mut header)?;
+    // Followers always read a header, so files from before headers send a blank one
+    header.resize(header.len().max(FILE_HEADER_LEN), 0);
+    out.write_all(&header)?;
+
+    offset = offset.max(format.data_start());
+    f.seek(SeekFrom::Start(offset))?;
+
+    // Whether records were sent since the follower was last told it caught up
+    let mut sent = true;
+
+    loop {
+        match KV::read_raw_record(&mut f) {
+            Ok(raw) => {
+                // Only records holding chunks of nearly 4 GiB can be this long
+                let len = u32::try_from(raw.len()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("record at {offset} is too long to replicate"),
+                    )
+                })?;
+                out.write_u64::<LittleEndian>(offset)?;
+                out.write_u32::<LittleEndian>(len)?;
+                out.write_all(&raw)?;
+                offset += raw.len() as u64;
+                sent = true;
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                if sent {
+                    out.write_u64::<LittleEndian>(offset)?;
+                    out.write_u32::<LittleEndian>(0)?;
+                    out.flush()?;
+                    sent = false;
+                }
+
+                thread::sleep(POLL_INTERVAL);
+                // Compaction renames a new file over the one open here, which is never
+                // written to again
+                if replaced(&path, format, offset)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the log was rewritten by compaction, so its offsets changed",
+                    ));
+                }
+                // The last read may have stopped halfway through a record that is still
+                // being written, so start over at the record boundary
+                f.seek(SeekFrom::Start(offset))?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+///
+/// Whether the file at `path` is no longer the log of `format`, read up to `offset`
+///
+/// Logs without an id are only told apart by being shorter than what was read.
+///
+fn replaced(path: &Path, format: Format, offset: u64) -> io::Result<bool> {
+    let mut f = File::open(path)?;
+    Ok(Format::read(&mut f)?.log_id != format.log_id || f.metadata()?.len() < offset)
+}
+
+///
+/// Fails unless the leader's log is the one the follower's offset is in, recording it if
+/// the follower hasn't any
+///
+/// Followers from before log ids resume from any log, like they used to.
+///
+fn check_log_id(store: &mut KV, leader: Option<LogId>) -> io::Result<()> {
+    let followed = match store.get_local(LOG_ID_KEY)? {
+        Some(stored) => {
+            let mut stored = &stored[..];
+            Some(LogId {
+                id: stored.read_u64::<LittleEndian>()?,
+                generation: stored.read_u64::<LittleEndian>()?,
+            })
+        }
+        None => None,
+    };
+
+    match (followed, leader) {
+        (Some(followed), _) if Some(followed) != leader => {
+            let leader = match leader {
+                Some(leader) => format!("generation {} of log {:x}", leader.generation, leader.id),
+                None => "a log without an id".to_string(),
+            };
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "follower is at generation {} of log {:x}, but the leader has {leader}, \
+                     whose offsets don't match, so follow it with a new store",
+                    followed.generation, followed.id
+                ),
+            ))
+        }
+        (None, Some(leader)) => {
+            let mut encoded = Vec::with_capacity(16);
+            encoded.write_u64::<LittleEndian>(leader.id)?;
+            encoded.write_u64::<LittleEndian>(leader.generation)?;
+            store.insert_local(LOG_ID_KEY, &encoded)
+        }
+        _ => Ok(()),
+    }
+}
+
+///
+/// The leader offset a follower resumes from, given the value stored under `OFFSET_KEY`
+///
+/// That's the offset applied up to, followed by the offset of the merge operand written
+/// along with it, if it was. An operand that never made it to disk leaves the offset as the
+/// last record in the file, and is asked for again.
+///
+fn resume_offset(store: &mut KV, mut stored: &ByteStr) -> io::Result<u64> {
+    let applied = stored.read_u64::<LittleEndian>()?;
+    if stored.is_empty() {
+        return Ok(applied);
+    }
+
+    let merge = stored.read_u64::<LittleEndian>()?;
+    match store.local_end(OFFSET_KEY)? == Some(store.loaded) {
+        true => Ok(merge),
+        false => Ok(applied),
+    }
+}
+
+/// What a follower received from its leader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    /// A record was received, and applied unless it was local to the leader. Holds the
+    /// leader offset it was stored at
+    Record(u64),
+    /// Everything up to the end of the leader's log, at this offset, has been applied
+    CaughtUp(u64),
+}
+
+///
+/// Applies records received from a leader to a local store
+///
+/// The leader offset applied so far is stored in the local key `OFFSET_KEY` whenever the
+/// follower catches up, so a new `Follower` resumes where the previous one stopped, as long
+/// as the leader's log wasn't compacted in the meantime, see `LOG_ID_KEY`. Records
+/// received twice after a crash are simply applied again, except for merge operands, which
+/// would be folded in twice: the offset is stored with the same write as each of them. The
+/// leader's local keys aren't applied, since they're about its own file.
+///
+/// The chunks of a large value are held in memory until its manifest arrives, and the value
+/// is then inserted as a whole. Namespaces keep the ids they have on the leader.
+///
+pub struct Follower<'a> {
+    store: &'a mut KV,
+    stream: BufReader<TcpStream>,
+    // Tells how the leader's records are checksummed
+    leader_format: Format,
+    // Leader offset right after the last record applied
+    applied: u64,
+    // Leader offset last written under `OFFSET_KEY`
+    stored: u64,
+    // Chunks of a large value received so far
+    chunks: ByteString,
+}
+
+impl<'a> Follower<'a> {
+    pub fn connect<A: ToSocketAddrs>(leader: A, store: &'a mut KV) -> io::Result<Self> {
+        let applied = match store.get_local(OFFSET_KEY)? {
+            Some(offset) => resume_offset(store, &offset)?,
+            // Followers from before local keys kept it in the default namespace. It's made a
+            // local key right away, so that a key of the same name received from the leader
+            // is never taken for it
+            None => {
+                let applied = match store.get(LEGACY_OFFSET_KEY)? {
+                    Some(offset) => (&offset[..]).read_u64::<LittleEndian>()?,
+                    None => 0,
+                };
+                store.insert_local(OFFSET_KEY, &applied.to_le_bytes())?;
+                applied
+            }
+        };
+
+        let mut stream = TcpStream::connect(leader)?;
+        stream.write_u64::<LittleEndian>(applied)?;
+        let mut stream = BufReader::new(stream);
+        let leader_format = Format::read(&mut stream)?;
+        check_log_id(store, leader_format.log_id)?;
+
+        Ok(Self {
+            store,
+            stream,
+            leader_format,
+            // Records start after the leader's file header
+            applied: applied.max(leader_format.data_start()),
+            stored: applied,
+            chunks: ByteString::new(),
+        })
+    }
+
+    /// The store records are applied to
+    pub fn store(&mut self) -> &mut KV {
+        self.store
+    }
+
+    /// Leader offset the store is up to date with
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    ///
+    /// Waits for the next frame from the leader and applies it
+    ///
+    /// Records failing their checksum are rejected with an `InvalidData` error before
+    /// anything is written.
+    ///
+    pub fn receive(&mut self) -> io::Result<Received> {
+        let offset = self.stream.read_u64::<LittleEndian>()?;
+        let len = self.stream.read_u32::<LittleEndian>()?;
+
+        if offset != self.applied {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected offset {} from leader, got {offset}", self.applied),
+            ));
+        }
+
+        if len == 0 {
+            // Chunks aren't stored yet, so the offset can't move past them
+            if offset != self.stored && self.chunks.is_empty() {
+                let mut encoded = Vec::with_capacity(8);
+                encoded.write_u64::<LittleEndian>(offset)?;
+                self.store.insert_local(OFFSET_KEY, &encoded)?;
+                self.stored = offset;
+            }
+            return Ok(Received::CaughtUp(offset));
+        }
+
+        let mut raw = vec![0; len as usize];
+        self.stream.read_exact(&mut raw)?;
+        // Decoding checks the checksum, and decrypts with the follower's key
+        let format = self.leader_format;
+        let (header, mut kv) =
+            KV::process_record(&mut &raw[..], format, self.store.cipher.as_ref())?;
+
+        // Sync markers belong to the leader's log, the follower's has its own
+        if header.flags & FLAG_SYNC != 0 {
+            self.applied = offset + len as u64;
+            return Ok(Received::Record(offset));
+        }
+
+        if header.flags & FLAG_CHUNK != 0 {
+            self.chunks.extend_from_slice(&kv.value);
+        } else {
+            if header.flags & FLAG_MANIFEST != 0 {
+                let manifest = Manifest::decode(&kv.value)?;
+                kv.value = std::mem::take(&mut self.chunks);
+                if kv.value.len() as u64 != manifest.value_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("large value at {offset} doesn't match its chunks"),
+                    ));
+                }
+            }
+
+            // Chunks left over from a large value that was never finished are dropped
+            self.chunks.clear();
+            match header.namespace {
+                LOCAL => {}
+                0 if header.flags & FLAG_MERGE != 0 => {
+                    let end = offset + len as u64;
+                    let mut stored = Vec::with_capacity(16);
+                    stored.write_u64::<LittleEndian>(end)?;
+                    stored.write_u64::<LittleEndian>(offset)?;
+                    self.store
+                        .merge_after_local(OFFSET_KEY, &stored, &kv.key, &kv.value)?;
+                    self.stored = end;
+                }
+                CATALOG => self.store.replicate_catalog(&kv.key, &kv.value)?,
+                namespace => self.store.insert_in(namespace, &kv.key, &kv.value)?,
+            }
+        }
+        self.applied = offset + len as u64;
+
+        Ok(Received::Record(offset))
+    }
+
+    /// Applies records until the connection to the leader is lost
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.receive()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catch_up(follower: &mut Follower, end: u64) {
+        // The leader may report catching up halfway, while records are still being written
+        while follower.receive().unwrap() != Received::CaughtUp(end) {}
+    }
+
+    #[test]
+    fn follower_applies_leader_log_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader_path = dir.path().join("leader.db");
+        let follower_path = dir.path().join("follower.db");
+
+        let mut leader = KV::open(&leader_path).unwrap();
+        leader.insert(b"apple", b"red").unwrap();
+        leader.insert(b"banana", b"yellow").unwrap();
+        leader.insert(b"+plus", b"sign").unwrap();
+        leader.insert_local(b"note", b"leader only").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&leader_path, listener));
+
+        let mut store = KV::open(&follower_path).unwrap();
+        {
+            let mut follower = Follower::connect(addr, &mut store).unwrap();
+            catch_up(&mut follower, leader.seek_to_end().unwrap());
+
+            leader.insert(b"cherry", b"dark red").unwrap();
+            leader.delete(b"apple").unwrap();
+            leader.create_namespace(b"trees").unwrap();
+            let mut trees = leader.namespace(b"trees").unwrap();
+            trees.insert(b"cherry", b"sakura").unwrap();
+            catch_up(&mut follower, leader.seek_to_end().unwrap());
+        }
+
+        assert_eq!(store.get(b"apple").unwrap(), Some(b"".to_vec()));
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"yellow".to_vec()));
+        assert_eq!(store.get(b"cherry").unwrap(), Some(b"dark red".to_vec()));
+        let mut trees = store.namespace(b"trees").unwrap();
+        assert_eq!(trees.get(b"cherry").unwrap(), Some(b"sakura".to_vec()));
+
+        // Any key is applied, but the leader's local keys aren't, and the follower keeps
+        // its offset among its own
+        assert_eq!(store.get(b"+plus").unwrap(), Some(b"sign".to_vec()));
+        assert_eq!(store.get_local(b"note").unwrap(), None);
+        assert!(store.get_local(OFFSET_KEY).unwrap().is_some());
+        assert!(!store.index().contains_key(OFFSET_KEY));
+
+        // A new follower on the reopened store only receives what it hasn't seen, even
+        // once the store is compacted
+        drop(store);
+        let mut store = KV::open(&follower_path).unwrap();
+        store.load().unwrap();
+        store.compact().unwrap();
+
+        let previous_end = leader.seek_to_end().unwrap();
+        leader.insert(b"date", b"brown").unwrap();
+        let end = leader.seek_to_end().unwrap();
+
+        let mut follower = Follower::connect(addr, &mut store).unwrap();
+        assert_eq!(follower.applied(), previous_end);
+        assert_eq!(follower.receive().unwrap(), Received::Record(previous_end));
+        assert_eq!(follower.receive().unwrap(), Received::CaughtUp(end));
+        drop(follower);
+        assert_eq!(store.get(b"date").unwrap(), Some(b"brown".to_vec()));
+
+        // Merge operands are applied once, even if the follower stops before catching up
+        let first = leader.seek_to_end().unwrap();
+        leader.merge(b"visits", &5u64.to_le_bytes()).unwrap();
+        let second = leader.seek_to_end().unwrap();
+        leader.merge(b"visits", &7u64.to_le_bytes()).unwrap();
+        let end = leader.seek_to_end().unwrap();
+        let mut follower = Follower::connect(addr, &mut store).unwrap();
+        assert_eq!(follower.receive().unwrap(), Received::Record(first));
+        assert_eq!(follower.receive().unwrap(), Received::Record(second));
+        drop(follower);
+        drop(store);
+
+        let reopen = || {
+            let mut store = KV::open(&follower_path).unwrap();
+            store.set_merge_operator(crate::merge::U64Add);
+            store.load().unwrap();
+            store
+        };
+        let mut store = reopen();
+        assert_eq!(
+            store.get(b"visits").unwrap(),
+            Some(12u64.to_le_bytes().to_vec())
+        );
+        let follower = Follower::connect(addr, &mut store).unwrap();
+        assert_eq!(follower.applied(), end);
+        drop(follower);
+        drop(store);
+
+        // An operand cut short by a crash is asked for again
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&follower_path)
+            .unwrap();
+        f.set_len(f.metadata().unwrap().len() - 1).unwrap();
+        drop(f);
+        let mut store = reopen();
+        assert_eq!(
+            store.get(b"visits").unwrap(),
+            Some(5u64.to_le_bytes().to_vec())
+        );
+        let mut follower = Follower::connect(addr, &mut store).unwrap();
+        assert_eq!(follower.applied(), second);
+        catch_up(&mut follower, end);
+        assert_eq!(
+            follower.store().get(b"visits").unwrap(),
+            Some(12u64.to_le_bytes().to_vec())
+        );
+
+        // Compacting the leader moves its records, so a follower connected to it is cut off,
+        // and doesn't resume from its offset in the old file
+        leader.set_merge_operator(crate::merge::U64Add);
+        leader.compact().unwrap();
+        leader.insert(b"elderberry", b"black").unwrap();
+        assert!(follower.run().is_err());
+        drop(follower);
+        let err = Follower::connect(addr, &mut store).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.get(b"elderberry").unwrap(), None);
+
+        // A new store follows the compacted log, and records its generation
+        let mut fresh = KV::open(&dir.path().join("fresh.db")).unwrap();
+        fresh.set_merge_operator(crate::merge::U64Add);
+        let mut follower = Follower::connect(addr, &mut fresh).unwrap();
+        catch_up(&mut follower, leader.seek_to_end().unwrap());
+        drop(follower);
+        assert_eq!(fresh.get(b"elderberry").unwrap(), Some(b"black".to_vec()));
+        assert_eq!(
+            fresh.get(b"visits").unwrap(),
+            Some(12u64.to_le_bytes().to_vec())
+        );
+        let generation = &fresh.get_local(LOG_ID_KEY).unwrap().unwrap()[8..];
+        assert_eq!(generation, 1u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,205 @@
+//!
+//! Secondary indexes: looking records up by something inside their value
+//!
+//! A secondary index is given an extractor, a function taking the value of a record and
+//! returning the key to index it under, such as the `email` field of a JSON document. It's
+//! kept in memory only: it's updated by every write and by `load`, so it's rebuilt along
+//! with the primary index whenever the store is opened. Indexes must be added again each
+//! time, before or after `load`: either way, the latest value of every key is read once
+//! more to index it.
+//!
+//! Only the default namespace is indexed. Deleted keys and values the extractor returns
+//! `None` for are left out.
+//!
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, io,
+};
+
+use crate::store::{ByteStr, ByteString, KV, KeyValuePair};
+
+/// Gives the key a value is indexed under, if any
+pub type Extractor = Box<dyn Fn(&ByteStr) -> Option<ByteString> + Send>;
+
+pub(super) struct SecondaryIndex {
+    extractor: Extractor,
+    // Primary keys of the records found under every index key
+    entries: HashMap<ByteString, BTreeSet<ByteString>>,
+    // Index key of every primary key indexed, to find the entry again once its value changes
+    keys: HashMap<ByteString, ByteString>,
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecondaryIndex {
+    /// Indexes `value` as the new value of `key`, replacing the previous one
+    fn update(&mut self, key: &ByteStr, value: &ByteStr) {
+        if let Some(old) = self.keys.remove(key)
+            && let Some(primary) = self.entries.get_mut(&old)
+        {
+            primary.remove(key);
+            if primary.is_empty() {
+                self.entries.remove(&old);
+            }
+        }
+
+        // Empty values are deletions
+        if value.is_empty() {
+            return;
+        }
+
+        if let Some(index_key) = (self.extractor)(value) {
+            let primary = self.entries.entry(index_key.clone()).or_default();
+            primary.insert(key.to_vec());
+            self.keys.insert(key.to_vec(), index_key);
+        }
+    }
+}
+
+impl KV {
+    ///
+    /// Adds the secondary index `name`, indexing every value under what `extractor` returns
+    ///
+    /// Records already in the index are read to fill it. An index of the same name is
+    /// replaced.
+    ///
+    pub fn add_index<F>(&mut self, name: &str, extractor: F) -> io::Result<()>
+    where
+        F: Fn(&ByteStr) -> Option<ByteString> + Send + 'static,
+    {
+        self.secondary.insert(
+            name.to_string(),
+            SecondaryIndex {
+                extractor: Box::new(extractor),
+                entries: HashMap::new(),
+                keys: HashMap::new(),
+            },
+        );
+
+        let keys: Vec<ByteString> = self.index.keys().cloned().collect();
+        self.update_secondary(keys)
+    }
+
+    /// Removes the secondary index `name`, returning whether there was one
+    pub fn remove_index(&mut self, name: &str) -> bool {
+        self.secondary.remove(name).is_some()
+    }
+
+    ///
+    /// Returns every record whose value is indexed under `key` by the secondary index
+    /// `name`, sorted by primary key
+    ///
+    pub fn get_by_index(&mut self, name: &str, key: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let index = self.secondary.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no secondary index named {name:?}"),
+            )
+        })?;
+
+        let matches: Vec<(ByteString, u64)> = match index.entries.get(key) {
+            None => Vec::new(),
+            Some(primary) => primary
+                .iter()
+                .filter_map(|key| Some((key.clone(), *self.index.get(key)?)))
+                .collect(),
+        };
+        self.read_live(0, matches)
+    }
+
+    /// Indexes `value` as the new value of `key` in every secondary index
+    pub(super) fn index_value(&mut self, key: &ByteStr, value: &ByteStr) {
+        for index in self.secondary.values_mut() {
+            index.update(key, value);
+        }
+    }
+
+    /// Reads the current values of `keys` back, and indexes them in every secondary index
+    pub(super) fn update_secondary<I>(&mut self, keys: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = ByteString>,
+    {
+        if self.secondary.is_empty() {
+            return Ok(());
+        }
+
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                self.index_value(&key, &value);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(value: &ByteStr) -> Option<ByteString> {
+        let user: serde_json::Value = serde_json::from_slice(value).ok()?;
+        Some(user.get("email")?.as_str()?.as_bytes().to_vec())
+    }
+
+    fn keys(found: Vec<KeyValuePair>) -> Vec<ByteString> {
+        found.into_iter().map(|kv| kv.key).collect()
+    }
+
+    #[test]
+    fn secondary_index_follows_writes_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .insert(b"ada", br#"{"email":"ada@example.com"}"#)
+            .unwrap();
+        store.add_index("email", email).unwrap();
+        store
+            .insert(b"alan", br#"{"email":"alan@example.com"}"#)
+            .unwrap();
+        store
+            .insert(b"grace", br#"{"email":"ada@example.com"}"#)
+            .unwrap();
+        store.insert(b"plain", b"not json").unwrap();
+
+        let found = store.get_by_index("email", b"ada@example.com").unwrap();
+        assert_eq!(keys(found), [b"ada".to_vec(), b"grace".to_vec()]);
+
+        store
+            .update(b"grace", br#"{"email":"grace@example.com"}"#)
+            .unwrap();
+        store.delete(b"alan").unwrap();
+        assert_eq!(
+            keys(store.get_by_index("email", b"ada@example.com").unwrap()),
+            [b"ada".to_vec()]
+        );
+        assert!(
+            store
+                .get_by_index("email", b"alan@example.com")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(store.get_by_index("name", b"ada").is_err());
+
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.add_index("email", email).unwrap();
+        store.load().unwrap();
+        let found = store.get_by_index("email", b"grace@example.com").unwrap();
+        assert_eq!(found[0].value, br#"{"email":"grace@example.com"}"#);
+        assert!(
+            store
+                .get_by_index("email", b"alan@example.com")
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//!
+//! Sorted tables: read-only snapshots of a store, for shipping elsewhere
+//!
+//! `KV::export_sstable` writes every live key of the default namespace with its value to a
+//! file of its own, sorted by key. `SsTableReader` answers lookups and range scans straight
+//! from that file, reading only the blocks it needs, without a log to load or an index to
+//! build. The file is never written again once exported:
+//!
+//! ```text
+//! [magic: "KVSSTBL"][version: u8][checksum algorithm: u8][reserved: 7 zero bytes]
+//! [data block]...
+//! [index block]
+//! [index offset: u64][index length: u32][index checksum: u32][entries: u64][magic: "KVSSTEND"]
+//! ```
+//!
+//! Data blocks hold entries of `[key length: u32][value length: u32][key][value]`, closed
+//! once they're `BLOCK_LEN` bytes or longer. The index block has an entry for every data
+//! block, in order:
+//!
+//! ```text
+//! [last key length: u32][last key][offset: u64][length: u32][checksum: u32]
+//! ```
+//!
+//! Every block is checked against its checksum when it's read, with the algorithm of the
+//! store it was exported from. Numbers are little endian.
+//!
+//! Values are stored as `get` returns them: large values whole and merge operands folded.
+//! Tables aren't encrypted, so a store with a cipher set only exports through
+//! `KV::export_sstable_decrypted`, which writes its values in the clear.
+//!
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    path::Path,
+    vec,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    format::Checksum,
+    store::{self, ByteStr, ByteString, KV, KeyValuePair, RecordError},
+};
+
+const MAGIC: &[u8; 7] = b"KVSSTBL";
+const FOOTER_MAGIC: &[u8; 8] = b"KVSSTEND";
+const VERSION: u8 = 1;
+
+const HEADER_LEN: u64 = 16;
+const FOOTER_LEN: u64 = 32;
+
+/// Data blocks are closed once they're this long, so they're read with a single call
+const BLOCK_LEN: usize = 4 << 10;
+
+/// Where a data block is, and the last key in it
+#[derive(Debug)]
+struct BlockHandle {
+    last_key: ByteString,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("sorted table {message}"),
+    )
+}
+
+/// Checks `bytes`, a block read back, against the checksum it was written with
+fn check(checksum: Checksum, bytes: &ByteStr, expected: u32) -> io::Result<()> {
+    let found = checksum.compute(bytes);
+    if found != expected {
+        return Err(RecordError::Corrupted { expected, found }.into());
+    }
+
+    Ok(())
+}
+
+/// Fails for lengths that don't fit the u32 fields of a table
+fn u32_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{len} bytes don't fit in a sorted table entry"),
+        )
+    })
+}
+
+/// Writes the blocks of a table one after another, noting where each one went
+struct TableWriter<W> {
+    f: W,
+    checksum: Checksum,
+    block: ByteString,
+    last_key: ByteString,
+    position: u64,
+    index: Vec<BlockHandle>,
+    entries: u64,
+}
+
+impl<W: Write> TableWriter<W> {
+    fn new(mut f: W, checksum: Checksum) -> io::Result<Self> {
+        let mut header = [0; HEADER_LEN as usize];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = VERSION;
+        header[MAGIC.len() + 1] = checksum.id();
+        f.write_all(&header)?;
+
+        Ok(Self {
+            f,
+            checksum,
+            block: ByteString::with_capacity(BLOCK_LEN),
+            last_key: ByteString::new(),
+            position: HEADER_LEN,
+            index: Vec::new(),
+            entries: 0,
+        })
+    }
+
+    /// Adds an entry, with a key sorting after the one added before it
+    fn add(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.block.write_u32::<LittleEndian>(u32_len(key.len())?)?;
+        self.block
+            .write_u32::<LittleEndian>(u32_len(value.len())?)?;
+        self.block.extend_from_slice(key);
+        self.block.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.entries += 1;
+
+        if self.block.len() >= BLOCK_LEN {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.index.push(BlockHandle {
+            last_key: std::mem::take(&mut self.last_key),
+            offset: self.position,
+            len: u32_len(self.block.len())?,
+            checksum: self.checksum.compute(&self.block),
+        });
+        self.f.write_all(&self.block)?;
+        self.position += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes what's left of the last block, the index and the footer
+    fn finish(mut self) -> io::Result<W> {
+        self.finish_block()?;
+
+        let mut index = ByteString::new();
+        for block in &self.index {
+            index.write_u32::<LittleEndian>(u32_len(block.last_key.len())?)?;
+            index.extend_from_slice(&block.last_key);
+            index.write_u64::<LittleEndian>(block.offset)?;
+            index.write_u32::<LittleEndian>(block.len)?;
+            index.write_u32::<LittleEndian>(block.checksum)?;
+        }
+        self.f.write_all(&index)?;
+
+        self.f.write_u64::<LittleEndian>(self.position)?;
+        self.f.write_u32::<LittleEndian>(u32_len(index.len())?)?;
+        self.f
+            .write_u32::<LittleEndian>(self.checksum.compute(&index))?;
+        self.f.write_u64::<LittleEndian>(self.entries)?;
+        self.f.write_all(FOOTER_MAGIC)?;
+        Ok(self.f)
+    }
+}
+
+impl KV {
+    ///
+    /// Writes every live key of the default namespace with its value to a sorted table at
+    /// `path`, returning how many there are
+    ///
+    /// Deleted keys are left out. The table is written next to `path` first, and only
+    /// renamed to it once it's whole and on disk, so `path` never holds part of one.
+    ///
+    /// Fails if a cipher is set, since the table would hold the decrypted values, see
+    /// `export_sstable_decrypted`.
+    ///
+    pub fn export_sstable(&mut self, path: &Path) -> io::Result<u64> {
+        if self.cipher.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sorted tables aren't encrypted, and exporting would write the values of an \
+                 encrypted store in the clear, see export_sstable_decrypted",
+            ));
+        }
+
+        self.export_sstable_decrypted(path)
+    }
+
+    ///
+    /// Like `export_sstable`, writing the values of encrypted records decrypted
+    ///
+    /// Anyone able to read the table reads them without the key.
+    ///
+    pub fn export_sstable_decrypted(&mut self, path: &Path) -> io::Result<u64> {
+        let mut tmp_path = OsString::from(path);
+        tmp_path.push(".tmp");
+
+        let written = File::create(&tmp_path).and_then(|f| {
+            let mut table = TableWriter::new(BufWriter::new(f), self.format.checksum)?;
+            for (key, position) in store::matching(&self.index, b"") {
+                let kv = self.read_value(0, &key, position)?;
+                if !kv.value.is_empty() {
+                    table.add(&key, &kv.value)?;
+                }
+            }
+
+            let entries = table.entries;
+            let f = table.finish()?.into_inner().map_err(io::Error::from)?;
+            f.sync_all()?;
+            Ok(entries)
+        });
+
+        match written.and_then(|entries| fs::rename(&tmp_path, path).map(|_| entries)) {
+            Ok(entries) => Ok(entries),
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(err)
+            }
+        }
+    }
+}
+
+///
+/// Lookups and range scans against a sorted table written by `KV::export_sstable`
+///
+/// Only the index of the blocks is read when opening, and a data block is read whenever an
+/// entry in it is needed.
+///
+#[derive(Debug)]
+pub struct SsTableReader {
+    f: File,
+    checksum: Checksum,
+    index: Vec<BlockHandle>,
+    entries: u64,
+}
+
+impl SsTableReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut f = File::open(path)?;
+        let len = f.metadata()?.len();
+        if len < HEADER_LEN + FOOTER_LEN {
+            return Err(invalid("is too short"));
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        f.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid("doesn't start with its magic bytes"));
+        }
+        let version = header[MAGIC.len()];
+        if version != VERSION {
+            return Err(invalid(&format!("has unsupported version {version}")));
+        }
+        let checksum = Checksum::from_id(header[MAGIC.len() + 1])?;
+
+        f.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        let index_offset = f.read_u64::<LittleEndian>()?;
+        let index_len = f.read_u32::<LittleEndian>()?;
+        let index_checksum = f.read_u32::<LittleEndian>()?;
+        let entries = f.read_u64::<LittleEndian>()?;
+        let mut magic = [0; FOOTER_MAGIC.len()];
+        f.read_exact(&mut magic)?;
+        if &magic != FOOTER_MAGIC {
+            return Err(invalid("is cut short"));
+        }
+        // The footer is read from the file, so its numbers can be anything
+        let index_end = index_offset.checked_add(index_len as u64);
+        if index_offset < HEADER_LEN || index_end != Some(len - FOOTER_LEN) {
+            return Err(invalid("has its index out of place"));
+        }
+
+        f.seek(SeekFrom::Start(index_offset))?;
+        let mut bytes = vec![0; index_len as usize];
+        f.read_exact(&mut bytes)?;
+        check(checksum, &bytes, index_checksum)?;
+
+        let mut index = Vec::new();
+        let mut bytes = &bytes[..];
+        while !bytes.is_empty() {
+            let key_len = bytes.read_u32::<LittleEndian>()? as usize;
+            let (last_key, rest) = bytes
+                .split_at_checked(key_len)
+                .ok_or_else(|| invalid("has a damaged index"))?;
+            bytes = rest;
+
+            let block = BlockHandle {
+                last_key: last_key.to_vec(),
+                offset: bytes.read_u64::<LittleEndian>()?,
+                len: bytes.read_u32::<LittleEndian>()?,
+                checksum: bytes.read_u32::<LittleEndian>()?,
+            };
+            let block_end = block.offset.checked_add(block.len as u64);
+            if block.offset < HEADER_LEN || block_end.is_none_or(|end| end > index_offset) {
+                return Err(invalid("has a block out of place"));
+            }
+            index.push(block);
+        }
+
+        Ok(Self {
+            f,
+            checksum,
+            index,
+            entries,
+        })
+    }
+
+    /// Number of keys in the table
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Reads and checks the data block at `index`, returning its entries in order
+    fn read_block(&mut self, index: usize) -> io::Result<Vec<KeyValuePair>> {
+        let block = &self.index[index];
+        self.f.seek(SeekFrom::Start(block.offset))?;
+        let mut bytes = vec![0; block.len as usize];
+        self.f.read_exact(&mut bytes)?;
+        check(self.checksum, &bytes, block.checksum)?;
+
+        let mut entries = Vec::new();
+        let mut bytes = &bytes[..];
+        while !bytes.is_empty() {
+            let key_len = bytes.read_u32::<LittleEndian>()? as usize;
+            let val_len = bytes.read_u32::<LittleEndian>()? as usize;
+            let (key, value) = key_len
+                .checked_add(val_len)
+                .and_then(|len| bytes.get(..len))
+                .ok_or_else(|| invalid("has a damaged block"))?
+                .split_at(key_len);
+            entries.push(KeyValuePair {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            });
+            bytes = &bytes[key_len + val_len..];
+        }
+        Ok(entries)
+    }
+
+    /// Looks up the value of `key`, reading at most one block
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        // The only block that can hold it is the first whose last key isn't before it
+        let index = self
+            .index
+            .partition_point(|block| &block.last_key[..] < key);
+        if index == self.index.len() {
+            return Ok(None);
+        }
+
+        let entries = self.read_block(index)?;
+        Ok(entries
+            .into_iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.value))
+    }
+
+    ///
+    /// Returns the entries with keys in `range`, in order
+    ///
+    /// Blocks are read as the iterator gets to them, so stopping early doesn't read the
+    /// rest.
+    ///
+    pub fn range<'k>(&mut self, range: impl RangeBounds<&'k ByteStr>) -> SsTableRange<'_> {
+        let start = range.start_bound().map(|key| key.to_vec());
+        let end = range.end_bound().map(|key| key.to_vec());
+        let block = match &start {
+            Bound::Included(start) => self.index.partition_point(|block| block.last_key < *start),
+            Bound::Excluded(start) => self.index.partition_point(|block| block.last_key <= *start),
+            Bound::Unbounded => 0,
+        };
+
+        SsTableRange {
+            table: self,
+            block,
+            entries: Vec::new().into_iter(),
+            start,
+            end,
+        }
+    }
+
+    /// Returns every entry whose key starts with `prefix`, sorted by key, like `KV::scan`
+    pub fn scan(&mut self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        self.range(prefix..)
+            .take_while(|entry| match entry {
+                Ok(entry) => entry.key.starts_with(prefix),
+                Err(_) => true,
+            })
+            .collect()
+    }
+}
+
+/// Iterator over the entries of a sorted table in a range of keys, see `SsTableReader::range`
+pub struct SsTableRange<'a> {
+    table: &'a mut SsTableReader,
+    // The next block to read
+    block: usize,
+    entries: vec::IntoIter<KeyValuePair>,
+    start: Bound<ByteString>,
+    end: Bound<ByteString>,
+}
+
+impl Iterator for SsTableRange<'_> {
+    type Item = io::Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                // Only the first block read can hold keys before the range
+                let after_start = match &self.start {
+                    Bound::Included(start) => entry.key >= *start,
+                    Bound::Excluded(start) => entry.key > *start,
+                    Bound::Unbounded => true,
+                };
+                let before_end = match &self.end {
+                    Bound::Included(end) => entry.key <= *end,
+                    Bound::Excluded(end) => entry.key < *end,
+                    Bound::Unbounded => true,
+                };
+
+                if !before_end {
+                    self.block = self.table.index.len();
+                    self.entries = Vec::new().into_iter();
+                    return None;
+                }
+                if after_start {
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.block += 1;
+                }
+                Err(err) => {
+                    // A damaged block ends the range
+                    self.block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{Cipher, KEY_LEN},
+        merge::U64Add,
+        store::Limits,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn tables_answer_like_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        store.set_merge_operator(U64Add);
+
+        let mut expected = BTreeMap::new();
+        for i in 0..2000u32 {
+            let key = format!("key{:05}", i * 7 % 2000).into_bytes();
+            let value = vec![i as u8 | 1; i as usize % 50 + 1];
+            store.insert(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+        for i in (0..2000u32).step_by(3) {
+            let key = format!("key{i:05}").into_bytes();
+            store.delete(&key).unwrap();
+            expected.remove(&key);
+        }
+        let large = vec![7; 10_000];
+        store.insert(b"large", &large).unwrap();
+        expected.insert(b"large".to_vec(), large);
+        store.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+        store.merge(b"counter", &3u64.to_le_bytes()).unwrap();
+        expected.insert(b"counter".to_vec(), 5u64.to_le_bytes().to_vec());
+        store.create_namespace(b"other").unwrap();
+        let mut other = store.namespace(b"other").unwrap();
+        other.insert(b"elsewhere", b"value").unwrap();
+
+        let path = dir.path().join("table.sst");
+        assert_eq!(store.export_sstable(&path).unwrap(), expected.len() as u64);
+        let mut table = SsTableReader::open(&path).unwrap();
+        assert_eq!(table.len(), expected.len() as u64);
+        assert!(table.index.len() > 1);
+
+        for (key, value) in &expected {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+        }
+        for missing in [&b"key00000"[..], b"a", b"zzz", b"elsewhere"] {
+            assert_eq!(table.get(missing).unwrap(), None);
+        }
+
+        let pairs = |entries: Vec<(&ByteString, &ByteString)>| -> Vec<KeyValuePair> {
+            entries
+                .into_iter()
+                .map(|(key, value)| KeyValuePair {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        };
+        let found = |range: SsTableRange<'_>| range.collect::<io::Result<Vec<_>>>().unwrap();
+        let (start, end) = (&b"key00500"[..], &b"key01000"[..]);
+        assert_eq!(
+            found(table.range(start..end)),
+            pairs(expected.range(start.to_vec()..end.to_vec()).collect())
+        );
+        assert_eq!(
+            found(table.range((Bound::Excluded(start), Bound::Included(end)))),
+            pairs(
+                expected
+                    .range((
+                        Bound::Excluded(start.to_vec()),
+                        Bound::Included(end.to_vec())
+                    ))
+                    .collect()
+            )
+        );
+        assert_eq!(found(table.range(..)), pairs(expected.iter().collect()));
+        assert_eq!(
+            table.scan(b"key019").unwrap(),
+            store.scan(b"key019").unwrap()
+        );
+        assert!(found(table.range(&b"zzz"[..]..)).is_empty());
+
+        // Damage is caught when the block holding it is read
+        drop(table);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_LEN as usize + 100] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let mut table = SsTableReader::open(&path).unwrap();
+        let err = table.get(b"counter").unwrap_err();
+        assert!(matches!(
+            RecordError::of(&err),
+            Some(RecordError::Corrupted { .. })
+        ));
+        assert_eq!(table.get(b"large").unwrap().unwrap().len(), 10_000);
+
+        // Offsets in a crafted footer are refused, however large
+        let footer = bytes.len() - FOOTER_LEN as usize;
+        let index_len = u32::from_le_bytes(bytes[footer + 8..footer + 12].try_into().unwrap());
+        let offset = (footer as u64).wrapping_sub(index_len as u64);
+        for crafted in [u64::MAX, u64::MAX - 10, offset.wrapping_add(1 << 63)] {
+            bytes[footer..footer + 8].copy_from_slice(&crafted.to_le_bytes());
+            fs::write(&path, &bytes).unwrap();
+            let err = SsTableReader::open(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Encrypted values are only written in the clear when asked to
+        store.set_cipher(Cipher::new(&[3; KEY_LEN]));
+        store.insert(b"secret", b"value").unwrap();
+        let path = dir.path().join("secret.sst");
+        let err = store.export_sstable(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+        store.export_sstable_decrypted(&path).unwrap();
+        let mut table = SsTableReader::open(&path).unwrap();
+        assert_eq!(table.get(b"secret").unwrap().unwrap(), b"value");
+    }
+}
@@ -0,0 +1,186 @@
+//!
+//! Counters describing a store, see `KV::stats`
+//!
+
+use std::{fmt::Write, io, time::Duration};
+
+use crate::store::KV;
+
+/// A snapshot of what a store holds and what it has done since it was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Keys in the index. Deleted keys count too, until compaction drops their tombstone
+    pub live_records: u64,
+    /// Records superseded by a later one for the same key, which compaction would drop
+    pub dead_records: u64,
+    /// Size of the data file
+    pub file_bytes: u64,
+    /// Records read back by key or position
+    pub reads: u64,
+    /// Records appended
+    pub writes: u64,
+    pub bytes_written: u64,
+    /// Records that didn't match their checksum, while loading or reading
+    pub checksum_failures: u64,
+    /// Bytes of damaged log passed over by `KV::recover`, up to the next sync marker
+    pub skipped_bytes: u64,
+    /// Time spent in `KV::load`, over all calls
+    pub load_time: Duration,
+    pub compactions: u64,
+    /// Bytes freed by compaction, over all runs
+    pub bytes_reclaimed: u64,
+    // Records in the data file, live and dead
+    pub(super) records: u64,
+}
+
+impl Stats {
+    ///
+    /// Formats the stats in the Prometheus text exposition format
+    ///
+    /// Every metric is prefixed with `kv_`, and counters end in `_total` as Prometheus
+    /// expects.
+    ///
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "kv_live_records",
+                "gauge",
+                "Keys in the index, deleted keys included",
+                self.live_records as f64,
+            ),
+            (
+                "kv_dead_records",
+                "gauge",
+                "Records superseded by a later record for the same key",
+                self.dead_records as f64,
+            ),
+            (
+                "kv_file_bytes",
+                "gauge",
+                "Size of the data file in bytes",
+                self.file_bytes as f64,
+            ),
+            (
+                "kv_reads_total",
+                "counter",
+                "Records read back by key or position",
+                self.reads as f64,
+            ),
+            (
+                "kv_writes_total",
+                "counter",
+                "Records appended",
+                self.writes as f64,
+            ),
+            (
+                "kv_written_bytes_total",
+                "counter",
+                "Bytes appended",
+                self.bytes_written as f64,
+            ),
+            (
+                "kv_checksum_failures_total",
+                "counter",
+                "Records that failed their checksum",
+                self.checksum_failures as f64,
+            ),
+            (
+                "kv_skipped_bytes_total",
+                "counter",
+                "Bytes of damaged log passed over while recovering",
+                self.skipped_bytes as f64,
+            ),
+            (
+                "kv_load_seconds_total",
+                "counter",
+                "Time spent building the index from the data file",
+                self.load_time.as_secs_f64(),
+            ),
+            (
+                "kv_compactions_total",
+                "counter",
+                "Compactions run",
+                self.compactions as f64,
+            ),
+            (
+                "kv_reclaimed_bytes_total",
+                "counter",
+                "Bytes freed by compaction",
+                self.bytes_reclaimed as f64,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            // Writing to a String can't fail
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} {kind}");
+            let _ = writeln!(text, "{name} {value}");
+        }
+
+        text
+    }
+}
+
+impl KV {
+    pub fn stats(&self) -> io::Result<Stats> {
+        let live_records = self.index.len() as u64 + self.namespaces.live_records();
+
+        Ok(Stats {
+            live_records,
+            dead_records: self.stats.records.saturating_sub(live_records),
+            file_bytes: self.f.metadata()?.len(),
+            ..self.stats.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_format() {
+        let stats = Stats {
+            live_records: 3,
+            dead_records: 2,
+            file_bytes: 4096,
+            writes: 5,
+            load_time: Duration::from_millis(1500),
+            ..Stats::default()
+        };
+        let text = stats.to_prometheus();
+
+        // Every metric has its help and type lines right before its sample
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len() % 3, 0);
+        let mut samples = Vec::new();
+        for metric in lines.chunks(3) {
+            let name = metric[2].split(' ').next().unwrap();
+            assert!(name.starts_with("kv_"));
+            assert!(metric[0].starts_with(&format!("# HELP {name} ")));
+            let kind = metric[1].strip_prefix(&format!("# TYPE {name} ")).unwrap();
+            match kind {
+                "counter" => assert!(name.ends_with("_total")),
+                "gauge" => assert!(!name.ends_with("_total")),
+                _ => panic!("unknown type {kind}"),
+            }
+            let value: f64 = metric[2][name.len() + 1..].parse().unwrap();
+            samples.push((name, value));
+        }
+
+        assert_eq!(samples.len(), 11);
+        for (name, value) in [
+            ("kv_live_records", 3.0),
+            ("kv_dead_records", 2.0),
+            ("kv_file_bytes", 4096.0),
+            ("kv_reads_total", 0.0),
+            ("kv_writes_total", 5.0),
+            ("kv_load_seconds_total", 1.5),
+        ] {
+            assert!(samples.contains(&(name, value)), "{name} isn't {value}");
+        }
+        assert!(text.contains("kv_load_seconds_total 1.5\n"));
+        assert!(text.contains("kv_reads_total 0\n"));
+    }
+}
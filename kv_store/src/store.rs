@@ -0,0 +1,1268 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    chunked,
+    crypto::{self, Cipher},
+    format::{Checksum, Format, LogId},
+    merge::MergeOperator,
+    namespace::Namespaces,
+    parallel_load,
+    record_ref::{self, RecordBlocks, RecordRef},
+    secondary::SecondaryIndex,
+    stats::Stats,
+    stream::ValueWriter,
+    sync_marker,
+    watch::{Event, Subscriber},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_derive::{Deserialize, Serialize};
+
+pub type ByteString = Vec<u8>;
+pub type ByteStr = [u8];
+
+/// Size of a record header: checksum, key length and value length, all u32. Records in a
+/// namespace have the namespace id after them, see `Header::len`
+pub const HEADER_LEN: usize = 12;
+
+/// Keys are at most 16 MiB, since their length shares a word with the record flags
+pub const MAX_KEY_LEN: usize = (1 << 24) - 1;
+
+/// Values longer than this don't fit in a single record, since their length is a u32
+pub const MAX_RECORD_VALUE_LEN: usize = u32::MAX as usize;
+
+/// Set on records whose data is encrypted, see `Cipher`
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+/// Set on records holding part of a large value, see `chunked`
+pub const FLAG_CHUNK: u8 = 0x02;
+/// Set on records standing for a large value stored in the chunk records before them
+pub const FLAG_MANIFEST: u8 = 0x04;
+/// Set on records outside the default namespace, whose header ends with the namespace id
+pub const FLAG_NAMESPACE: u8 = 0x08;
+/// Set on records holding a merge operand, applied to the value before it, see `merge`
+pub const FLAG_MERGE: u8 = 0x10;
+/// Set on sync markers, which hold no data, see `sync_marker`
+pub const FLAG_SYNC: u8 = 0x20;
+
+/// Most bytes allocated up front for the data of a record being read. A corrupted length
+/// could otherwise ask for gigabytes before the checksum shows it's wrong
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+///
+/// The fixed size part at the start of every record
+///
+/// On disk, it's three little endian u32 words: the checksum of the data following the
+/// header, the key length and the value length. The top 8 bits of the key length word hold
+/// flags about the record. Files written before flags existed have them all cleared, so
+/// they're read just like before. Records with `FLAG_NAMESPACE` set have a fourth word, the
+/// id of their namespace.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Header {
+    pub checksum: u32,
+    pub flags: u8,
+    pub key_len: u32,
+    pub val_len: u32,
+    /// 0 for the default namespace, see `namespace`
+    pub namespace: u32,
+}
+
+///
+/// The words of a header after the checksum, as stored
+///
+/// Covered by the checksum and sealed along with encrypted data, so their damage is caught.
+///
+pub(super) struct Words {
+    bytes: [u8; 12],
+    len: usize,
+}
+
+impl std::ops::Deref for Words {
+    type Target = ByteStr;
+
+    fn deref(&self) -> &ByteStr {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Header {
+    pub fn read<R: Read>(f: &mut R) -> io::Result<Self> {
+        // read_u32 is implementation in ReadBytesExt
+        // requires `ReadBytesExt` in scope.
+        let checksum = f.read_u32::<LittleEndian>()?;
+        let key_word = f.read_u32::<LittleEndian>()?;
+        let val_len = f.read_u32::<LittleEndian>()?;
+        let flags = (key_word >> 24) as u8;
+        let namespace = match flags & FLAG_NAMESPACE {
+            0 => 0,
+            _ => f.read_u32::<LittleEndian>()?,
+        };
+
+        Ok(Self {
+            checksum,
+            flags,
+            key_len: key_word & MAX_KEY_LEN as u32,
+            val_len,
+            namespace,
+        })
+    }
+
+    pub fn write<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        f.write_u32::<LittleEndian>(self.checksum)?;
+        f.write_all(&self.lengths())
+    }
+
+    /// The key length and value length words as stored, flags and namespace included
+    pub fn lengths(&self) -> Words {
+        let key_word = (self.flags as u32) << 24 | self.key_len;
+
+        let mut words = Words {
+            bytes: [0; 12],
+            len: 8,
+        };
+        words.bytes[..4].copy_from_slice(&key_word.to_le_bytes());
+        words.bytes[4..8].copy_from_slice(&self.val_len.to_le_bytes());
+        if self.flags & FLAG_NAMESPACE != 0 {
+            words.bytes[8..].copy_from_slice(&self.namespace.to_le_bytes());
+            words.len = 12;
+        }
+        words
+    }
+
+    /// Size of the header as stored
+    pub fn len(&self) -> usize {
+        match self.flags & FLAG_NAMESPACE {
+            0 => HEADER_LEN,
+            _ => HEADER_LEN + 4,
+        }
+    }
+
+    ///
+    /// Number of bytes stored after the header
+    ///
+    /// Computed without overflowing, and fails if the record can't be held in memory, which
+    /// can only happen on 32 bit platforms.
+    ///
+    pub fn data_len(&self) -> io::Result<usize> {
+        let overhead = match self.flags & FLAG_ENCRYPTED {
+            0 => 0,
+            _ => crypto::OVERHEAD as u64,
+        };
+        let len = self.key_len as u64 + self.val_len as u64 + overhead;
+
+        usize::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("record of {len} bytes doesn't fit in memory"),
+            )
+        })
+    }
+}
+
+///
+/// Why a stored record couldn't be read back
+///
+/// It's returned inside an `io::Error`, and `RecordError::of` gets it back out.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    /// The data doesn't match its checksum, e.g. because the disk corrupted it
+    Corrupted { expected: u32, found: u32 },
+    /// An encrypted record failed authentication. Either it was altered, checksum included,
+    /// or it was encrypted with a different key
+    Tampered,
+    /// The record is encrypted, but the store has no key to decrypt it
+    MissingKey,
+}
+
+impl RecordError {
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupted { expected, found } => {
+                write!(
+                    f,
+                    "data corruption encountered ({found:08x} != {expected:08x})"
+                )
+            }
+            Self::Tampered => f.write_str("encrypted record failed authentication"),
+            Self::MissingKey => f.write_str("record is encrypted, but no key was given"),
+        }
+    }
+}
+
+impl Error for RecordError {}
+
+impl From<RecordError> for io::Error {
+    fn from(err: RecordError) -> Self {
+        let kind = match err {
+            RecordError::MissingKey => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::InvalidData,
+        };
+
+        io::Error::new(kind, err)
+    }
+}
+
+///
+/// Size limits enforced when writing, see `KV::set_limits`
+///
+/// Values longer than `chunk_len` are split over several records, so values can be far
+/// longer than a single record allows.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest key accepted, at most `MAX_KEY_LEN`
+    pub max_key_len: usize,
+    /// Longest value accepted
+    pub max_value_len: u64,
+    /// Longest value stored in a single record, at most `MAX_RECORD_VALUE_LEN`
+    pub chunk_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_len: MAX_KEY_LEN,
+            max_value_len: u64::MAX,
+            chunk_len: 64 << 20,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyValuePair {
+    pub key: ByteString,
+    pub value: ByteString,
+}
+
+/// How a store's data file is opened and locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Appends are allowed. Takes an exclusive lock, so no other process can open the file
+    ReadWrite,
+    /// Only reads are allowed. Takes a shared lock, so any number of readers can coexist
+    /// but a writer can't
+    ReadOnly,
+}
+
+#[derive(Debug)]
+pub struct KV {
+    pub(super) f: File,
+    pub(super) path: PathBuf,
+    mode: Mode,
+    // Mapping between keys and file locations
+    pub index: HashMap<ByteString, u64>,
+    // Receivers of change events, see `KV::subscribe`
+    pub(super) subscribers: Vec<Subscriber>,
+    // Encrypts new records and decrypts encrypted ones, if a key was given
+    pub(super) cipher: Option<Cipher>,
+    // What the file header says, such as how records are checksummed
+    pub(super) format: Format,
+    // Position up to which records were read by `load`
+    pub(super) loaded: u64,
+    // Sizes accepted by `insert`
+    pub(super) limits: Limits,
+    // Counters behind `KV::stats`
+    pub(super) stats: Stats,
+    // Indexes of the namespaces other than the default one, see `namespace`
+    pub(super) namespaces: Namespaces,
+    // Indexes over the values of the default namespace, by name, see `KV::add_index`
+    pub(super) secondary: HashMap<String, SecondaryIndex>,
+    // Positions of the merge operands written after the record `index` points to, by key
+    pub(super) operands: HashMap<ByteString, Vec<u64>>,
+    // Folds merge operands into values, see `KV::set_merge_operator`
+    pub(super) merge_operator: Option<Arc<dyn MergeOperator>>,
+    // Faults injected into appends by tests, see `fault`
+    #[cfg(test)]
+    pub(super) faults: Option<crate::fault::Faults>,
+}
+
+impl KV {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with_mode(path, Mode::ReadWrite)
+    }
+
+    pub fn open_read_only(path: &Path) -> io::Result<Self> {
+        Self::open_with_mode(path, Mode::ReadOnly)
+    }
+
+    ///
+    /// Opens the data file and takes an advisory lock on it
+    ///
+    /// Offsets stored in the index are only valid as long as a single process appends to
+    /// the file, so the lock is held for the lifetime of `KV` and released when the file
+    /// is closed. Contention is reported immediately instead of blocking.
+    ///
+    fn open_with_mode(path: &Path, mode: Mode) -> io::Result<Self> {
+        let f = match mode {
+            Mode::ReadWrite => OpenOptions::new()
+                .read(true)
+                .create(true)
+                .append(true)
+                .open(path)?,
+            Mode::ReadOnly => OpenOptions::new().read(true).open(path)?,
+        };
+
+        let locked = match mode {
+            Mode::ReadWrite => f.try_lock(),
+            Mode::ReadOnly => f.try_lock_shared(),
+        };
+
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let held = match mode {
+                    Mode::ReadWrite => "another process has it open",
+                    Mode::ReadOnly => "another process has it open for writing",
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("unable to lock {}: {held}", path.display()),
+                ));
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+
+        // New files get a header. The cursor is moved by reading, but writes always append
+        let mut f = f;
+        let format = if mode == Mode::ReadWrite && f.metadata()?.len() == 0 {
+            let format = Format::new(Checksum::default());
+            format.write(&mut f)?;
+            format
+        } else {
+            f.seek(SeekFrom::Start(0))?;
+            Format::read(&mut f)?
+        };
+
+        let index = HashMap::new();
+        Ok(Self {
+            f,
+            path: path.to_path_buf(),
+            mode,
+            index,
+            subscribers: Vec::new(),
+            cipher: None,
+            format,
+            loaded: format.data_start(),
+            limits: Limits::default(),
+            stats: Stats::default(),
+            namespaces: Namespaces::default(),
+            secondary: HashMap::new(),
+            operands: HashMap::new(),
+            merge_operator: None,
+            #[cfg(test)]
+            faults: None,
+        })
+    }
+
+    ///
+    /// Encrypts records written from now on with `cipher`, and uses it to decrypt
+    ///
+    /// Records already stored are left as they are, and stores can hold a mix of encrypted
+    /// and plain records. Must be called before `load` if the file has encrypted records.
+    ///
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    ///
+    /// Chooses how the records of a new store are checksummed
+    ///
+    /// The choice is recorded in the file header, so it can only be made before anything
+    /// is written. Existing stores keep the algorithm they were created with.
+    ///
+    pub fn set_checksum(&mut self, checksum: Checksum) -> io::Result<()> {
+        self.ensure_writable()?;
+        if self.format.checksum == checksum {
+            return Ok(());
+        }
+
+        let len = self.f.metadata()?.len();
+        if !self.format.has_header || len > self.format.data_start() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} already holds records checksummed with {}",
+                    self.path.display(),
+                    self.format.checksum
+                ),
+            ));
+        }
+
+        self.f.set_len(0)?;
+        self.format = Format::new(checksum);
+        self.format.write(&mut self.f)?;
+        Ok(())
+    }
+
+    /// How records are checksummed, as recorded in the file header
+    pub fn checksum(&self) -> Checksum {
+        self.format.checksum
+    }
+
+    ///
+    /// Which log the file holds and its generation, as recorded in the file header
+    ///
+    /// Stores created before the header recorded it have none.
+    ///
+    pub fn log_id(&self) -> Option<LogId> {
+        self.format.log_id
+    }
+
+    /// Position of the first record, right after the file header if there is one
+    pub fn data_start(&self) -> u64 {
+        self.format.data_start()
+    }
+
+    /// Position of the latest record of every key in the default namespace
+    pub fn index(&self) -> &HashMap<ByteString, u64> {
+        &self.index
+    }
+
+    ///
+    /// Changes the sizes accepted for keys and values written from now on
+    ///
+    /// Fails if the limits go beyond what the file format can hold.
+    ///
+    pub fn set_limits(&mut self, limits: Limits) -> io::Result<()> {
+        if limits.max_key_len > MAX_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("keys can be at most {MAX_KEY_LEN} bytes"),
+            ));
+        }
+
+        if limits.chunk_len == 0 || limits.chunk_len > MAX_RECORD_VALUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunks must be between 1 and {MAX_RECORD_VALUE_LEN} bytes"),
+            ));
+        }
+
+        self.limits = limits;
+        Ok(())
+    }
+
+    ///
+    /// Loads data from buffer to index map
+    ///
+    /// Calling it again only reads the records appended since the last call, such as those
+    /// written by another process before this one took the lock. A damaged record fails
+    /// loading, with the records before it indexed. `KV::recover` goes on past it instead.
+    ///
+    /// A record cut short by a crash ends the log. Stores open for writing drop it from the
+    /// file, so that records appended afterwards follow the last whole one. If a sync
+    /// marker follows it, it's a damaged record rather than the end of the log, and it fails
+    /// like one.
+    ///
+    pub fn load(&mut self) -> io::Result<()> {
+        self.load_resyncing(false).map(drop)
+    }
+
+    ///
+    /// Loads like `load`, passing over damaged records instead of failing
+    ///
+    /// The log goes on at the first sync marker after a damaged record, in stores that have
+    /// them. Returns the spans of the file passed over, whose records are lost, and counts
+    /// them in `Stats::skipped_bytes`. They stay in the file, so `load` fails on them again
+    /// until the store is compacted.
+    ///
+    pub fn recover(&mut self) -> io::Result<Vec<Range<u64>>> {
+        self.load_resyncing(true)
+    }
+
+    fn load_resyncing(&mut self, resync: bool) -> io::Result<Vec<Range<u64>>> {
+        let start = Instant::now();
+        // Keys whose value changed, to be indexed again by the secondary indexes
+        let mut changed = HashSet::new();
+        // Large logs are split among threads, see `parallel_load`
+        let result = self.f.metadata().and_then(|metadata| {
+            let len = metadata.len().saturating_sub(self.loaded);
+            match parallel_load::segments(self.format, len) {
+                1 => self.load_serially(resync, &mut changed),
+                segments => self.load_in_segments(segments, resync, &mut changed),
+            }
+        });
+
+        // Values are read again, since large ones were left as their manifest
+        let result = result
+            .and_then(|skipped| self.drop_torn_tail().map(|_| skipped))
+            .and_then(|skipped| self.update_secondary(changed).map(|_| skipped));
+        self.stats.load_time += start.elapsed();
+        self.count_failure(result)
+    }
+
+    ///
+    /// Truncates whatever follows the last record `load` read, in stores open for writing
+    ///
+    /// Only called once `load` read everything it could, so that's a record cut short,
+    /// unless a sync marker follows it. Then it's a damaged record in the middle of the log,
+    /// and nothing is truncated.
+    ///
+    fn drop_torn_tail(&mut self) -> io::Result<()> {
+        if !self.log_ends_at(self.loaded)? && self.mode == Mode::ReadWrite {
+            self.f.set_len(self.loaded)?;
+            self.f.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Whether the log ends at `position` along with the file, failing if it doesn't
+    ///
+    /// Records following one cut short would have been written by a later run, and the
+    /// sync markers among them tell a damaged record apart from a crash.
+    ///
+    fn log_ends_at(&mut self, position: u64) -> io::Result<bool> {
+        if self.f.metadata()?.len() <= position {
+            return Ok(true);
+        }
+        match sync_marker::find(&mut self.f, position + 1, self.format)? {
+            Some(marker) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record at {position} is damaged, and the log goes on at {marker}"),
+            )),
+            None => Ok(false),
+        }
+    }
+
+    ///
+    /// Indexes the records following `loaded` one after another
+    ///
+    /// Returns the spans passed over if `resync` is set, see `KV::recover`.
+    ///
+    pub(super) fn load_serially(
+        &mut self,
+        resync: bool,
+        changed: &mut HashSet<ByteString>,
+    ) -> io::Result<Vec<Range<u64>>> {
+        // Records are parsed in place, and only positions are kept, so large values needn't
+        // be put together
+        let (format, cipher, loaded) = (self.format, self.cipher.clone(), self.loaded);
+        let (f, mut indexes) = self.split_for_load(changed);
+        let mut records = RecordBlocks::starting_at(f, loaded, format, cipher)?;
+        if resync {
+            records = records.resyncing();
+        }
+
+        let mut result = Ok(());
+        while let Some(record) = records.next() {
+            // The position a record starts at becomes the value of the index
+            let indexed = record
+                .and_then(|(position, header, record)| indexes.add(position, &header, record));
+            if let Err(err) = indexed {
+                result = Err(err);
+                break;
+            }
+        }
+
+        let (loaded, skipped_bytes) = (records.position(), records.skipped_bytes);
+        let checksum_failures = records.checksum_failures;
+        let skipped = records.skipped;
+        self.loaded = loaded;
+        self.stats.skipped_bytes += skipped_bytes;
+        self.stats.checksum_failures += checksum_failures;
+        result.map(|()| skipped)
+    }
+
+    /// The file, borrowed apart from what `load` updates for the records read from it
+    pub(super) fn split_for_load<'a>(
+        &'a mut self,
+        changed: &'a mut HashSet<ByteString>,
+    ) -> (&'a mut File, LoadedIndexes<'a>) {
+        let indexes = LoadedIndexes {
+            index: &mut self.index,
+            operands: &mut self.operands,
+            namespaces: &mut self.namespaces,
+            records: &mut self.stats.records,
+            changed: (!self.secondary.is_empty()).then_some(changed),
+        };
+        (&mut self.f, indexes)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let position = self.insert_but_ignore_index(key, value)?;
+        index_record(&mut self.index, &mut self.operands, 0, key, position);
+        self.index_value(key, value);
+        Ok(())
+    }
+
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
+        self.write_value(0, key, value)
+    }
+
+    ///
+    /// Stores `value` for `key` in the namespace with id `namespace`, returning its position
+    ///
+    /// Subscribers only hear about the default namespace.
+    ///
+    pub(super) fn write_value(
+        &mut self,
+        namespace: u32,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> io::Result<u64> {
+        self.ensure_writable()?;
+        self.check_key(key)?;
+        self.check_value_len(value.len() as u64)?;
+
+        // Values too long for one record are split, and the record standing for all of
+        // them is the one the index points to
+        let current_position = if value.len() > self.limits.chunk_len {
+            let mut writer = ValueWriter::new(self, key)?.in_namespace(namespace);
+            writer.write_all(value)?;
+            writer.finish_but_ignore_index()?
+        } else {
+            let (format, cipher) = (self.format, self.cipher.as_ref());
+            let record = Self::encode_record_in(key, value, 0, namespace, format, cipher)?;
+            self.append(&record)?
+        };
+
+        self.stats.records += 1;
+        self.stats.writes += 1;
+
+        if namespace == 0 && !self.subscribers.is_empty() {
+            self.notify(Event::new(current_position, key.to_vec(), value.to_vec()));
+        }
+
+        Ok(current_position)
+    }
+
+    ///
+    /// Appends an encoded record, returning the position it starts at
+    ///
+    /// Moving the cursor to the end, because we're appending the data. The cursor may be
+    /// anywhere after a read, so the position is taken from the seek itself. A sync marker
+    /// may be written right before the record, see `sync_marker`.
+    ///
+    pub(super) fn append(&mut self, record: &ByteStr) -> io::Result<u64> {
+        self.append_together(&[record])
+            .map(|positions| positions[0])
+    }
+
+    ///
+    /// Appends encoded records with a single write, returning the positions they start at
+    ///
+    /// A sync marker may be written before them, like `append` does, but never between
+    /// them. So once the file is loaded again, either the records that follow the first one
+    /// are all there, or none of them is.
+    ///
+    pub(super) fn append_together(&mut self, records: &[&ByteStr]) -> io::Result<Vec<u64>> {
+        let end = self.f.seek(SeekFrom::End(0))?;
+        let len: u64 = records.iter().map(|record| record.len() as u64).sum();
+        // A sync marker goes in the same write as the records it comes before
+        let is_manifest = Header::read(&mut &records[0][..])?.flags & FLAG_MANIFEST != 0;
+        let mut buffer = ByteString::with_capacity(len as usize);
+        if let Some(marker) = sync_marker::before(self.format, end, len)?
+            && !is_manifest
+        {
+            buffer.extend_from_slice(&marker);
+        }
+
+        let mut positions = Vec::with_capacity(records.len());
+        for record in records {
+            positions.push(end + buffer.len() as u64);
+            buffer.extend_from_slice(record);
+        }
+        self.write_at_end(&buffer)?;
+        let written = buffer.len() as u64;
+
+        // The records needn't be read again by `load`, unless records written by others
+        // come before them
+        if self.loaded == end {
+            self.loaded += written;
+        }
+        self.stats.bytes_written += written;
+
+        Ok(positions)
+    }
+
+    /// Writes `bytes` at the end of the file, suffering the faults tests injected, if any
+    pub(super) fn write_at_end(&mut self, bytes: &ByteStr) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(faults) = &mut self.faults {
+            return faults.writer(&mut self.f).write_all(bytes);
+        }
+
+        self.f.write_all(bytes)
+    }
+
+    /// Fails if `key` is longer than the limits allow
+    pub(super) fn check_key(&self, key: &ByteStr) -> io::Result<()> {
+        let max = self.limits.max_key_len;
+        if key.len() > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key is {} bytes, at most {max} are allowed", key.len()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if a value of `len` bytes is longer than the limits allow
+    pub(super) fn check_value_len(&self, len: u64) -> io::Result<()> {
+        let max = self.limits.max_value_len;
+        if len > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value is {len} bytes, at most {max} are allowed"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn ensure_writable(&self) -> io::Result<()> {
+        match self.mode {
+            Mode::ReadWrite => Ok(()),
+            Mode::ReadOnly => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "store was opened read-only",
+            )),
+        }
+    }
+
+    ///
+    /// Lays out a record the way it's stored, header included
+    ///
+    /// The whole record is built in memory first, so it's written with a single `write()`
+    /// call instead of one per field. This increases throughput while keeping the application
+    /// code neater.
+    ///
+    pub(super) fn encode_record(
+        key: &ByteStr,
+        value: &ByteStr,
+        format: Format,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<ByteString> {
+        Self::encode_record_in(key, value, 0, 0, format, cipher)
+    }
+
+    /// Like `encode_record`, for records with `flags` set in the namespace with id `namespace`
+    pub(super) fn encode_record_in(
+        key: &ByteStr,
+        value: &ByteStr,
+        flags: u8,
+        namespace: u32,
+        format: Format,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<ByteString> {
+        let key_len = key.len();
+        let val_len = value.len();
+        if key_len > MAX_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key is {key_len} bytes, at most {MAX_KEY_LEN} are allowed"),
+            ));
+        }
+        if val_len > MAX_RECORD_VALUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value is {val_len} bytes, at most {MAX_RECORD_VALUE_LEN} fit in a record"),
+            ));
+        }
+
+        let mut tmp = ByteString::with_capacity(key_len + val_len);
+
+        for byte in key {
+            tmp.push(*byte);
+        }
+
+        for byte in value {
+            tmp.push(*byte);
+        }
+
+        let mut header = Header {
+            checksum: 0,
+            flags,
+            key_len: key_len as u32,
+            val_len: val_len as u32,
+            namespace,
+        };
+        if namespace != 0 {
+            header.flags |= FLAG_NAMESPACE;
+        }
+
+        // Encrypted records store the sealed key+value instead. The lengths are sealed
+        // along with it, so the flags must be final before sealing
+        let data = match cipher {
+            Some(cipher) => {
+                header.flags |= FLAG_ENCRYPTED;
+                cipher.seal(&tmp, &header.lengths())
+            }
+            None => tmp,
+        };
+
+        // Get the checksum of the stored data
+        header.checksum = format.record_checksum(&header.lengths(), &data);
+
+        let mut record = ByteString::with_capacity(header.len() + data.len());
+        header.write(&mut record)?;
+        record.extend_from_slice(&data);
+        Ok(record)
+    }
+
+    pub fn seek_to_end(&mut self) -> io::Result<u64> {
+        self.f.seek(SeekFrom::End(0))
+    }
+
+    /// Waits until everything written so far is on disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.f.sync_data()
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let kv = self.read_value(0, key, position)?;
+        Ok(Some(kv.value))
+    }
+
+    ///
+    /// Returns every live key starting with `prefix` with its value, sorted by key
+    ///
+    /// Deleted keys are left out. An empty prefix returns the whole store.
+    ///
+    pub fn scan(&mut self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let matches = matching(&self.index, prefix);
+        self.read_live(0, matches)
+    }
+
+    ///
+    /// Reads the values of `matches`, keys of the namespace with id `namespace` along with
+    /// their positions, leaving out deleted keys
+    ///
+    pub(super) fn read_live(
+        &mut self,
+        namespace: u32,
+        matches: Vec<(ByteString, u64)>,
+    ) -> io::Result<Vec<KeyValuePair>> {
+        let mut found = Vec::with_capacity(matches.len());
+        for (key, position) in matches {
+            let kv = self.read_value(namespace, &key, position)?;
+            if !kv.value.is_empty() {
+                found.push(kv);
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
+        self.get_record_at(position).map(|(_, kv)| kv)
+    }
+
+    /// Like `get_at`, along with the header of the record
+    pub(super) fn get_record_at(&mut self, position: u64) -> io::Result<(Header, KeyValuePair)> {
+        self.stats.reads += 1;
+
+        // The header is read whole, and the data right after it, so it takes two reads
+        // rather than one for every field
+        let (format, cipher) = (self.format, self.cipher.as_ref());
+        let kv = read_header_at(&mut self.f, position).and_then(|(header, len)| {
+            let mut record = (&header[..len]).chain(&mut self.f);
+            let (header, mut kv) = KV::process_record(&mut record, format, cipher)?;
+            if header.flags & FLAG_MANIFEST != 0 {
+                kv.value = chunked::read_large(&mut self.f, position, &kv, format, cipher)?;
+            }
+            Ok((header, kv))
+        });
+        self.count_failure(kv)
+    }
+
+    /// Counts records failing their checksum, passing `result` through
+    pub(super) fn count_failure<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(err) = &result
+            && let Some(RecordError::Corrupted { .. }) = RecordError::of(err)
+        {
+            self.stats.checksum_failures += 1;
+        }
+        result
+    }
+
+    ///
+    /// Scans the whole log for the latest record of `target` in the default namespace,
+    /// without the index
+    ///
+    /// Records are parsed in place, and only the value of the one found is read again. Fails
+    /// on the first damaged record.
+    ///
+    pub fn find(&mut self, target: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
+        let (start, format, cipher) = (self.format.data_start(), self.format, self.cipher.clone());
+        let mut found = None;
+
+        let mut records = RecordBlocks::starting_at(&mut self.f, start, format, cipher)?;
+        while let Some(record) = records.next() {
+            let (position, header, record) = record?;
+
+            let is_value = header.flags & (FLAG_CHUNK | FLAG_SYNC) == 0;
+            if record.key == target && is_value && header.namespace == 0 {
+                found = Some(position);
+                // Shouldn't break here, because we're using append only Key Value store
+                // maybe value got overwritten, we would need that record
+            }
+        }
+        let end = records.position();
+        self.log_ends_at(end)?;
+
+        // Large values are put back together from their chunks here
+        found
+            .map(|position| Ok((position, self.get_at(position)?.value)))
+            .transpose()
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+        self.insert(key, b"")
+    }
+
+    ///
+    /// Reads the bytes of a single record as they are stored, without checking them
+    ///
+    /// Used to ship records elsewhere, where `process_record` decodes them again.
+    ///
+    pub(super) fn read_raw_record<R: Read>(f: &mut R) -> io::Result<ByteString> {
+        let header = Header::read(f)?;
+        let data_len = header.data_len()?;
+
+        let header_len = header.len();
+        let mut raw = ByteString::with_capacity(header_len + data_len.min(MAX_PREALLOCATION));
+        header.write(&mut raw)?;
+        f.take(data_len as u64).read_to_end(&mut raw)?;
+        if raw.len() != header_len + data_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(raw)
+    }
+
+    ///
+    /// Processes a single record
+    ///
+    /// f may be any type that implements Read, such as a type that reads files, but
+    /// can also be a &[u8]. `checksum` is the algorithm of the file the record comes from,
+    /// and `cipher` is only needed for encrypted records. The header is
+    /// returned too, since the value of chunk and manifest records isn't the whole value
+    /// of their key.
+    ///
+    pub(super) fn process_record<R: Read>(
+        f: &mut R,
+        format: Format,
+        cipher: Option<&Cipher>,
+    ) -> std::io::Result<(Header, KeyValuePair)> {
+        let header = Header::read(f)?;
+        let data_len = header.data_len()?;
+
+        // Vector created to store data, checksum length is not needed here.
+        let mut data = ByteString::with_capacity(data_len.min(MAX_PREALLOCATION));
+        // by_ref() is used to avoid ownership issues
+        // block is used to limit the scope of mutably borrowed reference.
+        {
+            f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+        }
+
+        // A record that was only partially written, e.g. because the writer is still busy
+        // with it, ends early just like a file without any more records
+        if data.len() != data_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        record_ref::check(&header, &data, format)?;
+
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            let cipher = cipher.ok_or(RecordError::MissingKey)?;
+            data = cipher.open(&data, &header.lengths())?;
+        }
+
+        // Splitted the data. Second-half is returned but first-half is still in the data
+        // The trick here is, it doesn't re-allocate space for key, because it's already in
+        // data variable, hence an efficient solution.
+        let value = data.split_off(header.key_len as usize);
+        let key = data;
+
+        Ok((header, KeyValuePair { key, value }))
+    }
+}
+
+///
+/// Reads the header of the record at `position` with a single read, or two for records of
+/// a namespace, returning its bytes and how many there are
+///
+/// `f` is left right after it, for the data to be read straight from it.
+///
+fn read_header_at(f: &mut File, position: u64) -> io::Result<([u8; HEADER_LEN + 4], usize)> {
+    f.seek(SeekFrom::Start(position))?;
+    let mut header = [0; HEADER_LEN + 4];
+    f.read_exact(&mut header[..HEADER_LEN])?;
+    // Flags are the top byte of the key length word
+    let len = match header[7] & FLAG_NAMESPACE {
+        0 => HEADER_LEN,
+        _ => HEADER_LEN + 4,
+    };
+    f.read_exact(&mut header[HEADER_LEN..len])?;
+    Ok((header, len))
+}
+
+///
+/// Indexes the record of the default namespace at `position`, which has `flags` set
+///
+/// Merge operands are kept on top of the value they apply to, in `operands`. Any other
+/// record replaces the value of its key, along with its operands.
+///
+pub(super) fn index_record(
+    index: &mut HashMap<ByteString, u64>,
+    operands: &mut HashMap<ByteString, Vec<u64>>,
+    flags: u8,
+    key: &ByteStr,
+    position: u64,
+) {
+    if flags & FLAG_MERGE != 0 && index.contains_key(key) {
+        operands.entry(key.to_vec()).or_default().push(position);
+        return;
+    }
+
+    operands.remove(key);
+    // Keys already indexed aren't copied again
+    match index.get_mut(key) {
+        Some(indexed) => *indexed = position,
+        None => {
+            index.insert(key.to_vec(), position);
+        }
+    }
+}
+
+/// The indexes `load` adds every record it reads to
+pub(super) struct LoadedIndexes<'a> {
+    index: &'a mut HashMap<ByteString, u64>,
+    operands: &'a mut HashMap<ByteString, Vec<u64>>,
+    namespaces: &'a mut Namespaces,
+    records: &'a mut u64,
+    // Keys whose value changed, if there are secondary indexes to update
+    changed: Option<&'a mut HashSet<ByteString>>,
+}
+
+impl LoadedIndexes<'_> {
+    /// Sets the key of the record at `position` in the index of its namespace
+    pub(super) fn add(
+        &mut self,
+        position: u64,
+        header: &Header,
+        record: RecordRef<'_>,
+    ) -> io::Result<()> {
+        // Chunks are found through the manifest following them, and markers hold nothing
+        if header.flags & (FLAG_CHUNK | FLAG_SYNC) != 0 {
+            return Ok(());
+        }
+        *self.records += 1;
+
+        match header.namespace {
+            0 => {
+                if let Some(changed) = self.changed.as_mut() {
+                    changed.insert(record.key.to_vec());
+                }
+                let (index, operands) = (&mut *self.index, &mut *self.operands);
+                index_record(index, operands, header.flags, record.key, position);
+                Ok(())
+            }
+            namespace => self.namespaces.index_record(namespace, position, record),
+        }
+    }
+}
+
+/// The keys of `index` starting with `prefix` and their positions, sorted by key
+pub(super) fn matching(
+    index: &HashMap<ByteString, u64>,
+    prefix: &ByteStr,
+) -> Vec<(ByteString, u64)> {
+    let mut matches: Vec<(ByteString, u64)> = index
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .map(|(key, position)| (key.clone(), *position))
+        .collect();
+    matches.sort();
+    matches
+}
+
+///
+/// Reads records one after another, starting from a given position
+///
+/// Each item is the position the record starts at, along with the record itself and its
+/// header. Iteration
+/// stops cleanly at the end of the file. Large values are put back together from their
+/// chunks, and show up at the position of their manifest. Only records of the default
+/// namespace are returned.
+///
+pub(super) struct Records<R> {
+    f: R,
+    format: Format,
+    cipher: Option<Cipher>,
+    // Where the next record starts. Only moves past complete records, so a record that is
+    // still being written is read again from its start next time
+    position: u64,
+    done: bool,
+}
+
+impl<R: Read + Seek> Records<R> {
+    pub(super) fn starting_at(
+        mut f: R,
+        position: u64,
+        format: Format,
+        cipher: Option<Cipher>,
+    ) -> io::Result<Self> {
+        f.seek(SeekFrom::Start(position))?;
+        Ok(Self {
+            f,
+            format,
+            cipher,
+            position,
+            done: false,
+        })
+    }
+
+    /// Position right after the last record read, where the next one would start
+    pub(super) fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: Read + Seek> Iterator for Records<R> {
+    type Item = io::Result<(u64, Header, KeyValuePair)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            // reads a record in the file at its current position
+            let item = KV::process_record(&mut self.f, self.format, self.cipher.as_ref())
+                .and_then(|(header, kv)| Ok((self.f.stream_position()?, header, kv)));
+
+            let (position, header, mut kv) = match item {
+                Ok((end, header, kv)) => {
+                    let position = std::mem::replace(&mut self.position, end);
+                    (position, header, kv)
+                }
+                Err(err) => {
+                    self.done = true;
+                    return match err.kind() {
+                        io::ErrorKind::UnexpectedEof => None,
+                        _ => Some(Err(err)),
+                    };
+                }
+            };
+
+            // Chunks are read along with the manifest following them
+            if header.flags & (FLAG_CHUNK | FLAG_SYNC) != 0 || header.namespace != 0 {
+                continue;
+            }
+
+            if header.flags & FLAG_MANIFEST != 0 {
+                let cipher = self.cipher.as_ref();
+                let value = chunked::read_large(&mut self.f, position, &kv, self.format, cipher)
+                    .and_then(|value| Ok((value, self.f.seek(SeekFrom::Start(self.position))?)));
+
+                match value {
+                    Ok((value, _)) => kv.value = value,
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+
+            return Some(Ok((position, header, kv)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_keep_a_single_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        assert_eq!(
+            KV::open_read_only(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let mut store = KV::open(&path).unwrap();
+        store.insert(b"apple", b"red").unwrap();
+
+        // Neither another writer nor a reader can open the file while it's being written
+        assert_eq!(
+            KV::open(&path).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            KV::open_read_only(&path).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        drop(store);
+
+        // Readers share it, and keep writers out until the last one is gone
+        let mut first = KV::open_read_only(&path).unwrap();
+        let mut second = KV::open_read_only(&path).unwrap();
+        for reader in [&mut first, &mut second] {
+            reader.load().unwrap();
+            assert_eq!(reader.get(b"apple").unwrap(), Some(b"red".to_vec()));
+        }
+        assert_eq!(
+            KV::open(&path).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Writing through a reader fails without touching the file
+        let len = std::fs::metadata(&path).unwrap().len();
+        let refused = [
+            first.insert(b"apple", b"green").unwrap_err(),
+            first.delete(b"apple").unwrap_err(),
+            first.merge(b"apple", b"!").unwrap_err(),
+            first.create_namespace(b"trees").unwrap_err(),
+            first.set_checksum(Checksum::Crc32c).unwrap_err(),
+            first.compact().unwrap_err(),
+        ];
+        for err in refused {
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        assert!(first.put_writer(b"apple").is_err());
+        assert!(first.group_commit().is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        drop(first);
+        assert!(KV::open(&path).is_err());
+        drop(second);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"apple").unwrap(), Some(b"red".to_vec()));
+    }
+}
@@ -0,0 +1,419 @@
+//!
+//! Streaming values in and out of the store
+//!
+//! `KV::get` and `KV::insert` hold whole values in memory. `ValueReader` and `ValueWriter`
+//! only ever hold a single chunk, see `chunked`, so values can be far larger than memory.
+//! Every chunk is checked against its checksum before any of its bytes are handed out.
+//!
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+};
+
+use crate::{
+    chunked::{self, Manifest},
+    crypto::Cipher,
+    format::Format,
+    store::{
+        ByteStr, ByteString, FLAG_CHUNK, FLAG_MANIFEST, FLAG_MERGE, FLAG_SYNC, Header, KV,
+        index_record,
+    },
+};
+
+/// Where a record holding part of a value is, and which part it holds
+#[derive(Debug, Clone, Copy)]
+struct Part {
+    position: u64,
+    start: u64,
+    len: u64,
+}
+
+///
+/// Reads a single value, see `KV::get_reader`
+///
+/// Seeking is cheap: only the chunk holding the new position is read, once reading
+/// continues from there.
+///
+pub struct ValueReader<'a> {
+    f: &'a mut File,
+    format: Format,
+    cipher: Option<&'a Cipher>,
+    key: ByteString,
+    // Whether the value is split into chunks, or stored in a single plain record
+    chunked: bool,
+    parts: Vec<Part>,
+    len: u64,
+    position: u64,
+    // The part last read, and its bytes
+    current: Option<(usize, ByteString)>,
+}
+
+impl ValueReader<'_> {
+    /// Length of the whole value
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads and checks the part at `index`, unless it's the one read last
+    fn load(&mut self, index: usize) -> io::Result<&ByteStr> {
+        if self
+            .current
+            .as_ref()
+            .is_none_or(|(current, _)| *current != index)
+        {
+            let part = self.parts[index];
+            self.f.seek(SeekFrom::Start(part.position))?;
+            let (header, kv) = KV::process_record(self.f, self.format, self.cipher)?;
+
+            let is_chunk = header.flags & FLAG_CHUNK != 0;
+            if is_chunk != self.chunked || kv.key != self.key || kv.value.len() as u64 != part.len {
+                return Err(chunked::missing_chunks());
+            }
+            self.current = Some((index, kv.value));
+        }
+
+        Ok(self
+            .current
+            .as_ref()
+            .map(|(_, bytes)| &bytes[..])
+            .unwrap_or_default())
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Parts are ordered, so the one holding the position is the last starting before it
+        let index = self
+            .parts
+            .partition_point(|part| part.start <= self.position)
+            - 1;
+        let offset = (self.position - self.parts[index].start) as usize;
+        let bytes = &self.load(index)?[offset..];
+
+        let n = bytes.len().min(buf.len());
+        buf[..n].copy_from_slice(&bytes[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+///
+/// Writes a single value, see `KV::put_writer`
+///
+/// Bytes are collected until a whole chunk is ready, which is then appended to the log.
+/// Nothing is visible until `finish` is called; a writer dropped before that leaves only
+/// unused chunks behind, and the key keeps its previous value. `flush` doesn't write
+/// partial chunks.
+///
+pub struct ValueWriter<'a> {
+    store: &'a mut KV,
+    key: ByteString,
+    chunk: ByteString,
+    // Position of the first chunk, once one is written
+    first: Option<u64>,
+    manifest: Manifest,
+    // Id of the namespace the value is written to, 0 for the default one
+    namespace: u32,
+}
+
+impl<'a> ValueWriter<'a> {
+    pub(super) fn new(store: &'a mut KV, key: &ByteStr) -> io::Result<Self> {
+        store.ensure_writable()?;
+        store.check_key(key)?;
+
+        Ok(Self {
+            store,
+            key: key.to_vec(),
+            chunk: ByteString::new(),
+            first: None,
+            manifest: Manifest {
+                value_len: 0,
+                chunks: 0,
+                distance: 0,
+            },
+            namespace: 0,
+        })
+    }
+
+    /// Writes to the namespace with id `namespace` instead of the default one
+    pub(super) fn in_namespace(mut self, namespace: u32) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let (format, cipher) = (self.store.format, self.store.cipher.as_ref());
+        let record = KV::encode_record_in(
+            &self.key,
+            &self.chunk,
+            FLAG_CHUNK,
+            self.namespace,
+            format,
+            cipher,
+        )?;
+        let position = self.store.append(&record)?;
+
+        self.first.get_or_insert(position);
+        self.manifest.chunks += 1;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    ///
+    /// Writes what's left of the value, returning the position the index should point to
+    ///
+    /// Values that fit in a single chunk are stored as a plain record, like `KV::insert`
+    /// would.
+    ///
+    pub(super) fn finish_but_ignore_index(&mut self) -> io::Result<u64> {
+        let (format, cipher) = (self.store.format, self.store.cipher.as_ref());
+        let Some(first) = self.first else {
+            let record =
+                KV::encode_record_in(&self.key, &self.chunk, 0, self.namespace, format, cipher)?;
+            return self.store.append(&record);
+        };
+
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+
+        self.manifest.distance = self.store.seek_to_end()? - first;
+        let (format, cipher) = (self.store.format, self.store.cipher.as_ref());
+        let manifest = self.manifest.encode()?;
+        let record = KV::encode_record_in(
+            &self.key,
+            &manifest,
+            FLAG_MANIFEST,
+            self.namespace,
+            format,
+            cipher,
+        )?;
+        self.store.append(&record)
+    }
+
+    /// Writes what's left of the value, and makes it the value of the key
+    pub fn finish(mut self) -> io::Result<()> {
+        let position = self.finish_but_ignore_index()?;
+
+        self.store.stats.records += 1;
+        self.store.stats.writes += 1;
+        // Replaces the operands merged into the old value too
+        index_record(
+            &mut self.store.index,
+            &mut self.store.operands,
+            0,
+            &self.key,
+            position,
+        );
+        // The value is gone from memory, so it's read back for the secondary indexes
+        self.store.update_secondary([mem::take(&mut self.key)])
+    }
+}
+
+impl Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_len = self.store.limits.chunk_len;
+        let n = buf.len().min(chunk_len - self.chunk.len());
+
+        let value_len = self.manifest.value_len + n as u64;
+        self.store.check_value_len(value_len)?;
+        self.manifest.value_len = value_len;
+
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == chunk_len {
+            self.write_chunk()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl KV {
+    ///
+    /// Opens the value of `key` for reading in pieces
+    ///
+    /// Returns `None` if the key isn't in the index. Deleted keys give an empty value,
+    /// just like `KV::get`.
+    ///
+    pub fn get_reader(&mut self, key: &ByteStr) -> io::Result<Option<ValueReader<'_>>> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+        self.stats.reads += 1;
+
+        self.f.seek(SeekFrom::Start(position))?;
+        let header = Header::read(&mut self.f)?;
+        if header.flags & FLAG_MERGE != 0 || self.operands.contains_key(key) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "value has merge operands, which can only be folded by `get`",
+            ));
+        }
+
+        let mut parts = Vec::new();
+        let chunked = header.flags & FLAG_MANIFEST != 0;
+        if chunked {
+            // Only the headers of the chunks are read here, their data is read when needed
+            self.f.seek(SeekFrom::Start(position))?;
+            let (_, kv) = KV::process_record(&mut self.f, self.format, self.cipher.as_ref())?;
+            let manifest = Manifest::decode(&kv.value)?;
+
+            let mut position = position
+                .checked_sub(manifest.distance)
+                .ok_or_else(chunked::missing_chunks)?;
+            let mut start = 0;
+            while (parts.len() as u64) < manifest.chunks {
+                self.f.seek(SeekFrom::Start(position))?;
+                let header = Header::read(&mut self.f)?;
+                let len = header.val_len as u64;
+
+                // Sync markers can come between chunks
+                if header.flags & FLAG_SYNC == 0 {
+                    parts.push(Part {
+                        position,
+                        start,
+                        len,
+                    });
+                    start += len;
+                }
+                position += (header.len() + header.data_len()?) as u64;
+            }
+
+            if start != manifest.value_len {
+                return Err(chunked::missing_chunks());
+            }
+        } else {
+            parts.push(Part {
+                position,
+                start: 0,
+                len: header.val_len as u64,
+            });
+        }
+
+        let len = parts.iter().map(|part| part.len).sum();
+        Ok(Some(ValueReader {
+            f: &mut self.f,
+            format: self.format,
+            cipher: self.cipher.as_ref(),
+            key: key.to_vec(),
+            chunked,
+            parts,
+            len,
+            position: 0,
+            current: None,
+        }))
+    }
+
+    ///
+    /// Starts writing a new value for `key` in pieces
+    ///
+    /// The value is only stored once `ValueWriter::finish` is called. Subscribers aren't
+    /// told about values written this way, since that would need the whole value in
+    /// memory; `KV::tail` does return them.
+    ///
+    pub fn put_writer(&mut self, key: &ByteStr) -> io::Result<ValueWriter<'_>> {
+        ValueWriter::new(self, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Limits;
+
+    use super::*;
+
+    #[test]
+    fn streamed_values_read_back_from_any_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 7,
+                ..Limits::default()
+            })
+            .unwrap();
+        let value: ByteString = (0..100).collect();
+
+        let mut writer = store.put_writer(b"blob").unwrap();
+        for piece in value.chunks(3) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(store.get(b"blob").unwrap(), Some(value.clone()));
+
+        let mut reader = store.get_reader(b"blob").unwrap().unwrap();
+        assert_eq!(reader.len(), 100);
+        let mut read = ByteString::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, value);
+
+        reader.seek(SeekFrom::End(-30)).unwrap();
+        let mut tail = [0; 10];
+        reader.read_exact(&mut tail).unwrap();
+        assert_eq!(tail[..], value[70..80]);
+
+        // Short values don't need chunks
+        let mut writer = store.put_writer(b"short").unwrap();
+        writer.write_all(b"abc").unwrap();
+        writer.finish().unwrap();
+        let mut read = ByteString::new();
+        let mut reader = store.get_reader(b"short").unwrap().unwrap();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"abc");
+
+        // Streamed values replace the operands merged into the old one, like `insert` does
+        let limits = |chunk_len| Limits {
+            chunk_len,
+            ..Limits::default()
+        };
+        store.set_limits(limits(8)).unwrap();
+        store.set_merge_operator(crate::merge::U64Add);
+        store.merge(b"counter", &5u64.to_le_bytes()).unwrap();
+        store.merge(b"counter", &7u64.to_le_bytes()).unwrap();
+        let counter = |n: u64| Some(n.to_le_bytes().to_vec());
+        assert_eq!(store.get(b"counter").unwrap(), counter(12));
+        store.set_limits(limits(3)).unwrap();
+        let mut writer = store.put_writer(b"counter").unwrap();
+        writer.write_all(&100u64.to_le_bytes()).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), counter(100));
+        drop(store);
+        let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+        store.set_merge_operator(crate::merge::U64Add);
+        store.load().unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), counter(100));
+    }
+}
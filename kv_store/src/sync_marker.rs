@@ -0,0 +1,226 @@
+//!
+//! Sync markers, letting readers go on past a damaged stretch of the log
+//!
+//! Records are only found through the lengths of the ones before them, so a damaged header
+//! used to end the log right there, and everything after it was lost. In files of version
+//! 2, a marker record comes before every record crossing a multiple of `SYNC_INTERVAL`
+//! bytes. When a record fails its checksum, or the log seems to end before the file does,
+//! `KV::recover` looks for the next marker and goes on from there, passing over what's
+//! between. `load` fails instead, and doesn't take a damaged record followed by a marker for
+//! a record cut short by a crash.
+//!
+//! A marker is a record flagged with `FLAG_SYNC`, its key being `MAGIC` and its value its
+//! own position, as a little endian u64:
+//!
+//! ```text
+//! [header: checksum, FLAG_SYNC | 16, 8][magic: 16 bytes][position: u64]
+//! ```
+//!
+//! The checksum and position tell a marker apart from the same bytes in a value, such as
+//! a copy of another store. Markers hold no data, so every reader passes over them.
+//! Compaction moves records, so it writes the markers anew for their new positions.
+//!
+//! Manifests never have a marker before them, since their distance back to the first chunk
+//! is measured before they're written.
+//!
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+    format::Format,
+    store::{ByteString, FLAG_SYNC, HEADER_LEN, KV},
+};
+
+/// Markers are about this many bytes apart, besides records longer than that
+pub(super) const SYNC_INTERVAL: u64 = 64 << 10;
+
+const MAGIC: &[u8; 16] = b"\xf3KV-SYNC-MARKER\x8b";
+
+pub(super) const MARKER_LEN: usize = HEADER_LEN + MAGIC.len() + 8;
+
+/// Bytes read at once while looking for a marker
+const SEARCH_LEN: usize = 64 << 10;
+
+/// The marker belonging at `position`
+pub(super) fn encode(format: Format, position: u64) -> io::Result<ByteString> {
+    KV::encode_record_in(MAGIC, &position.to_le_bytes(), FLAG_SYNC, 0, format, None)
+}
+
+///
+/// The marker to write before a record of `len` bytes appended at `position`, if it needs
+/// one
+///
+/// It does if the record crosses a multiple of `SYNC_INTERVAL`, and the file has markers.
+///
+pub(super) fn before(format: Format, position: u64, len: u64) -> io::Result<Option<ByteString>> {
+    if !format.sync_markers || position / SYNC_INTERVAL == (position + len) / SYNC_INTERVAL {
+        return Ok(None);
+    }
+
+    encode(format, position).map(Some)
+}
+
+/// Whether `bytes` start with the marker belonging at `position`
+fn is_marker(bytes: &[u8], position: u64, format: Format) -> bool {
+    bytes.len() >= MARKER_LEN
+        && encode(format, position).is_ok_and(|marker| bytes[..MARKER_LEN] == marker[..])
+}
+
+/// Position of the first marker at `from` or after it, moving the cursor of `f` anywhere
+pub(super) fn find<R: Read + Seek>(
+    f: &mut R,
+    from: u64,
+    format: Format,
+) -> io::Result<Option<u64>> {
+    if !format.sync_markers {
+        return Ok(None);
+    }
+
+    f.seek(SeekFrom::Start(from))?;
+    let mut buf = vec![0; SEARCH_LEN];
+    // `buf[..len]` is what the file holds from `start` on
+    let (mut start, mut len) = (from, 0);
+    loop {
+        match f.read(&mut buf[len..]) {
+            Ok(0) => return Ok(None),
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+
+        // A marker starting before `buf` was whole in the bytes searched last time
+        let found = buf[..len]
+            .windows(MAGIC.len())
+            .enumerate()
+            .filter(|&(at, window)| at >= HEADER_LEN && window == MAGIC)
+            .map(|(at, _)| at - HEADER_LEN)
+            .find(|&at| is_marker(&buf[at..len], start + at as u64, format));
+        if let Some(at) = found {
+            return Ok(Some(start + at as u64));
+        }
+
+        // Bytes that could start a marker are kept for the next read
+        let kept = len.min(MARKER_LEN - 1);
+        buf.copy_within(len - kept..len, 0);
+        start += (len - kept) as u64;
+        len = kept;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Limits;
+    use std::{fs::File, path::Path};
+
+    /// Positions of the markers in the file at `path`, found the slow way
+    fn markers(path: &Path, format: Format) -> Vec<u64> {
+        let bytes = std::fs::read(path).unwrap();
+        (0..bytes.len())
+            .filter(|&at| bytes[at..].get(HEADER_LEN..HEADER_LEN + MAGIC.len()) == Some(MAGIC))
+            .filter(|&at| is_marker(&bytes[at..], at as u64, format))
+            .map(|at| at as u64)
+            .collect()
+    }
+
+    fn check_values(store: &mut KV, large: impl Fn(u32) -> ByteString) {
+        for i in 0..300u32 {
+            assert_eq!(store.get(&i.to_le_bytes()).unwrap().unwrap(), large(i));
+            let mut reader = store.get_reader(&i.to_le_bytes()).unwrap().unwrap();
+            let mut value = Vec::new();
+            reader.read_to_end(&mut value).unwrap();
+            assert_eq!(value, large(i));
+        }
+    }
+
+    #[test]
+    fn load_resumes_at_the_next_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+
+        let mut store = KV::open(&path).unwrap();
+        store
+            .set_limits(Limits {
+                chunk_len: 1000,
+                ..Limits::default()
+            })
+            .unwrap();
+        let large = |i: u32| vec![i as u8; 5000 + i as usize];
+        for i in 0..300u32 {
+            store.insert(&i.to_le_bytes(), &large(i)).unwrap();
+        }
+        // A copy of the log holds markers that don't belong where it's stored
+        let copy = std::fs::read(&path).unwrap();
+        store.insert(b"copy", &copy).unwrap();
+        let commit = store.group_commit().unwrap();
+        for i in 300..400u32 {
+            commit.insert(&i.to_le_bytes(), &[1; 2000]).unwrap();
+        }
+        drop(commit);
+        store.load().unwrap();
+
+        // Markers are spread over the whole log, and readers pass over them
+        let format = store.format;
+        let len = std::fs::metadata(&path).unwrap().len();
+        let found = markers(&path, format);
+        assert!(found[0] < 2 * SYNC_INTERVAL && found[found.len() - 1] > len - 2 * SYNC_INTERVAL);
+        assert!(
+            found
+                .windows(2)
+                .all(|pair| pair[1] - pair[0] < 2 * SYNC_INTERVAL)
+        );
+        let mut f = File::open(&path).unwrap();
+        assert_eq!(find(&mut f, 0, format).unwrap(), Some(found[0]));
+        assert_eq!(find(&mut f, found[0] + 1, format).unwrap(), Some(found[1]));
+        check_values(&mut store, large);
+        assert_eq!(store.get(b"copy").unwrap().unwrap(), copy);
+
+        // Large values are copied whole, with a marker before their first chunk
+        store.compact().unwrap();
+        let found = markers(&path, format);
+        let longest = 2 * SYNC_INTERVAL + 2 * copy.len() as u64;
+        assert!(found.windows(2).all(|pair| pair[1] - pair[0] < longest));
+        check_values(&mut store, large);
+
+        // Damaged lengths in the middle of the log look like a record cut short, but the
+        // markers after them tell it apart, so loading fails rather than truncating them
+        let mut positions: Vec<u64> = store.index.values().copied().collect();
+        positions.sort();
+        let damaged = positions[positions.len() / 2];
+        drop(store);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[damaged as usize + 4..damaged as usize + 12].fill(0x7f);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut store = KV::open(&path).unwrap();
+        assert!(store.load().is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), bytes.len() as u64);
+        assert!(store.find(&399u32.to_le_bytes()).is_err());
+
+        // Recovering only loses the records up to the next marker
+        let next = found.into_iter().find(|&marker| marker > damaged).unwrap();
+        assert_eq!(store.recover().unwrap(), vec![damaged..next]);
+        assert_eq!(store.stats.skipped_bytes, next - damaged);
+        let lost = positions.iter().filter(|&&at| at >= damaged && at < next);
+        assert_eq!(store.index.len(), positions.len() - lost.count());
+        assert_eq!(
+            store.get(&399u32.to_le_bytes()).unwrap().unwrap(),
+            [1; 2000]
+        );
+
+        // Compacting drops the damage for good
+        store.compact().unwrap();
+        drop(store);
+        let mut store = KV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(store.find(&399u32.to_le_bytes()).unwrap().is_some());
+
+        // Stores of version 1 have none
+        let format = Format {
+            sync_markers: false,
+            ..format
+        };
+        assert_eq!(before(format, SYNC_INTERVAL - 1, 10).unwrap(), None);
+        assert_eq!(find(&mut f, 0, format).unwrap(), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::store::{ByteStr, ByteString, FLAG_MERGE, KV, Records};
+
+/// What happened to a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Put(ByteString),
+    /// Deletes are stored as records with an empty value
+    Delete,
+    /// An operand for the merge operator, see `KV::merge`, to fold into the value
+    Merge(ByteString),
+}
+
+/// A single change to the store, in the order it was appended to the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Position of the record in the data file. Passing it to `KV::tail` replays the log
+    /// starting from this event
+    pub offset: u64,
+    pub key: ByteString,
+    pub change: Change,
+}
+
+impl Event {
+    pub(super) fn new(offset: u64, key: ByteString, value: ByteString) -> Self {
+        let change = if value.is_empty() {
+            Change::Delete
+        } else {
+            Change::Put(value)
+        };
+
+        Self {
+            offset,
+            key,
+            change,
+        }
+    }
+
+    pub(super) fn merge(offset: u64, key: ByteString, operand: ByteString) -> Self {
+        Self {
+            offset,
+            key,
+            change: Change::Merge(operand),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Subscriber {
+    prefix: ByteString,
+    tx: Sender<Event>,
+}
+
+///
+/// Receiving end of `KV::subscribe`
+///
+/// Events are queued without bound until they're received, so a subscriber that falls far
+/// behind costs memory. Dropping the subscription unregisters it on the next write.
+///
+#[derive(Debug)]
+pub struct Subscription {
+    rx: Receiver<Event>,
+}
+
+impl Subscription {
+    /// Returns the next event if one is queued, without blocking
+    pub fn try_next(&self) -> Option<Event> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Event;
+
+    /// Blocks until the next event arrives. Ends when the store is dropped
+    fn next(&mut self) -> Option<Event> {
+        self.rx.recv().ok()
+    }
+}
+
+///
+/// Replays the log from a given offset, see `KV::tail`
+///
+/// Each item is the event stored in the next record. Once the iterator is exhausted,
+/// `position` gives the offset to resume from later, e.g. after a restart.
+///
+pub struct Tail<'a> {
+    records: Records<BufReader<&'a mut File>>,
+}
+
+impl Tail<'_> {
+    /// Offset right after the last event returned
+    pub fn position(&self) -> u64 {
+        self.records.position()
+    }
+}
+
+impl Iterator for Tail<'_> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        Some(
+            record.map(|(offset, header, kv)| match header.flags & FLAG_MERGE {
+                0 => Event::new(offset, kv.key, kv.value),
+                _ => Event::merge(offset, kv.key, kv.value),
+            }),
+        )
+    }
+}
+
+impl KV {
+    ///
+    /// Delivers every following put, delete and merge on keys starting with `prefix`
+    ///
+    /// Events arrive in log order, the same as `KV::tail` reads them back. An empty prefix
+    /// matches every key. Changes made before subscribing can be read with `KV::tail`;
+    /// calling it first and subscribing right after leaves no gap, since nothing can be
+    /// written in between.
+    ///
+    pub fn subscribe(&mut self, prefix: &ByteStr) -> Subscription {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(Subscriber {
+            prefix: prefix.to_vec(),
+            tx,
+        });
+
+        Subscription { rx }
+    }
+
+    ///
+    /// Reads the events stored in the log, starting from the record at `offset`
+    ///
+    /// `offset` must be the start of a record: 0, an `Event::offset` or a position returned
+    /// by `Tail::position`.
+    ///
+    pub fn tail(&mut self, offset: u64) -> io::Result<Tail<'_>> {
+        // Offset 0 stands for the first record, which comes after the file header
+        let offset = offset.max(self.format.data_start());
+        let f = BufReader::new(&mut self.f);
+        let records = Records::starting_at(f, offset, self.format, self.cipher.clone())?;
+        Ok(Tail { records })
+    }
+
+    /// Sends an event to every interested subscriber, forgetting those that hung up
+    pub(super) fn notify(&mut self, event: Event) {
+        self.subscribers.retain(|subscriber| {
+            if !event.key.starts_with(&subscriber.prefix) {
+                return true;
+            }
+
+            subscriber.tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscriptions_filter_by_prefix_and_tails_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = KV::open(&dir.path().join("store.db")).unwrap();
+
+        let fruit = store.subscribe(b"fruit/");
+        let everything = store.subscribe(b"");
+        store.insert(b"fruit/apple", b"red").unwrap();
+        store.insert(b"veg/leek", b"green").unwrap();
+        store.delete(b"fruit/apple").unwrap();
+        store.insert(b"fruits", b"many").unwrap();
+
+        let changes = |subscription: &Subscription| {
+            std::iter::from_fn(|| subscription.try_next())
+                .map(|event| (event.key, event.change))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            changes(&fruit),
+            [
+                (b"fruit/apple".to_vec(), Change::Put(b"red".to_vec())),
+                (b"fruit/apple".to_vec(), Change::Delete),
+            ]
+        );
+        assert_eq!(changes(&everything).len(), 4);
+
+        // Tailing again from where the last tail stopped only reads what came after
+        let mut tail = store.tail(0).unwrap();
+        let events: Vec<Event> = tail.by_ref().map(Result::unwrap).collect();
+        assert_eq!(events.len(), 4);
+        let position = tail.position();
+        assert_eq!(store.tail(events[2].offset).unwrap().count(), 2);
+
+        store.insert(b"fruit/kiwi", b"green").unwrap();
+        let mut tail = store.tail(position).unwrap();
+        let event = tail.next().unwrap().unwrap();
+        assert_eq!(event.offset, position);
+        assert_eq!(event.key, b"fruit/kiwi");
+        assert_eq!(event.change, Change::Put(b"green".to_vec()));
+        assert!(tail.next().is_none());
+        assert_eq!(changes(&fruit).len(), 1);
+    }
+}
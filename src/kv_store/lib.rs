@@ -1,206 +0,0 @@
-use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::Path,
-};
-
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
-use serde_derive::{Deserialize, Serialize};
-
-pub type ByteString = Vec<u8>;
-pub type ByteStr = [u8];
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct KeyValuePair {
-    pub key: ByteString,
-    pub value: ByteString,
-}
-
-#[derive(Debug)]
-pub struct KV {
-    f: File,
-    // Mapping between keys and file locations
-    pub index: HashMap<ByteString, u64>,
-}
-
-impl KV {
-    pub fn open(path: &Path) -> io::Result<Self> {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)?;
-
-        let index = HashMap::new();
-        Ok(Self { f, index })
-    }
-
-    ///
-    /// Loads data from buffer to index map
-    ///
-    pub fn load(&mut self) -> io::Result<()> {
-        let mut f = BufReader::new(&mut self.f);
-
-        loop {
-            // File::seek returns the number of bytes from the start of the file
-            // This becomes the value of the index
-            let position = f.seek(SeekFrom::Current(0))?;
-            // reads a record in the file at its current position
-            let maybe_kv = KV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
-                Err(err) => match err.kind() {
-                    io::ErrorKind::UnexpectedEof => {
-                        break;
-                    }
-                    _ => return Err(err),
-                },
-            };
-            // Set key and its position
-            self.index.insert(kv.key, position);
-        }
-
-        Ok(())
-    }
-
-    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
-        let position = self.insert_but_ignore_index(key, value)?;
-        self.index.insert(key.to_vec(), position);
-        Ok(())
-    }
-
-    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        // `BufWriter` type batches multiple short `write()` calls into fewer actuall
-        // disk operations, resulting in a single one. This increases throughput while keeping
-        // the application code neater
-        let mut f = BufWriter::new(&mut self.f);
-
-        let key_len = key.len();
-        let val_len = value.len();
-        let mut tmp = ByteString::with_capacity(key_len + val_len);
-
-        for byte in key {
-            tmp.push(*byte);
-        }
-
-        for byte in value {
-            tmp.push(*byte);
-        }
-
-        // Get the checksum of key+value
-        let checksum = crc32::checksum_ieee(&tmp);
-        // Position specifier. Will move cursor to the end
-        let next_byte = SeekFrom::End(0);
-        // Gets the current position, which will be stored as index. It's also the starting
-        // position of the current record
-        let current_position = f.seek(SeekFrom::Current(0))?;
-        // Moving the cursor to the end, because we're appending the data
-        f.seek(next_byte)?;
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(val_len as u32)?;
-        // Write the bytes
-        f.write_all(&mut tmp)?;
-
-        Ok(current_position)
-    }
-
-    pub fn seek_to_end(&mut self) -> io::Result<u64> {
-        self.f.seek(SeekFrom::End(0))
-    }
-
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        let position = match self.index.get(key) {
-            None => return Ok(None),
-            Some(position) => *position,
-        };
-
-        let kv = self.get_at(position)?;
-        Ok(Some(kv.value))
-    }
-
-    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
-        let mut f = BufReader::new(&mut self.f);
-        f.seek(SeekFrom::Start(position))?;
-        let kv = KV::process_record(&mut self.f)?;
-        Ok(kv)
-    }
-
-    pub fn find(&mut self, target: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
-        let mut f = BufReader::new(&mut self.f);
-        let mut found: Option<(u64, ByteString)> = None;
-
-        loop {
-            let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = Self::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
-                Err(err) => match err.kind() {
-                    io::ErrorKind::UnexpectedEof => {
-                        break;
-                    }
-                    _ => return Err(err),
-                },
-            };
-
-            if kv.key == target {
-                found = Some((position, kv.value));
-                // Shouldn't break here, because we're using append only Key Value store
-                // maybe value got overwritten, we would need that record
-            }
-        }
-
-        Ok(found)
-    }
-
-    #[inline]
-    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
-        self.insert(key, value)
-    }
-
-    #[inline]
-    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
-        self.insert(key, b"")
-    }
-
-    ///
-    /// Processes a single record
-    ///
-    /// f may be any type that implements Read, such as a type that reads files, but
-    /// can also be a &[u8]
-    ///
-    fn process_record<R: Read>(f: &mut R) -> std::io::Result<KeyValuePair> {
-        // read_u32 is implementation in ReadBytesExt
-        // requires `ReadBytesExt` in scope.
-        let saved_checksum = f.read_u32::<LittleEndian>()?;
-        let key_len = f.read_u32::<LittleEndian>()?;
-        let val_len = f.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
-
-        // Vector created to store data, checksum length is not needed here.
-        let mut data = ByteString::with_capacity(data_len as usize);
-        // by_ref() is used to avoid ownership issues
-        // block is used to limit the scope of mutably borrowed reference.
-        {
-            f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
-        }
-
-        debug_assert_eq!(data.len(), data_len as usize);
-
-        let checksum = crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!("data corruption encountered ({checksum:08x} != {saved_checksum:08x})");
-        }
-
-        // Splitted the data. Second-half is returned but first-half is still in the data
-        // The trick here is, it doesn't re-allocate space for key, because it's already in
-        // data variable, hence an efficient solution.
-        let value = data.split_off(key_len as usize);
-        let key = data;
-
-        Ok(KeyValuePair { key, value })
-    }
-}
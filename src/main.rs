@@ -1,9 +1,12 @@
 mod cpu_emulator;
 // Uncomment following module if you want to visualize the heap allocations
 // mod heap_visualizer;
-mod kv_store;
 
 fn main() {
     // heap_visualizer::visualize();
-    kv_store::run();
+
+    // The key value store used to run from here, and is a crate of its own now
+    println!("The key value store has binaries of its own:");
+    println!("    cargo run -p kv_store --bin kv_mem -- FILE COMMAND ...");
+    println!("    cargo run -p kv_store --bin kv_bench -- OPTIONS ...");
 }